@@ -1,14 +1,26 @@
 use crate::common::MergeResult;
-use crate::common::{dit_error, ThreadRunContext};
+use crate::common::{dit_error, CancelHandle, CopyOptions, Dedupe, MatchBy, ThreadRunContext};
+use crate::layout::Layout;
+use crate::ledger::Ledger;
+use crate::media::MediaType;
 use crate::message::{
-    CopyFileRequest, CopyToDestRequest, HashRequest, HashResult, TransferRequest,
+    CopyFileRequest, CopyToDestRequest, HashRequest, HashResponse, TransferRequest,
 };
+use crate::progress::{Progress, ProgressReport};
+use crate::threads::{
+    CopyContext, DiscoverContext, Duplicates, HashContext, HashQueue, HashResultReceiver,
+    MergeContext, SourceCopies,
+};
+use crate::volume::Volume;
+use crate::watch::Profile;
+use crate::xmp::Sidecars;
 use crate::{common, ReadWritePaths};
-use crate::threads;
+use crate::{discover, fsutil, threads};
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 /**
@@ -20,7 +32,9 @@ use std::thread;
  *
  * Excepts to find arguments of the form: ["read", <read-paths...>, "write", <write-paths...>]
  *
- * Does not check paths for validity
+ * Does not check paths for validity, or look at them at all, so read_volumes is left empty for
+ * copy() to look up. Options (arguments starting with "--") are skipped, see
+ * get_cli_copy_options().
  *
  * Returns ReadWritePaths, or Err if at least one read and write path are provided
  */
@@ -42,6 +56,9 @@ pub fn get_cli_read_write_paths(args: &[String]) -> Result<ReadWritePaths, Box<d
             set_write = true;
             continue;
         }
+        if s.starts_with("--") {
+            continue;
+        }
 
         // strip trailing slash from path, if present
         //
@@ -74,21 +91,220 @@ pub fn get_cli_read_write_paths(args: &[String]) -> Result<ReadWritePaths, Box<d
     Ok(ReadWritePaths {
         read_paths,
         write_paths,
+        read_volumes: vec![],
     })
 }
 
+/**
+ * Accepts the command-line arguments (excluding args[0], which is the program name).
+ *
+ * Picks out the options (arguments starting with "--") and ignores everything else, so the same
+ * arguments can also be passed to get_cli_read_write_paths().
+ *
+ * Returns CopyOptions, or Err if an unknown option is found
+ */
+pub fn get_cli_copy_options(args: &[String]) -> Result<CopyOptions, Box<dyn Error>> {
+    let mut copy_options = CopyOptions::default();
+
+    for s in args {
+        if !s.starts_with("--") {
+            continue;
+        }
+
+        match s.as_str() {
+            "--progress" => copy_options.progress = true,
+            "--force" => copy_options.force = true,
+            "--verbose" => copy_options.verbose = true,
+            "--verify" => copy_options.verify = true,
+            "--ledger" => copy_options.ledger = true,
+            "--media-only" => copy_options.media_only = true,
+            "--check" => copy_options.check = true,
+            "--xmp" => copy_options.xmp = true,
+            _ if s.starts_with("--preserve=") => {
+                for attr in s["--preserve=".len()..].split(',') {
+                    match attr {
+                        "mode" => copy_options.preserve.mode = true,
+                        "owner" => copy_options.preserve.owner = true,
+                        "xattr" => copy_options.preserve.xattr = true,
+                        "acl" => copy_options.preserve.acl = true,
+                        _ => {
+                            let err_msg = format!("unknown attribute to preserve: '{}'", attr);
+                            return dit_error(&err_msg);
+                        }
+                    }
+                }
+            }
+            _ if s.starts_with("--match=") => {
+                copy_options.match_by = match &s["--match=".len()..] {
+                    "size" => MatchBy::Size,
+                    "time" => MatchBy::Time,
+                    "hash" => MatchBy::Hash,
+                    value => {
+                        let err_msg = format!("unknown way to match files: '{}'", value);
+                        return dit_error(&err_msg);
+                    }
+                };
+            }
+            _ if s.starts_with("--dedupe=") => {
+                copy_options.dedupe = match &s["--dedupe=".len()..] {
+                    "report" => Some(Dedupe::Report),
+                    "skip" => Some(Dedupe::Skip),
+                    "link" => Some(Dedupe::Link),
+                    value => {
+                        let err_msg = format!("unknown way to dedupe files: '{}'", value);
+                        return dit_error(&err_msg);
+                    }
+                };
+            }
+            _ if s.starts_with("--layout=") => {
+                let template = &s["--layout=".len()..];
+                Layout::new(template)?;
+                copy_options.layout = Some(String::from(template));
+            }
+            _ if s.starts_with("--buffer-size=") => {
+                copy_options.buf_size = parse_size(&s["--buffer-size=".len()..])?;
+            }
+            _ if s.starts_with("--hash-threads=") => {
+                copy_options.hash_threads = parse_threads(&s["--hash-threads=".len()..])?;
+            }
+            _ if s.starts_with("--device-threads=") => {
+                let value = &s["--device-threads=".len()..];
+                match value.rsplit_once('=') {
+                    Some((path, threads)) => {
+                        let threads = parse_threads(threads)?;
+                        let path = PathBuf::from(path);
+                        copy_options.device_threads_by_path.push((path, threads));
+                    }
+                    None => copy_options.device_threads = parse_threads(value)?,
+                }
+            }
+            _ if s.starts_with("--file-mode=") => {
+                copy_options.file_mode = Some(parse_mode(&s["--file-mode=".len()..])?);
+            }
+            _ if s.starts_with("--dir-mode=") => {
+                copy_options.dir_mode = Some(parse_mode(&s["--dir-mode=".len()..])?);
+            }
+            _ => {
+                let err_msg = format!("unknown option: '{}'", s);
+                return dit_error(&err_msg);
+            }
+        }
+    }
+
+    if copy_options.preserve.mode && copy_options.file_mode.is_some() {
+        return dit_error("can not use --file-mode with --preserve=mode");
+    }
+
+    Ok(copy_options)
+}
+
+/**
+ * Accepts the command-line arguments after "watch" (see watch()).
+ *
+ * Expects to find "--profile <file>" (or "--profile=<file>"), and reads the profile from that
+ * file (see Profile::load()).
+ *
+ * Returns the Profile, or Err if there is no profile, an unknown argument, or the profile can't be
+ * read
+ */
+pub fn get_cli_watch_profile(args: &[String]) -> Result<Profile, Box<dyn Error>> {
+    let mut profile_path = None;
+
+    let mut args = args.iter();
+    while let Some(s) = args.next() {
+        match s.as_str() {
+            "--profile" => profile_path = args.next(),
+            _ if s.starts_with("--profile=") => profile_path = Some(s),
+            _ => {
+                let err_msg = format!("unknown argument: '{}'", s);
+                return dit_error(&err_msg);
+            }
+        }
+    }
+
+    match profile_path {
+        Some(s) => Profile::load(Path::new(s.strip_prefix("--profile=").unwrap_or(s))),
+        None => dit_error("must have a profile (--profile <file>)"),
+    }
+}
+
+/**
+ * Parse an octal permission mode from the command line (e.g. "0664" or "2775").
+ */
+fn parse_mode(s: &str) -> Result<u32, Box<dyn Error>> {
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => {
+            let err_msg = format!("invalid octal mode: '{}'", s);
+            dit_error(&err_msg)
+        }
+    }
+}
+
+/**
+ * Parse a size in bytes from the command line, with an optional K, M or G suffix (powers of 1024).
+ */
+fn parse_size(s: &str) -> Result<usize, Box<dyn Error>> {
+    let (digits, multiplier) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1024),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1024 * 1024),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+        _ => (s, 1),
+    };
+
+    match digits.parse::<usize>() {
+        Ok(size) if size > 0 => match size.checked_mul(multiplier) {
+            Some(size) => Ok(size),
+            None => {
+                let err_msg = format!("size is too large: '{}'", s);
+                dit_error(&err_msg)
+            }
+        },
+        _ => {
+            let err_msg = format!("invalid size: '{}'", s);
+            dit_error(&err_msg)
+        }
+    }
+}
+
+/**
+ * Parse a number of threads from the command line (at least 1).
+ */
+fn parse_threads(s: &str) -> Result<usize, Box<dyn Error>> {
+    match s.parse::<usize>() {
+        Ok(threads) if threads > 0 => Ok(threads),
+        _ => {
+            let err_msg = format!("invalid number of threads: '{}'", s);
+            dit_error(&err_msg)
+        }
+    }
+}
+
 /**
  * Top-level entry point to copy files from N sources to M destinations.
  *
+ * The read paths are scanned up front to total up how much will be written to each destination.
+ * If that won't fit in the free space on a destination, an Err is returned before anything is
+ * copied, unless copy_options.force (or copy_options.ledger) is set.
+ *
+ * If copy_options.progress is set, log_progress is called every copy_options.progress_interval
+ * with a ProgressReport.
+ *
+ * The copy can be stopped early from another thread by calling cancel() on the given
+ * CancelHandle, in which case MergeResult::Cancelled is returned once all threads have stopped.
+ *
  * Returns a MergeResult indicating how it went.
  */
 pub fn copy(
     log_info: fn(&str),
     log_warn: fn(&str),
+    log_progress: fn(&ProgressReport),
     read_write_paths: &ReadWritePaths,
+    copy_options: &CopyOptions,
+    cancel_handle: &CancelHandle,
 ) -> Result<MergeResult, Box<dyn Error>> {
     // ensure we have valid read and write paths, creating the write paths if necessary
-    match common::ensure_valid_read_write_paths(read_write_paths) {
+    match common::ensure_valid_read_write_paths(read_write_paths, copy_options.dir_mode) {
         Ok(_) => {}
         Err(e) => {
             return dit_error(&e.to_string());
@@ -105,6 +321,112 @@ pub fn copy(
         write_paths.push(write_path.to_str().unwrap());
     }
 
+    // identify the card or volume that each read path is on, for the run report, the ledger, and
+    // the {card} layout token
+    let mut read_volumes = vec![];
+    for (i, read_path) in read_write_paths.read_paths.iter().enumerate() {
+        let volume = match read_write_paths.read_volumes.get(i) {
+            Some(volume) => volume.clone(),
+            None => Volume::of(read_path),
+        };
+        log_info(&format!(
+            "reading '{}' from {}",
+            read_path.to_str().unwrap(),
+            volume
+        ));
+        read_volumes.push(volume);
+    }
+
+    let layout = match &copy_options.layout {
+        Some(template) => {
+            let cards = read_volumes.iter().map(|v| String::from(v.card_name()));
+            Some(Layout::new(template)?.with_cards(cards.collect()))
+        }
+        None => None,
+    };
+
+    let mut ledger = None;
+    if copy_options.ledger {
+        ledger = Some(Arc::new(Ledger::open(
+            &read_write_paths.read_paths,
+            &read_volumes,
+            &read_write_paths.write_paths,
+        )?));
+    }
+
+    let mut sidecars = None;
+    if copy_options.xmp {
+        sidecars = Some(Arc::new(Sidecars::new(
+            &read_write_paths.read_paths,
+            &read_volumes,
+            copy_options.file_mode.unwrap_or(0o644),
+        )));
+    }
+
+    // report files whose extension doesn't match their contents (with check), and non-media files
+    // to skip (with media_only)
+    let media_report =
+        discover::check_media(&read_paths, copy_options.media_only, copy_options.check)?;
+    for (path, classification) in &media_report.mismatched {
+        let describe = |media_type: Option<MediaType>| match media_type {
+            Some(media_type) => media_type.to_string(),
+            None => String::from("unrecognized"),
+        };
+        log_warn(&format!(
+            "extension/content mismatch: '{}' (extension: {}, contents: {})",
+            path.to_str().unwrap(),
+            describe(classification.by_extension),
+            describe(classification.by_contents)
+        ));
+    }
+    for path in &media_report.non_media {
+        log_warn(&format!(
+            "skipping '{}' (not a photo, video or audio file)",
+            path.to_str().unwrap()
+        ));
+    }
+
+    // report each spanned clip, and whether any of its chapters are missing
+    let mut spanned_result = MergeResult::Ok;
+    for spanned_clip in &media_report.spanned_clips {
+        let clip_sub_path = spanned_clip.sub_path(spanned_clip.name.first_chapter);
+        let missing_chapters = spanned_clip.missing_chapters();
+        if missing_chapters.is_empty() {
+            log_info(&format!(
+                "spanned clip {}: {} chapters, complete",
+                clip_sub_path,
+                spanned_clip.chapters.len()
+            ));
+            continue;
+        }
+
+        let missing_names: Vec<String> = missing_chapters
+            .iter()
+            .map(|&chapter| spanned_clip.name.file_name(chapter))
+            .collect();
+        log_warn(&format!(
+            "spanned clip {}: {} chapters, incomplete (missing {} in every read path)",
+            clip_sub_path,
+            spanned_clip.chapters.len(),
+            missing_names.join(", ")
+        ));
+        spanned_result = MergeResult::Conflict;
+    }
+
+    // pre-scan the read paths to find out how much there is to copy, and make sure it will fit
+    let (scan_totals, scan_plan) = discover::scan(
+        log_warn,
+        &read_paths,
+        &write_paths,
+        layout.as_ref(),
+        copy_options.match_by,
+        media_report.skipped,
+    )?;
+    // the scan also counts files that the ledger will skip (since it doesn't hash them), so with
+    // the ledger this is the most that could be written, which still has to fit
+    common::ensure_enough_free_space(log_warn, read_write_paths, &scan_totals, copy_options.force)?;
+    let progress = Arc::new(Progress::new(read_write_paths, scan_totals));
+
     // transfer request channel
     let (xfer_req_tx, xfer_req_rx): (SyncSender<TransferRequest>, Receiver<TransferRequest>) =
         mpsc::sync_channel(crate::common::CHANNEL_SIZE_TRANSFER_REQUEST);
@@ -115,7 +437,7 @@ pub fn copy(
         Receiver<CopyToDestRequest>,
     ) = mpsc::sync_channel(crate::common::CHANNEL_SIZE_COPY_TO_DEST_REQUEST);
 
-    // hash request/response channels
+    // hash request/response channels, one of each per read path
     let mut hash_req_channels_tx = vec![];
     let mut hash_req_channels_rx = vec![];
     let mut hash_res_channels_tx = vec![];
@@ -128,42 +450,65 @@ pub fn copy(
         ) = mpsc::sync_channel(crate::common::CHANNEL_SIZE_HASH_REQUEST);
 
         // hash response channel
-        let (hash_res_tx, hash_res_rx): (
-            SyncSender<Option<HashResult>>,
-            Receiver<Option<HashResult>>,
-        ) = mpsc::sync_channel(crate::common::CHANNEL_SIZE_HASH_RESPONSE);
+        let (hash_res_tx, hash_res_rx): (SyncSender<HashResponse>, Receiver<HashResponse>) =
+            mpsc::sync_channel(crate::common::CHANNEL_SIZE_HASH_RESPONSE);
 
         // add channels to vectors
         hash_req_channels_tx.push(hash_req_tx);
-        hash_req_channels_rx.push(hash_req_rx);
+        hash_req_channels_rx.push(Some(hash_req_rx));
+
+        hash_res_channels_tx.push(Some(hash_res_tx));
+        hash_res_channels_rx.push(HashResultReceiver::new(hash_res_rx));
+    }
 
-        hash_res_channels_tx.push(hash_res_tx);
-        hash_res_channels_rx.push(hash_res_rx);
+    // read paths on the same device share a queue of hash requests, so they don't seek against
+    // each other, and a pool of hash threads works through it
+    let read_devices = common::group_by_device(&read_write_paths.read_paths)?;
+    let mut hash_queues = vec![];
+    for (_, read_path_indexes) in &read_devices {
+        let mut hash_req_rx_vec = vec![];
+        let mut hash_res_tx_vec = vec![];
+        for i in read_path_indexes {
+            hash_req_rx_vec.push(hash_req_channels_rx[*i].take().unwrap());
+            hash_res_tx_vec.push(hash_res_channels_tx[*i].take().unwrap());
+        }
+        let hash_queue = Arc::new(Mutex::new(HashQueue::new(hash_req_rx_vec)));
+        hash_queues.push((hash_queue, hash_res_tx_vec));
     }
 
-    // copy file request channels
-    let mut copy_file_req_channels_tx = vec![];
+    // copy file request channels, one per write device, shared by all of the copy threads for
+    // that device, and by all of the write paths on that device
+    // merge still sends to each write path by index, so it gets a clone of the sender for each one
+    let write_devices = common::group_by_device(&read_write_paths.write_paths)?;
+    let mut copy_file_req_channels_tx = vec![None; write_paths.len()];
     let mut copy_file_req_channels_rx = vec![];
-    for _ in &read_write_paths.write_paths {
+    for (dev, write_path_indexes) in &write_devices {
         // copy file request channel
         let (copy_file_req_tx, copy_file_req_rx): (
             SyncSender<CopyFileRequest>,
             Receiver<CopyFileRequest>,
-        ) = mpsc::sync_channel(crate::common::CHANNEL_SIZE_COPY_FILE_REQUEST);
+        ) = mpsc::sync_channel(
+            crate::common::CHANNEL_SIZE_COPY_FILE_REQUEST * write_path_indexes.len(),
+        );
+
+        for i in write_path_indexes {
+            copy_file_req_channels_tx[*i] = Some(copy_file_req_tx.clone());
+        }
 
-        // add channel to vectors
-        copy_file_req_channels_tx.push(copy_file_req_tx);
-        copy_file_req_channels_rx.push(copy_file_req_rx);
+        let copy_threads = common::get_device_threads(copy_options, *dev)?.max(1);
+        copy_file_req_channels_rx.push((Arc::new(Mutex::new(copy_file_req_rx)), copy_threads));
     }
+    let copy_file_req_channels_tx: Vec<SyncSender<CopyFileRequest>> =
+        copy_file_req_channels_tx.into_iter().flatten().collect();
 
-    let root_run_ctx = ThreadRunContext::new();
+    let root_run_ctx = ThreadRunContext::new(cancel_handle);
 
     let discovery_run_ctx = ThreadRunContext::from(&root_run_ctx);
     let discovery_run_ctx_clone = discovery_run_ctx.clone();
 
     let mut hash_run_ctx_vec = vec![];
     let mut hash_run_ctx_clone_vec = vec![];
-    for _ in &read_paths {
+    for _ in &read_devices {
         let hash_run_ctx = ThreadRunContext::from(&root_run_ctx);
         let hash_run_ctx_clone = hash_run_ctx.clone();
 
@@ -174,14 +519,12 @@ pub fn copy(
     let merge_run_ctx = ThreadRunContext::from(&root_run_ctx);
     let merge_run_ctx_clone = merge_run_ctx.clone();
 
-    let mut copy_run_ctx_vec = vec![];
-    let mut copy_run_ctx_clone_vec = vec![];
-    for _ in &read_write_paths.write_paths {
-        let copy_run_ctx = ThreadRunContext::from(&root_run_ctx);
-        let copy_run_ctx_clone = copy_run_ctx.clone();
+    let progress_run_ctx = ThreadRunContext::from(&root_run_ctx);
+    let progress_run_ctx_clone = progress_run_ctx.clone();
 
-        copy_run_ctx_vec.push(copy_run_ctx);
-        copy_run_ctx_clone_vec.push(copy_run_ctx_clone);
+    let mut copy_run_ctx_vec = vec![];
+    for _ in &write_devices {
+        copy_run_ctx_vec.push(ThreadRunContext::from(&root_run_ctx));
     }
 
     let mut read_paths_copy = vec![];
@@ -199,56 +542,88 @@ pub fn copy(
         write_paths_copy.push(String::from(*write_path));
     }
 
-    let discovery_thread = thread::spawn(move || {
-        threads::discover(
-            discovery_run_ctx_clone,
-            &xfer_req_tx,
-            &copy_to_dest_tx,
-            hash_req_channels_tx,
-            read_paths_copy,
-            write_paths_discover_copy,
-        )
-    });
+    let discover_ctx = DiscoverContext {
+        read_paths: read_paths_copy,
+        write_paths: write_paths_discover_copy,
+        scan_plan,
+        copy_options: copy_options.clone(),
+        xfer_req_tx,
+        copy_to_dest_req_tx: copy_to_dest_tx,
+        hash_req_channels_tx,
+    };
+    let discovery_thread =
+        thread::spawn(move || threads::discover(discovery_run_ctx_clone, discover_ctx));
 
     let mut hash_threads = vec![];
-    for _ in &read_paths {
-        let hash_run_ctx_clone = hash_run_ctx_clone_vec.pop().unwrap();
-        let hash_req_channel_rx = hash_req_channels_rx.pop().unwrap();
-        let hash_res_channel_tx = hash_res_channels_tx.pop().unwrap();
-
-        let hash_thread = thread::spawn(move || {
-            threads::hash(
-                hash_run_ctx_clone,
+    for (hash_queue, hash_res_tx_vec) in hash_queues {
+        let hash_run_ctx = hash_run_ctx_clone_vec.pop().unwrap();
+        for _ in 0..copy_options.hash_threads.max(1) {
+            let hash_run_ctx_clone = hash_run_ctx.clone();
+            let hash_ctx = HashContext {
                 log_warn,
-                hash_req_channel_rx,
-                hash_res_channel_tx,
-            );
-        });
-        hash_threads.push(hash_thread);
-    }
-
-    let merge_thread = thread::spawn(move || {
-        threads::merge(
-            merge_run_ctx_clone,
-            log_info,
-            log_warn,
-            write_paths_copy,
-            xfer_req_rx,
-            hash_res_channels_rx,
-            copy_to_dest_rx,
-            copy_file_req_channels_tx,
-        )
-    });
+                progress: progress.clone(),
+                buf_size: copy_options.buf_size,
+                check: copy_options.check,
+                hash_queue: hash_queue.clone(),
+                hash_res_channels_tx: hash_res_tx_vec.clone(),
+            };
+
+            let hash_thread = thread::spawn(move || threads::hash(hash_run_ctx_clone, hash_ctx));
+            hash_threads.push(hash_thread);
+        }
+    }
+
+    let merge_ctx = MergeContext {
+        log_info,
+        log_warn,
+        write_paths: write_paths_copy,
+        match_by: copy_options.match_by,
+        duplicates: Duplicates::new(copy_options.dedupe),
+        progress: progress.clone(),
+        xfer_req_rx,
+        hash_res_channels_rx,
+        copy_to_dest_rx,
+        copy_file_req_channels_tx,
+    };
+    let merge_thread = thread::spawn(move || threads::merge(merge_run_ctx_clone, merge_ctx));
+
+    let copy_ctx = CopyContext {
+        log_info,
+        log_warn,
+        copy_options: copy_options.clone(),
+        progress: progress.clone(),
+        source_copies: Arc::new(SourceCopies::new(write_paths.len())),
+        ledger,
+        sidecars,
+    };
 
     let mut copy_threads = vec![];
-    for _ in &read_write_paths.write_paths {
-        let copy_run_ctx_clone = copy_run_ctx_clone_vec.pop().unwrap();
-        let copy_file_req_channel_rx = copy_file_req_channels_rx.pop().unwrap();
+    for (copy_run_ctx, (copy_file_req_channel_rx, device_threads)) in
+        copy_run_ctx_vec.iter().zip(copy_file_req_channels_rx)
+    {
+        for _ in 0..device_threads {
+            let copy_run_ctx_clone = copy_run_ctx.clone();
+            let copy_file_req_channel_rx = copy_file_req_channel_rx.clone();
+            let copy_ctx_clone = copy_ctx.clone();
+
+            let copy_thread = thread::spawn(move || {
+                threads::copy(copy_run_ctx_clone, copy_ctx_clone, copy_file_req_channel_rx)
+            });
+            copy_threads.push(copy_thread);
+        }
+    }
 
-        let copy_thread = thread::spawn(move || {
-            threads::copy(copy_run_ctx_clone, log_warn, copy_file_req_channel_rx);
-        });
-        copy_threads.push(copy_thread);
+    let mut progress_thread = None;
+    if copy_options.progress {
+        let progress_interval = copy_options.progress_interval;
+        progress_thread = Some(thread::spawn(move || {
+            threads::progress(
+                progress_run_ctx_clone,
+                log_progress,
+                progress,
+                progress_interval,
+            );
+        }));
     }
 
     match discovery_thread.join() {
@@ -266,9 +641,11 @@ pub fn copy(
         hash_run_ctx.shutdown();
     }
 
+    // damaged files found by the hash threads, with check
+    let mut check_result = MergeResult::Ok;
     for hash_thread in hash_threads {
         match hash_thread.join() {
-            Ok(_) => {}
+            Ok(cur_result) => check_result = threads::max_merge_result(&check_result, &cur_result),
             Err(_) => {
                 if root_run_ctx.is_clean() {
                     let err = format!("error in hash_thread.join()");
@@ -286,9 +663,11 @@ pub fn copy(
         copy_run_ctx.shutdown();
     }
 
+    // files copied without some of their attributes
+    let mut copy_result = MergeResult::Ok;
     for copy_thread in copy_threads {
         match copy_thread.join() {
-            Ok(_) => {}
+            Ok(cur_result) => copy_result = threads::max_merge_result(&copy_result, &cur_result),
             Err(_) => {
                 if root_run_ctx.is_clean() {
                     let err = format!("error in copy_thread.join()");
@@ -299,9 +678,121 @@ pub fn copy(
         }
     }
 
+    // now that all of the files have been written, give the destination directories the same
+    // times as their source directories (writing a file into a directory updates its mtime)
+    // with a layout, the destination directories don't correspond to source directories
+    if root_run_ctx.is_clean() && copy_options.layout.is_none() {
+        copy_dir_time_metadata(log_warn, &root_run_ctx, &read_paths, &write_paths);
+    }
+
+    progress_run_ctx.shutdown();
+    if let Some(progress_thread) = progress_thread {
+        if progress_thread.join().is_err() && root_run_ctx.is_clean() {
+            log_warn("error in progress_thread.join()");
+            root_run_ctx.unclean_shutdown();
+        }
+    }
+
+    if root_run_ctx.is_cancelled() {
+        return Ok(MergeResult::Cancelled);
+    }
+
     if !root_run_ctx.is_clean() {
         return Ok(MergeResult::Error);
     }
 
-    Ok(merge_result.unwrap())
+    let merge_result = threads::max_merge_result(&merge_result.unwrap(), &check_result);
+    let merge_result = threads::max_merge_result(&merge_result, &copy_result);
+    Ok(threads::max_merge_result(&merge_result, &spanned_result))
+}
+
+/**
+ * Copy the access and modification times from each source directory to the matching directory
+ * in each write path, if it exists.
+ *
+ * Must be called after all of the files have been copied.
+ */
+fn copy_dir_time_metadata(
+    log_warn: fn(&str),
+    thread_run_ctx: &ThreadRunContext,
+    read_paths: &Vec<&str>,
+    write_paths: &Vec<&str>,
+) {
+    let dirs = match discover::find_dirs(read_paths) {
+        Ok(dirs) => dirs,
+        Err(e) => {
+            let err = format!("error finding source directories: '{}'", e);
+            log_warn(&err);
+            thread_run_ctx.unclean_shutdown();
+            return;
+        }
+    };
+
+    for (sub_path, src_dir) in &dirs {
+        for write_path in write_paths {
+            let mut dest_dir = PathBuf::from(write_path);
+            dest_dir.push(sub_path);
+
+            if !dest_dir.is_dir() {
+                continue;
+            }
+
+            if let Err(e) = fsutil::copy_file_time_metadata(src_dir, &dest_dir) {
+                let err = format!(
+                    "error copying directory time metadata from '{}' to '{}': '{}'",
+                    src_dir.to_str().unwrap(),
+                    dest_dir.to_str().unwrap(),
+                    e
+                );
+                log_warn(&err);
+                thread_run_ctx.unclean_shutdown();
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn dedupe_skip_family() {
+        let dir = std::env::temp_dir().join(format!("dit-test-dedupe-{}", std::process::id()));
+        let src = dir.join("src");
+        let dest = dir.join("dest");
+        fs::create_dir_all(src.join("a")).unwrap();
+        fs::create_dir_all(src.join("b")).unwrap();
+
+        // a raw file and its JPEG, and a copy of the same raw file with a different JPEG
+        fs::write(src.join("a/DSC1.RAF"), "raw").unwrap();
+        fs::write(src.join("a/DSC1.JPG"), "jpeg").unwrap();
+        fs::write(src.join("b/DSC2.RAF"), "raw").unwrap();
+        fs::write(src.join("b/DSC2.JPG"), "edited jpeg").unwrap();
+
+        let read_write_paths = ReadWritePaths {
+            read_paths: vec![src.clone()],
+            write_paths: vec![dest.clone()],
+            read_volumes: vec![Volume::default()],
+        };
+        let copy_options = get_cli_copy_options(&[String::from("--dedupe=skip")]).unwrap();
+        let result = copy(
+            |_| {},
+            |_| {},
+            |_| {},
+            &read_write_paths,
+            &copy_options,
+            &CancelHandle::new(),
+        )
+        .unwrap();
+
+        assert!(MergeResult::Ok == result);
+        assert!(dest.join("a/DSC1.RAF").exists());
+        assert!(dest.join("a/DSC1.JPG").exists());
+        assert!(!dest.join("b/DSC2.RAF").exists());
+        assert!(dest.join("b/DSC2.JPG").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
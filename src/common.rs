@@ -1,10 +1,16 @@
+use crate::discover::ScanTotals;
 use crate::fsutil;
+use crate::media;
+use crate::volume::Volume;
+use std::collections::{BTreeMap, BTreeSet};
 use std::error::Error;
 use std::fmt::{Display, Formatter};
+use std::fs;
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 // queue sizes
 // the queues that are small refer to files that might be in the OS page cache
@@ -51,6 +57,161 @@ pub fn dit_error<T>(s: &str) -> Result<T, Box<dyn Error>> {
 pub struct ReadWritePaths {
     pub read_paths: Vec<PathBuf>,
     pub write_paths: Vec<PathBuf>,
+
+    // card or volume that each read path is on, in the same order as read_paths (copy() looks up
+    // any that are missing, once the paths are known to be valid)
+    pub read_volumes: Vec<Volume>,
+}
+
+/**
+ * Which attributes of each source file to carry over to its copies, besides the access and
+ * modification times, which are always copied.
+ */
+#[derive(Clone, Default)]
+pub struct Preserve {
+    // permission bits, instead of the fixed 0644
+    pub mode: bool,
+
+    // user and group, when running as root
+    pub owner: bool,
+
+    // user.* extended attributes, and security.* ones when running as root
+    pub xattr: bool,
+
+    // POSIX access control lists
+    pub acl: bool,
+}
+
+/**
+ * How to tell whether an existing destination file is the same file as the source file with the
+ * same sub path, so it doesn't need to be copied again.
+ */
+#[derive(Clone, Copy, PartialEq)]
+pub enum MatchBy {
+    // the same size (cameras that roll their file numbers over can give two different photos the
+    // same name and size, though)
+    Size,
+
+    // the same size and capture time (see media::capture_info())
+    Time,
+
+    // the same size and contents
+    Hash,
+}
+
+/**
+ * What to do with a file that has the same contents as a file that was already copied under a
+ * different sub path (e.g. a photo exported from a phone, and the same photo from the card).
+ */
+#[derive(Clone, Copy, PartialEq)]
+pub enum Dedupe {
+    // copy it anyway, with a warning
+    Report,
+
+    // don't copy it
+    Skip,
+
+    // hard link it to the first copy, in each destination
+    Link,
+}
+
+/**
+ * Optional settings for a copy operation. The defaults match the original behavior of dit.
+ */
+#[derive(Clone)]
+pub struct CopyOptions {
+    // call log_progress periodically while copying?
+    pub progress: bool,
+
+    // how often to call log_progress
+    pub progress_interval: Duration,
+
+    // start copying even if a destination looks like it doesn't have enough free space
+    pub force: bool,
+
+    // file attributes to copy over from each source file
+    pub preserve: Preserve,
+
+    // permissions for copied files, instead of 0644 (can't be used with preserve.mode)
+    pub file_mode: Option<u32>,
+
+    // permissions for directories that get created, instead of the defaults from the umask
+    pub dir_mode: Option<u32>,
+
+    // log each file as it is copied, along with how it was copied
+    pub verbose: bool,
+
+    // destination layout template (see layout::Layout), or None to mirror the source sub paths
+    pub layout: Option<String>,
+
+    // read each copy back from the destination device, and compare its hash to the source file
+    pub verify: bool,
+
+    // size of the buffer used to read files for hashing, and for plain read/write copies
+    pub buf_size: usize,
+
+    // number of hash threads reading from each source device at once
+    pub hash_threads: usize,
+
+    // number of copy threads writing to each device at once
+    pub device_threads: usize,
+
+    // overrides for device_threads, for the device that each path is on
+    pub device_threads_by_path: Vec<(PathBuf, usize)>,
+
+    // how to tell that an existing destination file with the same name is the same file
+    pub match_by: MatchBy,
+
+    // hash every file, and deal with files that have the same contents under different sub paths
+    pub dedupe: Option<Dedupe>,
+
+    // keep a ledger of ingested files at the top of each write path, and don't copy files that
+    // are already in it again (see ledger::Ledger)
+    pub ledger: bool,
+
+    // only copy photo, video and audio files, and their sidecars (see media::classify())
+    pub media_only: bool,
+
+    // check the structure of JPEG and MP4/MOV files as they are hashed (see integrity::check())
+    pub check: bool,
+
+    // write an XMP sidecar with the provenance of each photo and video (see xmp::Sidecars)
+    pub xmp: bool,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        CopyOptions {
+            progress: false,
+            progress_interval: Duration::from_secs(1),
+            force: false,
+            preserve: Preserve::default(),
+            file_mode: None,
+            dir_mode: None,
+            verbose: false,
+            layout: None,
+            verify: false,
+            buf_size: fsutil::DEFAULT_BUF_SIZE,
+            hash_threads: 1,
+            device_threads: 1,
+            device_threads_by_path: vec![],
+            match_by: MatchBy::Size,
+            dedupe: None,
+            ledger: false,
+            media_only: false,
+            check: false,
+            xmp: false,
+        }
+    }
+}
+
+impl CopyOptions {
+    /**
+     * Does every file need to be hashed, even when there is only one copy of it to compare?
+     */
+    pub fn hash_all(&self) -> bool {
+        self.dedupe.is_some() || self.ledger || self.check || self.xmp
+    }
 }
 
 /**
@@ -61,6 +222,45 @@ pub enum MergeResult {
     Ok,
     Conflict,
     Error,
+    Cancelled,
+}
+
+/**
+ * Handle that lets the caller cancel a running copy operation from another thread
+ * (e.g. a signal handler thread).
+ *
+ * Cancelling stops discovery and any pending transfers. Copies that are already in flight are
+ * rolled back, and their temp files are removed, so the destination directories are left with
+ * only complete files.
+ */
+#[derive(Clone)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn new() -> CancelHandle {
+        CancelHandle {
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /**
+     * Request cancellation. Safe to call more than once, and from any thread.
+     */
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for CancelHandle {
+    fn default() -> Self {
+        CancelHandle::new()
+    }
 }
 
 /**
@@ -73,13 +273,17 @@ pub struct ThreadRunContext {
 
     // once the thread stops running, should the thread finish any pending work before it stops?
     clean: Arc<AtomicBool>,
+
+    // has the caller asked us to stop? shared by every context in the tree
+    cancel_handle: CancelHandle,
 }
 
 impl ThreadRunContext {
-    pub fn new() -> ThreadRunContext {
+    pub fn new(cancel_handle: &CancelHandle) -> ThreadRunContext {
         ThreadRunContext {
             running: Arc::new(AtomicBool::new(true)),
             clean: Arc::new(AtomicBool::new(true)),
+            cancel_handle: cancel_handle.clone(),
         }
     }
 
@@ -89,17 +293,23 @@ impl ThreadRunContext {
         ThreadRunContext {
             running: Arc::new(AtomicBool::new(true)),
             clean,
+            cancel_handle: parent.cancel_handle.clone(),
         }
     }
 
+    // a cancelled context is neither running nor clean, so threads stop without draining
     pub fn is_running(&self) -> bool {
         let atomic_boolean = self.running.clone();
-        return atomic_boolean.load(Ordering::Relaxed);
+        atomic_boolean.load(Ordering::Relaxed) && !self.is_cancelled()
     }
 
     pub fn is_clean(&self) -> bool {
         let atomic_boolean = self.clean.clone();
-        return atomic_boolean.load(Ordering::Relaxed);
+        atomic_boolean.load(Ordering::Relaxed) && !self.is_cancelled()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel_handle.is_cancelled()
     }
 
     pub fn shutdown(&self) {
@@ -122,12 +332,15 @@ impl ThreadRunContext {
  * Read paths are verified, to make sure they exist and are directories.
  *
  * Write paths are verified, to make sure they exist and are directories. If write paths do not
- * exist, this function attempts to non-recursively create them.
+ * exist, this function attempts to non-recursively create them, with dir_mode if given.
+ *
+ * No path may be the same directory as, or be nested inside of, any other read or write path.
  *
  * If anything goes wrong, an Error is returned.
  */
 pub fn ensure_valid_read_write_paths(
     read_write_paths: &ReadWritePaths,
+    dir_mode: Option<u32>,
 ) -> Result<(), Box<dyn Error>> {
     // make sure all read paths exists, and are valid directories
     for read_path in &read_write_paths.read_paths {
@@ -155,6 +368,9 @@ pub fn ensure_valid_read_write_paths(
         }
     }
 
+    // write paths that we created, so we can clean them up if the paths overlap
+    let mut created_write_paths = vec![];
+
     // make sure all write paths exist, creating them (non-recursively) if necessary
     for write_path in &read_write_paths.write_paths {
         // special case: disallow root path
@@ -173,8 +389,8 @@ pub fn ensure_valid_read_write_paths(
             }
         } else {
             // if the write path doesn't exist, create it
-            match fsutil::mkdir(write_path) {
-                Ok(_) => {}
+            match fsutil::mkdir(write_path, dir_mode) {
+                Ok(_) => created_write_paths.push(write_path),
                 Err(e) => {
                     return dit_error(&e.to_string());
                 }
@@ -182,19 +398,241 @@ pub fn ensure_valid_read_write_paths(
         }
     }
 
+    let result = ensure_no_overlapping_paths(read_write_paths);
+    if result.is_err() {
+        // don't leave empty directories behind inside of the read paths, in reverse order so
+        // nested write paths are removed first
+        for write_path in created_write_paths.iter().rev() {
+            let _ = fs::remove_dir(write_path);
+        }
+    }
+
+    result
+}
+
+// (device, inode) pair that identifies a directory, no matter what name it was reached by
+type DevIno = (u64, u64);
+
+/**
+ * Make sure that none of the read and write paths are the same directory as, or inside of, any
+ * of the others.
+ *
+ * Paths are canonicalized, and then compared by device and inode, so the same directory under
+ * another name (a symlink, a bind mount, "./" etc.) is caught too. The same read path given
+ * twice is also rejected, because it would look like two copies that agree with each other.
+ */
+fn ensure_no_overlapping_paths(read_write_paths: &ReadWritePaths) -> Result<(), Box<dyn Error>> {
+    // (kind, path as given, device and inode of the path and all of its ancestors)
+    let mut roots: Vec<(&str, &Path, Vec<DevIno>)> = vec![];
+
+    let read_paths = read_write_paths.read_paths.iter().map(|p| ("read", p));
+    let write_paths = read_write_paths.write_paths.iter().map(|p| ("write", p));
+    for (kind, path) in read_paths.chain(write_paths) {
+        let canonical_path = match path.canonicalize() {
+            Ok(canonical_path) => canonical_path,
+            Err(e) => {
+                let err_msg = format!(
+                    "could not resolve {} path: '{}': {}",
+                    kind,
+                    path.to_str().unwrap(),
+                    e
+                );
+                return dit_error(&err_msg);
+            }
+        };
+
+        // the first entry is the path itself, followed by its parent, and so on up to "/"
+        let mut ids = vec![];
+        for ancestor in canonical_path.ancestors() {
+            let metadata = ancestor.metadata()?;
+            ids.push((metadata.dev(), metadata.ino()));
+        }
+
+        roots.push((kind, path, ids));
+    }
+
+    for (i, (kind_a, path_a, ids_a)) in roots.iter().enumerate() {
+        for (kind_b, path_b, ids_b) in roots.iter().skip(i + 1) {
+            let path_a = path_a.to_str().unwrap();
+            let path_b = path_b.to_str().unwrap();
+
+            if ids_a[0] == ids_b[0] {
+                let err_msg = if kind_a == kind_b {
+                    format!(
+                        "{} path given more than once: '{}' and '{}' are the same directory",
+                        kind_a, path_a, path_b
+                    )
+                } else {
+                    format!(
+                        "{} path '{}' and {} path '{}' are the same directory",
+                        kind_a, path_a, kind_b, path_b
+                    )
+                };
+                return dit_error(&err_msg);
+            }
+
+            if ids_b.contains(&ids_a[0]) {
+                let err_msg = format!(
+                    "{} path '{}' is inside {} path '{}'",
+                    kind_b, path_b, kind_a, path_a
+                );
+                return dit_error(&err_msg);
+            }
+
+            if ids_a.contains(&ids_b[0]) {
+                let err_msg = format!(
+                    "{} path '{}' is inside {} path '{}'",
+                    kind_a, path_a, kind_b, path_b
+                );
+                return dit_error(&err_msg);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Is there enough free space on each destination filesystem for everything we expect to write?
+ *
+ * Write paths that share a filesystem have their totals added together before comparing them
+ * to the free space on that filesystem.
+ *
+ * If a filesystem would fill up, an Error is returned, unless force is set, in which case a
+ * warning is logged instead.
+ */
+pub fn ensure_enough_free_space(
+    log_warn: fn(&str),
+    read_write_paths: &ReadWritePaths,
+    scan_totals: &ScanTotals,
+    force: bool,
+) -> Result<(), Box<dyn Error>> {
+    // map of device id -> (bytes needed, write paths on that device)
+    let mut devices: BTreeMap<u64, (u64, Vec<&str>)> = BTreeMap::new();
+
+    for (i, write_path) in read_write_paths.write_paths.iter().enumerate() {
+        let dev = write_path.metadata()?.dev();
+        let device = devices.entry(dev).or_insert((0, vec![]));
+        device.0 += scan_totals.dest_bytes[i];
+        device.1.push(write_path.to_str().unwrap());
+    }
+
+    for (bytes_needed, write_paths) in devices.values() {
+        let bytes_free = match fsutil::free_space(Path::new(write_paths[0])) {
+            Ok(bytes_free) => bytes_free,
+            Err(e) => {
+                let err_msg = format!(
+                    "could not get free space for write path: '{}': {}",
+                    write_paths[0], e
+                );
+                return dit_error(&err_msg);
+            }
+        };
+
+        if *bytes_needed > bytes_free {
+            let err_msg = format!(
+                "not enough free space for write path: '{}': need {} bytes, {} bytes free",
+                write_paths.join("', '"),
+                bytes_needed,
+                bytes_free
+            );
+            if !force {
+                return dit_error(&err_msg);
+            }
+            log_warn(&err_msg);
+        }
+    }
+
     Ok(())
 }
 
+// (device id, indexes into a list of paths on that device)
+pub type DeviceGroup = (u64, Vec<usize>);
+
+/**
+ * Group paths by the device they are on.
+ *
+ * Returns a list of (device id, indexes into paths), in the order each device was first seen.
+ */
+pub fn group_by_device(paths: &[PathBuf]) -> Result<Vec<DeviceGroup>, Box<dyn Error>> {
+    let mut groups: Vec<DeviceGroup> = vec![];
+
+    for (i, path) in paths.iter().enumerate() {
+        let dev = path.metadata()?.dev();
+        match groups.iter_mut().find(|(group_dev, _)| *group_dev == dev) {
+            Some((_, indexes)) => indexes.push(i),
+            None => groups.push((dev, vec![i])),
+        }
+    }
+
+    Ok(groups)
+}
+
+/**
+ * How many copy threads should write to the given device at once?
+ */
+pub fn get_device_threads(copy_options: &CopyOptions, dev: u64) -> Result<usize, Box<dyn Error>> {
+    for (path, threads) in &copy_options.device_threads_by_path {
+        match path.metadata() {
+            Ok(metadata) => {
+                if metadata.dev() == dev {
+                    return Ok(*threads);
+                }
+            }
+            Err(e) => {
+                let err_msg = format!(
+                    "could not find device for path: '{}': {}",
+                    path.to_str().unwrap(),
+                    e
+                );
+                return dit_error(&err_msg);
+            }
+        }
+    }
+
+    Ok(copy_options.device_threads)
+}
+
+/**
+ * Get the stem that groups a file with the other files that belong to the same photo or clip,
+ * like a raw file with its JPEG and XMP sidecar (DSCF1234.RAF, DSCF1234.JPG, and DSCF1234.xmp or
+ * DSCF1234.RAF.xmp), or a clip with its metadata (C0001.MP4 and C0001M01.XML).
+ *
+ * This is the file name up to the first '.', without the "M01" suffix of XML clip metadata.
+ * Hidden files are their own family.
+ */
+pub fn family_stem(file_name: &str) -> &str {
+    let (stem, extension) = match file_name.find('.') {
+        Some(0) | None => return file_name,
+        Some(i) => (&file_name[..i], &file_name[i + 1..]),
+    };
+
+    if extension.eq_ignore_ascii_case("xml") {
+        let b = stem.as_bytes();
+        let n = b.len();
+        if n > 3 && b'M' == b[n - 3] && b[n - 2].is_ascii_digit() && b[n - 1].is_ascii_digit() {
+            return &stem[..n - 3];
+        }
+    }
+
+    stem
+}
+
 /**
  * Does the given set of source path and write paths have a write merge conflict?
  *
  * Checks filesystem metadata on the source path, and all existing destination paths, to see if
- * the files are all the same size.
+ * the files are all the same size. Files of the same size are also compared by match_by.
+ *
+ * With MatchBy::Hash, src_hash is used as the source file's hash if it is known, or else set to
+ * the hash if the source file had to be hashed, so it's only hashed once.
  */
 pub fn has_write_merge_conflict(
     write_paths: &Vec<String>,
     src_path: &Path,
     sub_path: &str,
+    match_by: MatchBy,
+    src_hash: &mut Option<String>,
 ) -> bool {
     match src_path.metadata() {
         Ok(src_metadata) => {
@@ -210,6 +648,23 @@ pub fn has_write_merge_conflict(
                                 // it's a write merge conflict
                                 return true;
                             }
+
+                            // same size, but a different file (e.g. a camera's file
+                            // numbers rolled over), it's a write merge conflict
+                            if MatchBy::Hash == match_by {
+                                if src_hash.is_none() {
+                                    match fsutil::hash_file(src_path) {
+                                        Ok(hash) => *src_hash = Some(hash),
+                                        Err(_) => return true,
+                                    }
+                                }
+                                match fsutil::hash_file(&dest_path) {
+                                    Ok(hash) if src_hash.as_ref() == Some(&hash) => {}
+                                    _ => return true,
+                                }
+                            } else if !same_files(match_by, &[src_path.to_path_buf(), dest_path]) {
+                                return true;
+                            }
                         }
                         Err(_) => {
                             // if the dest path exists, but we can't read its metadata,
@@ -238,30 +693,49 @@ pub fn all_files_match(
     read_paths: &Vec<&str>,
     write_paths: &Vec<&str>,
     sub_path_plus_dirent: &str,
+    match_by: MatchBy,
 ) -> bool {
-    let mut found_read_file = false;
-    let mut file_size = 0;
-
+    let mut src_paths = vec![];
     for read_path in read_paths {
         let mut path_buf = PathBuf::from(read_path);
         path_buf.push(sub_path_plus_dirent);
 
         if path_buf.exists() {
-            match path_buf.metadata() {
-                Ok(metadata) => {
-                    if !found_read_file {
-                        // set the comparison file size to the first file size we see
-                        file_size = metadata.size();
-                        found_read_file = true;
-                    } else if metadata.size() != file_size {
-                        // read file sizes differ
-                        return false;
-                    }
-                }
-                Err(_) => {
+            src_paths.push(path_buf);
+        }
+    }
+
+    all_dest_files_match(&src_paths, write_paths, sub_path_plus_dirent, match_by)
+}
+
+/**
+ * Same as all_files_match(), for the given source files, which don't have to be at the same sub
+ * path as the destination files.
+ */
+pub fn all_dest_files_match(
+    src_paths: &[PathBuf],
+    write_paths: &Vec<&str>,
+    sub_path: &str,
+    match_by: MatchBy,
+) -> bool {
+    let mut found_read_file = false;
+    let mut file_size = 0;
+
+    for path_buf in src_paths {
+        match path_buf.metadata() {
+            Ok(metadata) => {
+                if !found_read_file {
+                    // set the comparison file size to the first file size we see
+                    file_size = metadata.size();
+                    found_read_file = true;
+                } else if metadata.size() != file_size {
+                    // read file sizes differ
                     return false;
                 }
             }
+            Err(_) => {
+                return false;
+            }
         }
     }
 
@@ -270,14 +744,16 @@ pub fn all_files_match(
         panic!();
     }
 
+    let mut all_paths = src_paths.to_vec();
     for write_path in write_paths {
         let mut path_buf = PathBuf::from(write_path);
-        path_buf.push(sub_path_plus_dirent);
+        path_buf.push(sub_path);
 
         // file must exist in all destination paths to be considered a match
         if !path_buf.exists() {
             return false;
         }
+        all_paths.push(path_buf.clone());
 
         match path_buf.metadata() {
             Ok(metadata) => {
@@ -293,5 +769,72 @@ pub fn all_files_match(
     }
 
     // we have at least one read file, and all write files, and they're all the same size
-    true
+    same_files(match_by, &all_paths)
+}
+
+/**
+ * Are the given files, which are already known to be the same size, the same by match_by?
+ *
+ * With MatchBy::Hash, every file is read in full.
+ */
+pub fn same_files(match_by: MatchBy, paths: &[PathBuf]) -> bool {
+    match match_by {
+        MatchBy::Size => true,
+        MatchBy::Time => {
+            let mut times = BTreeSet::new();
+            for path in paths {
+                match media::capture_info(path) {
+                    Ok(capture_info) => times.insert(capture_info.time),
+                    Err(_) => return false,
+                };
+            }
+            times.len() <= 1
+        }
+        MatchBy::Hash => {
+            let mut hashes = BTreeSet::new();
+            for path in paths {
+                match fsutil::hash_file(path) {
+                    Ok(hash) => hashes.insert(hash),
+                    Err(_) => return false,
+                };
+            }
+            hashes.len() <= 1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn family_stem_of_photos_and_sidecars() {
+        assert_eq!("DSCF1234", family_stem("DSCF1234.RAF"));
+        assert_eq!("DSCF1234", family_stem("DSCF1234.JPG"));
+        assert_eq!("DSCF1234", family_stem("DSCF1234.xmp"));
+        assert_eq!("DSCF1234", family_stem("DSCF1234.RAF.xmp"));
+        assert_eq!("IMG_0001", family_stem("IMG_0001.CR3"));
+    }
+
+    #[test]
+    fn family_stem_of_clip_metadata() {
+        assert_eq!("C0001", family_stem("C0001.MP4"));
+        assert_eq!("C0001", family_stem("C0001M01.XML"));
+        assert_eq!("C0001", family_stem("C0001M01.xml"));
+
+        // only an M and two digits is the metadata suffix, and only on XML files
+        assert_eq!("C0001M1", family_stem("C0001M1.XML"));
+        assert_eq!("C0001X01", family_stem("C0001X01.XML"));
+        assert_eq!("C0001M01", family_stem("C0001M01.MP4"));
+        assert_eq!("M01", family_stem("M01.XML"));
+    }
+
+    #[test]
+    fn family_stem_of_other_names() {
+        assert_eq!("README", family_stem("README"));
+        assert_eq!(".DS_Store", family_stem(".DS_Store"));
+        assert_eq!(".hidden.jpg", family_stem(".hidden.jpg"));
+        assert_eq!("archive", family_stem("archive.tar.gz"));
+        assert_eq!("trailing", family_stem("trailing."));
+    }
 }
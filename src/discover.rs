@@ -1,10 +1,15 @@
 use crate::common;
-use crate::common::{dit_error, ThreadRunContext};
+use crate::common::{dit_error, MatchBy, ThreadRunContext};
+use crate::fsutil;
+use crate::layout::Layout;
+use crate::media;
+use crate::media::{Classification, ClipName};
 use crate::message::{CopyToDestRequest, HashRequest, TransferRequest};
-use std::collections::{BTreeSet, HashSet};
+use crate::threads::DiscoverContext;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::error::Error;
-use std::path::PathBuf;
-use std::sync::mpsc::SyncSender;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 
 /**
  * Recursively discover all of the files in the read paths that we want to copy, and send them
@@ -20,48 +25,1226 @@ use std::sync::mpsc::SyncSender;
  *
  * If a HashRequest (or None) was sent to each hash request queue, a TransferRequest of type Merge
  * is also sent.
+ *
+ * With copy_options.dedupe, every file is sent to be hashed, even if it was only found in one read
+ * path or was already copied, so the merge thread can spot files with the same contents under
+ * different sub paths. Likewise, every file that gets this far is sent to be hashed with
+ * copy_options.ledger (so the copy threads can look it up in the ledger), copy_options.check (so
+ * the hash threads can check its structure), and copy_options.xmp (so its hash can go in its
+ * sidecar). See CopyOptions::hash_all().
+ *
+ * Files in the same directory that belong together, like a raw file and its JPEG and XMP sidecar
+ * (see common::family_stem()), are sent one after another, after a TransferRequest of type Group,
+ * so that a merge conflict in any of them renames all of them the same way. If any file in the
+ * family needs to be copied, they all go through the merge thread.
+ *
+ * With a layout (when scan() was given one), files are matched up across the read paths by the
+ * sub path the layout gives them, instead of their sub path in the read paths. If the layout gives
+ * more than one file in the same read path the same sub path, they are all sent to be hashed, in
+ * rounds of one HashRequest (or None) per read path, followed by a TransferRequest of type
+ * Merge(n) for the n rounds, so each distinct version is copied as a read merge conflict.
+ * Families are placed together (see Layout::sub_paths()), and sent together by the directory and
+ * family stem they end up with, unless one of their files has more than one version like that.
  */
 pub fn discover_files(
     thread_run_ctx: &ThreadRunContext,
-    xfer_req_tx: &SyncSender<TransferRequest>,
-    copy_to_dest_req_tx: &SyncSender<CopyToDestRequest>,
-    hash_req_tx_vec: &Vec<&SyncSender<Option<HashRequest>>>,
+    discover_ctx: &DiscoverContext,
+) -> Result<(), Box<dyn Error>> {
+    let mut read_paths = vec![];
+    for read_path in &discover_ctx.read_paths {
+        read_paths.push(read_path.as_str());
+    }
+
+    let mut write_paths = vec![];
+    for write_path in &discover_ctx.write_paths {
+        write_paths.push(write_path.as_str());
+    }
+
+    if let Some(layout_plan) = &discover_ctx.scan_plan.layout_plan {
+        return discover_layout_files(thread_run_ctx, discover_ctx, &write_paths, layout_plan);
+    }
+
+    __discover_files(thread_run_ctx, discover_ctx, &read_paths, &write_paths, "")
+}
+
+/**
+ * Totals for how much data a copy is expected to write to each destination.
+ *
+ * The list order of write_paths is the index into each vector.
+ */
+pub struct ScanTotals {
+    pub dest_files: Vec<u64>,
+    pub dest_bytes: Vec<u64>,
+
+    // can each destination's filesystem keep the holes in sparse files? (see fsutil::written_len())
+    pub dest_keeps_holes: Vec<bool>,
+}
+
+/**
+ * What scan() found out about the files besides the totals, which discover_files() works from
+ * instead of finding it out again.
+ */
+#[derive(Default)]
+pub struct ScanPlan {
+    // with MatchBy::Hash, the sub paths whose source and destination files all have the same
+    // contents, so they are skipped without being hashed again (the other ways of matching files
+    // are cheap enough to repeat)
+    matched: HashSet<String>,
+
+    // with media_only, every source file that is skipped (see check_media())
+    non_media: HashSet<PathBuf>,
+
+    // with a layout, the files for each sub path it gives, so they're only read and placed once
+    layout_plan: Option<LayoutPlan>,
+}
+
+impl ScanPlan {
+    /**
+     * Do the source files for the given sub path all match the destination files, so they can
+     * be skipped (see common::all_dest_files_match())?
+     */
+    fn all_dest_files_match(
+        &self,
+        src_paths: &[PathBuf],
+        write_paths: &Vec<&str>,
+        sub_path: &str,
+        match_by: MatchBy,
+    ) -> bool {
+        match match_by {
+            MatchBy::Hash => self.matched.contains(sub_path),
+            _ => common::all_dest_files_match(src_paths, write_paths, sub_path, match_by),
+        }
+    }
+
+    /**
+     * Same as all_dest_files_match(), for the source files at the same sub path in each read path
+     * (see common::all_files_match()).
+     */
+    fn all_files_match(
+        &self,
+        read_paths: &Vec<&str>,
+        write_paths: &Vec<&str>,
+        sub_path: &str,
+        match_by: MatchBy,
+    ) -> bool {
+        match match_by {
+            MatchBy::Hash => self.matched.contains(sub_path),
+            _ => common::all_files_match(read_paths, write_paths, sub_path, match_by),
+        }
+    }
+}
+
+/**
+ * Walk the read paths the same way discover_files() does, without sending anything anywhere,
+ * and total up the files and bytes that will be written to each destination.
+ *
+ * Files that all_files_match() would skip, and destination files that already exist with the
+ * same size (and match by match_by), are not counted, unless they're in a family of files that
+ * will be renamed because of a merge conflict. Sparse files are counted by the space their data
+ * takes up, since their holes are not written out, except in destinations whose filesystem
+ * fills them in (see fsutil::keeps_holes()). Each version of a file involved in a read merge
+ * conflict is counted, as long as the versions have different sizes (versions with the same size
+ * can't be told apart without hashing them, so they're counted once).
+ *
+ * non_media has the source files to skip with media_only (see check_media()), which
+ * discover_files() skips too.
+ *
+ * With a layout, a file that it can't place (see Layout::sub_path()) is shown as a warning and
+ * skipped.
+ */
+pub fn scan(
+    log_warn: fn(&str),
+    read_paths: &Vec<&str>,
+    write_paths: &Vec<&str>,
+    layout: Option<&Layout>,
+    match_by: MatchBy,
+    non_media: HashSet<PathBuf>,
+) -> Result<(ScanTotals, ScanPlan), Box<dyn Error>> {
+    let mut scan_totals = ScanTotals {
+        dest_files: vec![0; write_paths.len()],
+        dest_bytes: vec![0; write_paths.len()],
+        dest_keeps_holes: write_paths
+            .iter()
+            .map(|write_path| fsutil::keeps_holes(Path::new(write_path)))
+            .collect(),
+    };
+    let mut scan_plan = ScanPlan {
+        non_media,
+        ..Default::default()
+    };
+
+    match layout {
+        Some(layout) => {
+            let layout_plan = plan_layout(log_warn, read_paths, layout, &scan_plan.non_media)?;
+            for family in find_layout_families(&layout_plan).values() {
+                let mut members = vec![];
+                for &sub_path in family {
+                    // files from the same read path that land on the same sub path are kept as
+                    // separate versions (see send_layout_versions())
+                    let files = &layout_plan[sub_path];
+                    let src_paths: Vec<PathBuf> = files.iter().flatten().cloned().collect();
+                    let all_match = !has_versions(files)
+                        && common::all_dest_files_match(
+                            &src_paths,
+                            write_paths,
+                            sub_path,
+                            match_by,
+                        );
+                    if all_match && MatchBy::Hash == match_by {
+                        scan_plan.matched.insert(String::from(sub_path));
+                    }
+                    members.push((sub_path, src_paths, has_versions(files), all_match));
+                }
+
+                // the same as in __scan(), for the families that discover_layout_files() sends
+                // together
+                let mut renamed = false;
+                if members.len() > 1 && !members.iter().any(|(_, _, versions, _)| *versions) {
+                    for (sub_path, src_paths, _, _) in &members {
+                        if scan_conflict(write_paths, sub_path, src_paths, match_by)? {
+                            renamed = true;
+                            break;
+                        }
+                    }
+                }
+
+                for (sub_path, src_paths, versions, all_match) in &members {
+                    if *all_match && !renamed {
+                        continue;
+                    }
+
+                    scan_file(
+                        write_paths,
+                        sub_path,
+                        src_paths,
+                        match_by,
+                        renamed || *versions,
+                        &mut scan_totals,
+                    )?;
+                }
+            }
+            scan_plan.layout_plan = Some(layout_plan);
+        }
+        None => __scan(
+            read_paths,
+            write_paths,
+            "",
+            match_by,
+            &mut scan_totals,
+            &mut scan_plan,
+        )?,
+    }
+
+    Ok((scan_totals, scan_plan))
+}
+
+// recursive implementation of public scan() function
+fn __scan(
     read_paths: &Vec<&str>,
     write_paths: &Vec<&str>,
+    sub_path: &str,
+    match_by: MatchBy,
+    scan_totals: &mut ScanTotals,
+    scan_plan: &mut ScanPlan,
 ) -> Result<(), Box<dyn Error>> {
-    __discover_files(
-        thread_run_ctx,
-        xfer_req_tx,
-        copy_to_dest_req_tx,
-        hash_req_tx_vec,
-        read_paths,
-        write_paths,
-        "",
-    )
+    let (all_dirent_maps, read_path_dirent_maps) = read_dirents(read_paths, sub_path)?;
+
+    let mut dirents = vec![];
+    for dirent_str in &all_dirent_maps {
+        let sub_path_plus_dirent = join_sub_path(sub_path, dirent_str);
+        let dirent_instances = find_dirent_instances(
+            read_paths,
+            &read_path_dirent_maps,
+            dirent_str,
+            &sub_path_plus_dirent,
+        )?;
+
+        dirents.push((sub_path_plus_dirent, dirent_instances));
+    }
+
+    // with media_only, skip the same files that __discover_files() does
+    let non_media = find_non_media_dirents(&dirents, &scan_plan.non_media);
+
+    // files, in the same families that __discover_files() sends together
+    for family in find_families(&all_dirent_maps, &dirents).values() {
+        if family.iter().all(|j| non_media.contains(j)) {
+            continue;
+        }
+
+        let mut members = vec![];
+        for &j in family {
+            let (sub_path_plus_dirent, dirent_instances) = &dirents[j];
+            let all_match =
+                common::all_files_match(read_paths, write_paths, sub_path_plus_dirent, match_by);
+            if all_match && MatchBy::Hash == match_by {
+                scan_plan.matched.insert(sub_path_plus_dirent.clone());
+            }
+
+            let src_paths: Vec<PathBuf> = dirent_instances
+                .files_found_or_placeholders
+                .iter()
+                .flatten()
+                .cloned()
+                .collect();
+            members.push((sub_path_plus_dirent.as_str(), src_paths, all_match));
+        }
+
+        // a merge conflict in one file of a family renames all of them, even the ones that are
+        // already there (see threads::handle_family())
+        let mut renamed = false;
+        if members.len() > 1 {
+            for (sub_path_plus_dirent, src_paths, _) in &members {
+                if scan_conflict(write_paths, sub_path_plus_dirent, src_paths, match_by)? {
+                    renamed = true;
+                    break;
+                }
+            }
+        }
+
+        for (sub_path_plus_dirent, src_paths, all_match) in &members {
+            if *all_match && !renamed {
+                continue;
+            }
+
+            scan_file(
+                write_paths,
+                sub_path_plus_dirent,
+                src_paths,
+                match_by,
+                renamed,
+                scan_totals,
+            )?;
+        }
+    }
+
+    // directories
+    for (sub_path_plus_dirent, dirent_instances) in &dirents {
+        if dirent_instances.is_dir {
+            __scan(
+                read_paths,
+                write_paths,
+                sub_path_plus_dirent,
+                match_by,
+                scan_totals,
+                scan_plan,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Add the files and bytes that will be written to each destination for a single sub path to the
+ * totals, given the source files found for it.
+ *
+ * If renamed, the files are part of a family that is renamed because of a merge conflict, so
+ * they're copied even to the destinations that already have them.
+ */
+fn scan_file(
+    write_paths: &Vec<&str>,
+    sub_path: &str,
+    src_paths: &[PathBuf],
+    match_by: MatchBy,
+    renamed: bool,
+    scan_totals: &mut ScanTotals,
+) -> Result<(), Box<dyn Error>> {
+    // one copy of each distinct source file gets written, but we can't tell which files
+    // differ without hashing them, so files of different sizes are assumed to be the
+    // only ones that differ (a read merge conflict)
+    // map of file size -> metadata
+    let mut src_sizes = BTreeMap::new();
+    for src_path in src_paths {
+        let metadata = src_path.metadata()?;
+        src_sizes.insert(metadata.len(), metadata);
+    }
+
+    if src_sizes.len() > 1 || renamed {
+        // read merge conflict, or a renamed family: every version is copied to every
+        // destination, under new names
+        for i in 0..write_paths.len() {
+            let keeps_holes = scan_totals.dest_keeps_holes[i];
+            scan_totals.dest_files[i] += src_sizes.len() as u64;
+            scan_totals.dest_bytes[i] += src_sizes
+                .values()
+                .map(|metadata| fsutil::written_len(metadata, keeps_holes))
+                .sum::<u64>();
+        }
+    } else if let Some((src_size, src_metadata)) = src_sizes.first_key_value() {
+        for (i, write_path) in write_paths.iter().enumerate() {
+            let mut dest_path = PathBuf::from(write_path);
+            dest_path.push(sub_path);
+
+            // the copy thread skips destination files that already exist, and a different
+            // file (by size, or match_by) is a write merge conflict that still gets copied
+            if let Ok(dest_metadata) = dest_path.metadata() {
+                if dest_metadata.len() == *src_size
+                    && common::same_files(match_by, &[src_paths[0].clone(), dest_path])
+                {
+                    continue;
+                }
+            }
+
+            scan_totals.dest_files[i] += 1;
+            scan_totals.dest_bytes[i] +=
+                fsutil::written_len(src_metadata, scan_totals.dest_keeps_holes[i]);
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Will the files for a single sub path be renamed because of a merge conflict, as far as can be
+ * told without hashing them? That is, do the source files have different sizes, or is there a
+ * different file (by size, or match_by) in one of the destinations?
+ */
+fn scan_conflict(
+    write_paths: &Vec<&str>,
+    sub_path: &str,
+    src_paths: &[PathBuf],
+    match_by: MatchBy,
+) -> Result<bool, Box<dyn Error>> {
+    let mut src_sizes = BTreeSet::new();
+    for src_path in src_paths {
+        src_sizes.insert(src_path.metadata()?.len());
+    }
+    if src_sizes.len() > 1 {
+        return Ok(true);
+    }
+
+    for write_path in write_paths {
+        let mut dest_path = PathBuf::from(write_path);
+        dest_path.push(sub_path);
+
+        if let Ok(dest_metadata) = dest_path.metadata() {
+            if !src_sizes.contains(&dest_metadata.len())
+                || !common::same_files(match_by, &[src_paths[0].clone(), dest_path])
+            {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/**
+ * Files in the read paths that stand out by their type, from check_media().
+ */
+pub struct MediaReport {
+    // files that discover_files() skips with media_only (the first instance of each sub path)
+    pub non_media: Vec<PathBuf>,
+
+    // every instance of those files, in all of the read paths (see scan())
+    pub skipped: HashSet<PathBuf>,
+
+    // files whose extension doesn't match their contents
+    pub mismatched: Vec<(PathBuf, Classification)>,
+
+    // recordings that the camera split into more than one file
+    pub spanned_clips: Vec<SpannedClip>,
+}
+
+/**
+ * A recording that the camera split into chapters (see media::clip_chapter() and
+ * media::numbered_clip()), with the chapters found across all of the read paths.
+ */
+pub struct SpannedClip {
+    // sub path of the directory that the chapters are in
+    pub dir_sub_path: String,
+
+    pub name: ClipName,
+    pub chapters: BTreeSet<u32>,
+
+    // was the last chapter found split off at the file size limit, so the recording goes on in a
+    // chapter that wasn't found? (see media::was_split())
+    pub continues: bool,
+}
+
+impl SpannedClip {
+    /**
+     * Get the chapters missing before the last one found, and the one after it if the recording
+     * goes on. (Otherwise, a missing last chapter can't be told apart from a recording that ended
+     * there.)
+     */
+    pub fn missing_chapters(&self) -> Vec<u32> {
+        let last_chapter = *self.chapters.last().unwrap();
+        let mut missing_chapters: Vec<u32> = (self.name.first_chapter..last_chapter)
+            .filter(|chapter| !self.chapters.contains(chapter))
+            .collect();
+        if self.continues {
+            missing_chapters.push(last_chapter + 1);
+        }
+        missing_chapters
+    }
+
+    /**
+     * Get the sub path of the given chapter.
+     */
+    pub fn sub_path(&self, chapter: u32) -> String {
+        join_sub_path(&self.dir_sub_path, &self.name.file_name(chapter))
+    }
+}
+
+/**
+ * Find the spanned clips in the read paths, so that a missing chapter can be reported. A chapter
+ * only needs to be in one of the read paths, since it gets copied from there.
+ *
+ * With check, also classify the files (see media::classify()) to find the ones whose extension
+ * doesn't match their contents, which can be a sign of corruption or of a bad recovery tool. With
+ * media_only, find the ones that discover_files() will skip. Each sub path is classified once, by
+ * its first instance, like discover_files() does, so the same file on several cards only shows
+ * up once.
+ */
+pub fn check_media(
+    read_paths: &Vec<&str>,
+    media_only: bool,
+    check: bool,
+) -> Result<MediaReport, Box<dyn Error>> {
+    let mut media_report = MediaReport {
+        non_media: vec![],
+        skipped: HashSet::new(),
+        mismatched: vec![],
+        spanned_clips: vec![],
+    };
+
+    // map of directory sub path -> file name -> the file in each read path that has it
+    let mut dirs: BTreeMap<String, BTreeMap<String, Vec<PathBuf>>> = BTreeMap::new();
+    for read_path in read_paths {
+        let mut src_paths = vec![];
+        find_files(&PathBuf::from(read_path), &HashSet::new(), &mut src_paths)?;
+
+        for src_path in src_paths {
+            let dir = src_path.parent().unwrap();
+            let dir_sub_path = dir.strip_prefix(read_path).unwrap().to_str().unwrap();
+            let file_name = src_path.file_name().unwrap().to_str().unwrap();
+            dirs.entry(String::from(dir_sub_path))
+                .or_default()
+                .entry(String::from(file_name))
+                .or_default()
+                .push(src_path);
+        }
+    }
+
+    // map of (directory sub path, clip name) -> chapters found in any read path
+    let mut clips: BTreeMap<(String, ClipName), BTreeSet<u32>> = BTreeMap::new();
+
+    // map of (directory sub path, series name) -> file number -> first instance of the file, for
+    // the cameras that give each chapter the next file number (see media::numbered_clip())
+    let mut series: BTreeMap<(String, ClipName), BTreeMap<u32, PathBuf>> = BTreeMap::new();
+
+    for (dir_sub_path, files) in dirs {
+        for (file_name, src_paths) in &files {
+            if let Some((clip_name, chapter)) = media::clip_chapter(file_name) {
+                clips
+                    .entry((dir_sub_path.clone(), clip_name))
+                    .or_default()
+                    .insert(chapter);
+            } else if let Some((series_name, number)) = media::numbered_clip(file_name) {
+                series
+                    .entry((dir_sub_path.clone(), series_name))
+                    .or_default()
+                    .insert(number, src_paths[0].clone());
+            }
+        }
+
+        // classifying a file means opening it, so only do it when it's needed
+        if !media_only && !check {
+            continue;
+        }
+
+        let classifications: Vec<Classification> = files
+            .values()
+            .map(|src_paths| media::classify(&src_paths[0]))
+            .collect();
+
+        let mut non_media = HashSet::new();
+        if media_only {
+            let names_media: Vec<(&str, bool)> = files
+                .keys()
+                .zip(&classifications)
+                .map(|(name, classification)| (name.as_str(), classification.is_media()))
+                .collect();
+            non_media = find_non_media(&names_media);
+        }
+
+        for (j, (src_paths, classification)) in files.into_values().zip(classifications).enumerate()
+        {
+            if non_media.contains(&j) {
+                media_report.non_media.push(src_paths[0].clone());
+                media_report.skipped.extend(src_paths.iter().cloned());
+            }
+            if check && classification.is_mismatch() {
+                media_report
+                    .mismatched
+                    .push((src_paths[0].clone(), classification));
+            }
+        }
+    }
+
+    for ((dir_sub_path, name), chapters) in clips {
+        let spanned_clip = SpannedClip {
+            dir_sub_path,
+            name,
+            chapters,
+            continues: false,
+        };
+
+        // a single complete chapter is just a clip
+        if spanned_clip.chapters.len() > 1 || !spanned_clip.missing_chapters().is_empty() {
+            media_report.spanned_clips.push(spanned_clip);
+        }
+    }
+
+    for ((dir_sub_path, series_name), files) in series {
+        let spanned_clips = find_numbered_clips(&dir_sub_path, &series_name, &files);
+        media_report.spanned_clips.extend(spanned_clips);
+    }
+
+    Ok(media_report)
+}
+
+/**
+ * Find the spanned clips in a directory's series of numbered files (see media::numbered_clip()),
+ * by following each file on to the next file number for as long as it continues the recording
+ * (see media::continues_clip()).
+ *
+ * Takes a map of file number -> file.
+ */
+fn find_numbered_clips(
+    dir_sub_path: &str,
+    series_name: &ClipName,
+    files: &BTreeMap<u32, PathBuf>,
+) -> Vec<SpannedClip> {
+    let mut spanned_clips = vec![];
+
+    // each run of file numbers that continue the recording in the one before, as (first, last)
+    let mut runs: Vec<(u32, u32)> = vec![];
+    for (&number, src_path) in files {
+        if let Some((_, last)) = runs.last_mut() {
+            if *last + 1 == number && media::continues_clip(&files[last], src_path) {
+                *last = number;
+                continue;
+            }
+        }
+        runs.push((number, number));
+    }
+
+    for (first, last) in runs {
+        let mut name = series_name.clone();
+        name.first_chapter = first;
+        let spanned_clip = SpannedClip {
+            dir_sub_path: String::from(dir_sub_path),
+            name,
+            chapters: (first..=last).collect(),
+            continues: media::was_split(&files[&last]),
+        };
+
+        // a single file that wasn't split is just a clip
+        if first != last || spanned_clip.continues {
+            spanned_clips.push(spanned_clip);
+        }
+    }
+
+    spanned_clips
+}
+
+/**
+ * Find every directory under the read paths, as a list of (sub path, source directory) pairs.
+ *
+ * When the same sub path is a directory in more than one read path, the source directory with
+ * the newest modification time is used.
+ */
+pub fn find_dirs(read_paths: &Vec<&str>) -> Result<Vec<(String, PathBuf)>, Box<dyn Error>> {
+    let mut dirs = vec![];
+    __find_dirs(read_paths, "", &mut dirs)?;
+    Ok(dirs)
+}
+
+// recursive implementation of public find_dirs() function
+fn __find_dirs(
+    read_paths: &Vec<&str>,
+    sub_path: &str,
+    dirs: &mut Vec<(String, PathBuf)>,
+) -> Result<(), Box<dyn Error>> {
+    let (all_dirent_maps, _) = read_dirents(read_paths, sub_path)?;
+
+    for dirent_str in &all_dirent_maps {
+        let sub_path_plus_dirent = join_sub_path(sub_path, dirent_str);
+
+        // newest (mtime, mtime_nsec, path) of this directory across the read paths
+        let mut newest: Option<(i64, i64, PathBuf)> = None;
+        for read_path in read_paths {
+            let mut path_buf = PathBuf::from(read_path);
+            path_buf.push(&sub_path_plus_dirent);
+
+            if !path_buf.is_dir() {
+                continue;
+            }
+
+            let metadata = path_buf.metadata()?;
+            let mtime = (metadata.mtime(), metadata.mtime_nsec());
+            match &newest {
+                Some((sec, nsec, _)) if (*sec, *nsec) >= mtime => {}
+                _ => newest = Some((mtime.0, mtime.1, path_buf)),
+            }
+        }
+
+        if let Some((_, _, src_dir)) = newest {
+            dirs.push((String::from(&sub_path_plus_dirent), src_dir));
+            __find_dirs(read_paths, &sub_path_plus_dirent, dirs)?;
+        }
+    }
+
+    Ok(())
 }
 
 // recursive implementation of public discover_files() function
 fn __discover_files(
     thread_run_ctx: &ThreadRunContext,
-    xfer_req_tx: &SyncSender<TransferRequest>,
-    copy_to_dest_req_tx: &SyncSender<CopyToDestRequest>,
-    hash_req_tx_vec: &Vec<&SyncSender<Option<HashRequest>>>,
+    discover_ctx: &DiscoverContext,
     read_paths: &Vec<&str>,
     write_paths: &Vec<&str>,
     sub_path: &str,
 ) -> Result<(), Box<dyn Error>> {
+    let scan_plan = &discover_ctx.scan_plan;
+    let copy_options = &discover_ctx.copy_options;
+
     // if the program is supposed to shut down, stop discovering files
     // this returns Ok because if we get to this point, something else already shut down
     if !thread_run_ctx.is_running() {
         return Ok(());
     }
 
+    let (all_dirent_maps, read_path_dirent_maps) = read_dirents(read_paths, sub_path)?;
+
+    // go through each dirent that we found across all of the read paths with sub paths
+    let mut dirents = vec![];
+    for dirent_str in &all_dirent_maps {
+        // assemble the next sub path, based on the sub path we received, plus the dirent
+        let sub_path_plus_dirent = join_sub_path(sub_path, dirent_str);
+
+        // figure out whether each instance of this dirent that exists is a file/directory/etc
+        let dirent_instances = find_dirent_instances(
+            read_paths,
+            &read_path_dirent_maps,
+            dirent_str,
+            &sub_path_plus_dirent,
+        )?;
+
+        dirents.push((sub_path_plus_dirent, dirent_instances));
+    }
+
+    // group the files into families, like a raw file with its JPEG and XMP sidecar, so that a
+    // merge conflict in one of them renames all of them the same way
+    let families = find_families(&all_dirent_maps, &dirents);
+
+    // with media_only, skip the families without a photo, video or audio file
+    let non_media = find_non_media_dirents(&dirents, &scan_plan.non_media);
+
+    // files
+    for family in families.values() {
+        if family.iter().all(|j| non_media.contains(j)) {
+            continue;
+        }
+
+        // if the metadata for all src and dest files match, we can avoid hashing and copying
+        // (with dedupe, files that were already copied still need to be hashed, so that their
+        // duplicates are spotted)
+        if copy_options.dedupe.is_none()
+            && family.iter().all(|&j| {
+                scan_plan.all_files_match(
+                    read_paths,
+                    write_paths,
+                    &dirents[j].0,
+                    copy_options.match_by,
+                )
+            })
+        {
+            continue;
+        }
+
+        if family.len() > 1 {
+            discover_ctx
+                .xfer_req_tx
+                .send(TransferRequest::Group(family.len()))?;
+        }
+
+        for &j in family {
+            let (sub_path_plus_dirent, dirent_instances) = &dirents[j];
+            send_file(
+                thread_run_ctx,
+                discover_ctx,
+                sub_path_plus_dirent,
+                dirent_instances.files_found_or_placeholders.clone(),
+                dirent_instances.actual_files_found,
+            )?;
+        }
+    }
+
+    // directories
+    for (sub_path_plus_dirent, dirent_instances) in &dirents {
+        if dirent_instances.is_dir {
+            match __discover_files(
+                thread_run_ctx,
+                discover_ctx,
+                read_paths,
+                write_paths,
+                sub_path_plus_dirent,
+            ) {
+                Ok(()) => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// sub path in the destinations -> the files that the layout gives that sub path, for each read
+// path (in the same order as read_paths)
+type LayoutPlan = BTreeMap<String, Vec<Vec<PathBuf>>>;
+
+/**
+ * Find every file in the read paths, and group them by the sub path that the layout gives them.
+ *
+ * A family of files that the layout can't place is shown as a warning and left out.
+ */
+fn plan_layout(
+    log_warn: fn(&str),
+    read_paths: &Vec<&str>,
+    layout: &Layout,
+    non_media: &HashSet<PathBuf>,
+) -> Result<LayoutPlan, Box<dyn Error>> {
+    let mut plan: LayoutPlan = BTreeMap::new();
+
+    for (i, read_path) in read_paths.iter().enumerate() {
+        let mut src_paths = vec![];
+        find_files(&PathBuf::from(read_path), non_media, &mut src_paths)?;
+
+        // group the files into families (see common::family_stem()), so that sidecars are
+        // placed along with their photos and clips
+        let mut families: BTreeMap<(PathBuf, String), Vec<PathBuf>> = BTreeMap::new();
+        for src_path in src_paths {
+            let dir = src_path.parent().unwrap().to_path_buf();
+            let file_name = src_path.file_name().unwrap().to_str().unwrap();
+            let family_stem = String::from(common::family_stem(file_name));
+            families
+                .entry((dir, family_stem))
+                .or_default()
+                .push(src_path);
+        }
+
+        for family in families.into_values() {
+            let sub_paths = match layout.sub_paths(&family, i) {
+                Ok(sub_paths) => sub_paths,
+                Err(e) => {
+                    for src_path in &family {
+                        let err = format!(
+                            "skipping '{}' (can't place it with the layout: {})",
+                            src_path.to_str().unwrap(),
+                            e
+                        );
+                        log_warn(&err);
+                    }
+                    continue;
+                }
+            };
+
+            for (sub_path, src_path) in sub_paths.into_iter().zip(family) {
+                let files = plan
+                    .entry(sub_path)
+                    .or_insert_with(|| vec![vec![]; read_paths.len()]);
+                files[i].push(src_path);
+            }
+        }
+    }
+
+    Ok(plan)
+}
+
+/**
+ * Does the layout give more than one file in the same read path the same sub path? (see
+ * send_layout_versions())
+ */
+fn has_versions(files: &[Vec<PathBuf>]) -> bool {
+    files
+        .iter()
+        .any(|read_path_files| read_path_files.len() > 1)
+}
+
+/**
+ * Group the sub paths in a layout plan into families (see common::family_stem()) by their
+ * directory in the destinations, the same way find_families() does with the read paths.
+ *
+ * Returns a map of (directory, family stem) -> sub paths.
+ */
+fn find_layout_families(layout_plan: &LayoutPlan) -> BTreeMap<(&str, &str), Vec<&str>> {
+    let mut families: BTreeMap<(&str, &str), Vec<&str>> = BTreeMap::new();
+    for sub_path in layout_plan.keys() {
+        let (dir, file_name) = match sub_path.rfind('/') {
+            Some(j) => (&sub_path[..j], &sub_path[j + 1..]),
+            None => ("", sub_path.as_str()),
+        };
+        families
+            .entry((dir, common::family_stem(file_name)))
+            .or_default()
+            .push(sub_path);
+    }
+
+    families
+}
+
+/**
+ * Recursively find every file under a directory, in sorted order, skipping hidden files and
+ * directories, and the files in non_media (see check_media()).
+ */
+fn find_files(
+    dir: &Path,
+    non_media: &HashSet<PathBuf>,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let mut paths = vec![];
+    for dirent in dir.read_dir()? {
+        let dirent = dirent?;
+        if dirent.file_name().to_str().unwrap().starts_with('.') {
+            continue;
+        }
+        paths.push(dirent.path());
+    }
+    paths.sort();
+
+    for path in paths {
+        if path.is_dir() {
+            find_files(&path, non_media, files)?;
+        } else if path.is_file() {
+            if !non_media.contains(&path) {
+                files.push(path);
+            }
+        } else {
+            let err_str = format!(
+                "path must be a file or directory: '{}'",
+                path.to_str().unwrap()
+            );
+            return dit_error(&err_str);
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Find the files in a directory that media_only skips: every file in a family (see
+ * common::family_stem()) without a photo, video or audio file in it (see media::classify()), so
+ * sidecars like XMP files and clip metadata are still copied along with their photos and clips.
+ *
+ * Takes the name of each dirent in the directory, and whether it is a media file, and returns
+ * the indexes of the ones in families without one (which can include directories, since they
+ * aren't media files).
+ */
+fn find_non_media(names_media: &[(&str, bool)]) -> HashSet<usize> {
+    // map of family stem -> does the family have a media file?
+    let mut media_families: HashMap<&str, bool> = HashMap::new();
+    for (name, is_media) in names_media {
+        *media_families.entry(common::family_stem(name)).or_default() |= is_media;
+    }
+
+    let mut non_media = HashSet::new();
+    for (j, (name, _)) in names_media.iter().enumerate() {
+        if !media_families[common::family_stem(name)] {
+            non_media.insert(j);
+        }
+    }
+
+    non_media
+}
+
+/**
+ * Group the files found in a directory across the read paths into families (see
+ * common::family_stem()).
+ *
+ * Returns a map of family stem -> indexes into dirents.
+ */
+fn find_families<'a>(
+    all_dirent_maps: &'a BTreeSet<String>,
+    dirents: &[(String, DirentInstances)],
+) -> BTreeMap<&'a str, Vec<usize>> {
+    let mut families: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (j, dirent_str) in all_dirent_maps.iter().enumerate() {
+        if dirents[j].1.is_file {
+            let family_stem = common::family_stem(dirent_str);
+            families.entry(family_stem).or_default().push(j);
+        }
+    }
+
+    families
+}
+
+/**
+ * Find the dirents found in a directory across the read paths that are in non_media (see
+ * check_media()), by their first instance.
+ *
+ * Returns their indexes into dirents.
+ */
+fn find_non_media_dirents(
+    dirents: &[(String, DirentInstances)],
+    non_media: &HashSet<PathBuf>,
+) -> HashSet<usize> {
+    let mut non_media_dirents = HashSet::new();
+    for (j, (_, dirent_instances)) in dirents.iter().enumerate() {
+        let first_file = dirent_instances
+            .files_found_or_placeholders
+            .iter()
+            .flatten()
+            .next();
+        if let Some(path) = first_file {
+            if non_media.contains(path) {
+                non_media_dirents.insert(j);
+            }
+        }
+    }
+
+    non_media_dirents
+}
+
+/**
+ * Implementation of discover_files() with a layout.
+ */
+fn discover_layout_files(
+    thread_run_ctx: &ThreadRunContext,
+    discover_ctx: &DiscoverContext,
+    write_paths: &Vec<&str>,
+    layout_plan: &LayoutPlan,
+) -> Result<(), Box<dyn Error>> {
+    let scan_plan = &discover_ctx.scan_plan;
+    let copy_options = &discover_ctx.copy_options;
+
+    // if the metadata for all src and dest files match, we can avoid hashing and copying
+    let all_match = |sub_path: &str| {
+        let files = &layout_plan[sub_path];
+        let src_paths: Vec<PathBuf> = files.iter().flatten().cloned().collect();
+        copy_options.dedupe.is_none()
+            && !has_versions(files)
+            && scan_plan.all_dest_files_match(
+                &src_paths,
+                write_paths,
+                sub_path,
+                copy_options.match_by,
+            )
+    };
+
+    // families of files, like __discover_files() sends them
+    for family in find_layout_families(layout_plan).values() {
+        // if the program is supposed to shut down, stop discovering files
+        if !thread_run_ctx.is_running() {
+            return Ok(());
+        }
+
+        // each version of a file that has more than one in the same read path gets its own name,
+        // so a family with one of those can't be renamed together, and is sent one file at a time
+        let grouped = family.len() > 1 && !family.iter().any(|s| has_versions(&layout_plan[*s]));
+        if grouped {
+            if family.iter().all(|sub_path| all_match(sub_path)) {
+                continue;
+            }
+            discover_ctx
+                .xfer_req_tx
+                .send(TransferRequest::Group(family.len()))?;
+        }
+
+        for &sub_path in family {
+            let files = &layout_plan[sub_path];
+            if has_versions(files) {
+                send_layout_versions(thread_run_ctx, discover_ctx, sub_path, files)?;
+                continue;
+            }
+
+            if !grouped && all_match(sub_path) {
+                continue;
+            }
+
+            let files_found_or_placeholders: Vec<Option<PathBuf>> = files
+                .iter()
+                .map(|read_path_files| read_path_files.first().cloned())
+                .collect();
+            let actual_files_found = files_found_or_placeholders.iter().flatten().count();
+
+            send_file(
+                thread_run_ctx,
+                discover_ctx,
+                sub_path,
+                files_found_or_placeholders,
+                actual_files_found,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Send the files that a layout gave the same sub path, when there is more than one of them in the
+ * same read path, to be hashed, so the merge thread copies each distinct version as a read merge
+ * conflict (or just one of them, if they all have the same contents).
+ *
+ * Every read path is sent a HashRequest (or None) in each round, the same as for any other merge,
+ * with as many rounds as the most files in one read path.
+ */
+fn send_layout_versions(
+    thread_run_ctx: &ThreadRunContext,
+    discover_ctx: &DiscoverContext,
+    sub_path: &str,
+    files: &[Vec<PathBuf>],
+) -> Result<(), Box<dyn Error>> {
+    let rounds = files
+        .iter()
+        .map(|read_path_files| read_path_files.len())
+        .max()
+        .unwrap_or(0);
+
+    for round in 0..rounds {
+        for (i, read_path_files) in files.iter().enumerate() {
+            // if we're supposed to shut down, stop discovering new files
+            if !thread_run_ctx.is_running() {
+                return Ok(());
+            }
+
+            let option_hash_req = read_path_files.get(round).map(|src_path| HashRequest {
+                sub_path: String::from(sub_path),
+                src_path: src_path.clone(),
+            });
+            discover_ctx.hash_req_channels_tx[i].send(option_hash_req)?;
+        }
+    }
+
+    // if we're supposed to shut down, stop discovering new files
+    if !thread_run_ctx.is_running() {
+        return Ok(());
+    }
+
+    // send a merge transfer request for all of the rounds
+    discover_ctx
+        .xfer_req_tx
+        .send(TransferRequest::Merge(rounds))?;
+
+    Ok(())
+}
+
+/**
+ * Send the requests for a single sub path that was found in one or more read paths, as described
+ * in discover_files().
+ *
+ * With CopyOptions::hash_all(), a file found in only one read path is sent to be hashed as well,
+ * as if it were found in more than one.
+ */
+fn send_file(
+    thread_run_ctx: &ThreadRunContext,
+    discover_ctx: &DiscoverContext,
+    sub_path: &str,
+    files_found_or_placeholders: Vec<Option<PathBuf>>,
+    actual_files_found: usize,
+) -> Result<(), Box<dyn Error>> {
+    let xfer_req_tx = &discover_ctx.xfer_req_tx;
+    let hash_req_tx_vec = &discover_ctx.hash_req_channels_tx;
+
+    if actual_files_found > 1 || discover_ctx.copy_options.hash_all() {
+        let mut i = 0;
+        for file_name in files_found_or_placeholders {
+            // if we're supposed to shut down, stop discovering new files
+            if !thread_run_ctx.is_running() {
+                return Ok(());
+            }
+
+            match file_name {
+                Some(file_name) => {
+                    // add hash request to queue
+                    let hash_request = HashRequest {
+                        sub_path: String::from(sub_path),
+                        src_path: file_name,
+                    };
+                    hash_req_tx_vec.get(i).unwrap().send(Some(hash_request))?;
+                }
+                None => {
+                    // add placeholder to hash request queue
+                    hash_req_tx_vec.get(i).unwrap().send(None)?;
+                }
+            }
+
+            i += 1;
+        }
+
+        // if we're supposed to shut down, stop discovering new files
+        if !thread_run_ctx.is_running() {
+            return Ok(());
+        }
+
+        // send a merge transfer request
+        xfer_req_tx.send(TransferRequest::Merge(1))?;
+    } else {
+        for (i, file_name) in files_found_or_placeholders.into_iter().enumerate() {
+            // if we're supposed to shut down, stop discovering new files
+            if !thread_run_ctx.is_running() {
+                return Ok(());
+            }
+
+            match file_name {
+                Some(file_name) => {
+                    // send copy to dest request
+                    let copy_to_dest_request = CopyToDestRequest {
+                        sub_path: String::from(sub_path),
+                        src_path: file_name,
+                        read_path_index: i,
+                    };
+                    discover_ctx
+                        .copy_to_dest_req_tx
+                        .send(copy_to_dest_request)?;
+
+                    // send copy transfer request
+                    xfer_req_tx.send(TransferRequest::Copy)?;
+
+                    break;
+                }
+                None => {}
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * All of the instances of a single dirent across the read paths.
+ */
+struct DirentInstances {
+    // is this dirent a file in at least one read path?
+    is_file: bool,
+
+    // is this dirent a directory in at least one read path?
+    is_dir: bool,
+
+    // full path to the file in each read path, or None if that read path doesn't have the file
+    files_found_or_placeholders: Vec<Option<PathBuf>>,
+
+    // number of Some entries in files_found_or_placeholders
+    actual_files_found: usize,
+}
+
+// all dirents across the read paths, plus the dirents in each individual read path
+type DirentMaps = (BTreeSet<String>, Vec<HashSet<String>>);
+
+/**
+ * Read the read_paths[*]/sub_path directories.
+ *
+ * Returns the sorted set of all dirents found in any of them, and a set of dirents for each
+ * individual read path. The list order of read_paths is the index into the per-read-path sets,
+ * e.g. read_paths[i] matches up with read_path_dirent_maps[i].
+ */
+fn read_dirents(read_paths: &Vec<&str>, sub_path: &str) -> Result<DirentMaps, Box<dyn Error>> {
     // map of all dirents found in any of the read_paths[*]/sub_path directories
     let mut all_dirent_maps: BTreeSet<String> = BTreeSet::new();
 
     // vector of hash sets of dirents, with one hash set for each read_path
-    // the list order of read_paths is the index into the hash maps
-    // e.g. read_paths[i] matches up with read_path_dirent_maps[i]
     let mut read_path_dirent_maps: Vec<HashSet<String>> = vec![];
 
     // go through each read path
@@ -107,163 +1290,93 @@ fn __discover_files(
         read_path_dirent_maps.push(dirent_map);
     }
 
-    // go through each dirent that we found across all of the read paths with sub paths
-    for dirent_str in &all_dirent_maps {
-        // keep track of if this dirent is a file or a directory (or both or neither across dirs)
-        let mut is_file = false;
-        let mut is_dir = false;
-
-        // list of full paths to all the files that we found
-        let mut files_found_or_placeholders: Vec<Option<PathBuf>> = vec![];
-
-        // assemble the next sub path, based on the sub path we received, plus the dirent
-        let mut next_sub_path = String::new();
-        if !"".eq(sub_path) {
-            next_sub_path.push_str(&sub_path);
-            next_sub_path.push('/');
-        }
-        next_sub_path.push_str(dirent_str);
-        let sub_path_plus_dirent = next_sub_path;
-
-        // figure out whether each instance of this dirent that exists is a file/directory/etc
-        let mut i = 0;
-        let mut actual_files_found = 0;
-        for read_path in read_paths {
-            let mut found_file_this_time = false;
-            let dirent_map = &read_path_dirent_maps.get(i).unwrap();
-            if dirent_map.contains(dirent_str) {
-                // assemble a path buf with the full path to this particular file in one read path
-                let mut full_path_buf = PathBuf::from(&read_path);
-                full_path_buf.push(&sub_path_plus_dirent);
-                let full_path_buf = full_path_buf;
-
-                // if the full path file dirent exists, figure out if it's a file or directory
-                if full_path_buf.exists() {
-                    if full_path_buf.is_dir() {
-                        is_dir = true;
-                    } else if full_path_buf.is_file() {
-                        // if it's a file, we'll probably need to refer to it again soon
-                        files_found_or_placeholders.push(Some(full_path_buf));
-                        is_file = true;
-                        found_file_this_time = true;
-                        actual_files_found += 1;
-                    }
-                }
-            }
-
-            // if we didn't find a file, add a None placeholder
-            if !found_file_this_time {
-                files_found_or_placeholders.push(None);
-            }
-
-            i += 1;
-        }
-
-        // alias previously mutable variables to immutable equivalents
-        let is_file = is_file;
-        let is_dir = is_dir;
-
-        // file and directory
-        if is_file && is_dir {
-            let err_str = format!(
-                "path must be a file or directory, not both: '{}'",
-                &sub_path_plus_dirent
-            );
-            return dit_error(&err_str);
-        }
-
-        // neither file nor directory
-        if (!is_file) && (!is_dir) {
-            let err_str = format!(
-                "path must be a file or directory: '{}'",
-                &sub_path_plus_dirent
-            );
-            return dit_error(&err_str);
-        }
-
-        // file
-        if is_file {
-            // if the metadata for all src and dest files match, we can avoid hashing and copying
-            if common::all_files_match(read_paths, write_paths, &sub_path_plus_dirent) {
-                continue;
-            }
-
-            if actual_files_found > 1 {
-                let mut i = 0;
-                for file_name in files_found_or_placeholders {
-                    // if we're supposed to shut down, stop discovering new files
-                    if !thread_run_ctx.is_running() {
-                        return Ok(());
-                    }
-
-                    match file_name {
-                        Some(file_name) => {
-                            // add hash request to queue
-                            let hash_request = HashRequest {
-                                sub_path: String::from(&sub_path_plus_dirent),
-                                src_path: file_name,
-                            };
-                            hash_req_tx_vec.get(i).unwrap().send(Some(hash_request))?;
-                        }
-                        None => {
-                            // add placeholder to hash request queue
-                            hash_req_tx_vec.get(i).unwrap().send(None)?;
-                        }
-                    }
-
-                    i += 1;
-                }
+    Ok((all_dirent_maps, read_path_dirent_maps))
+}
 
-                // if we're supposed to shut down, stop discovering new files
-                if !thread_run_ctx.is_running() {
-                    return Ok(());
-                }
+/**
+ * Append a dirent to a sub path (e.g. "foo" + "bar" -> "foo/bar", or "" + "bar" -> "bar").
+ */
+fn join_sub_path(sub_path: &str, dirent_str: &str) -> String {
+    let mut next_sub_path = String::new();
+    if !"".eq(sub_path) {
+        next_sub_path.push_str(sub_path);
+        next_sub_path.push('/');
+    }
+    next_sub_path.push_str(dirent_str);
+    next_sub_path
+}
 
-                // send a merge transfer request
-                xfer_req_tx.send(TransferRequest::Merge)?;
-            } else {
-                for file_name in files_found_or_placeholders {
-                    // if we're supposed to shut down, stop discovering new files
-                    if !thread_run_ctx.is_running() {
-                        return Ok(());
-                    }
+/**
+ * Figure out whether each instance of the given dirent across the read paths is a file or a
+ * directory.
+ *
+ * Returns an error if the dirent is a file in one read path and a directory in another, or if
+ * it is neither a file nor a directory.
+ */
+fn find_dirent_instances(
+    read_paths: &Vec<&str>,
+    read_path_dirent_maps: &[HashSet<String>],
+    dirent_str: &str,
+    sub_path_plus_dirent: &str,
+) -> Result<DirentInstances, Box<dyn Error>> {
+    // keep track of if this dirent is a file or a directory (or both or neither across dirs)
+    let mut is_file = false;
+    let mut is_dir = false;
 
-                    match file_name {
-                        Some(file_name) => {
-                            // send copy to dest request
-                            let copy_to_dest_request = CopyToDestRequest {
-                                sub_path: String::from(&sub_path_plus_dirent),
-                                src_path: file_name,
-                            };
-                            copy_to_dest_req_tx.send(copy_to_dest_request)?;
+    // list of full paths to all the files that we found
+    let mut files_found_or_placeholders: Vec<Option<PathBuf>> = vec![];
 
-                            // send copy transfer request
-                            xfer_req_tx.send(TransferRequest::Copy)?;
+    let mut actual_files_found = 0;
+    for (read_path, dirent_map) in read_paths.iter().zip(read_path_dirent_maps) {
+        let mut found_file_this_time = false;
+        if dirent_map.contains(dirent_str) {
+            // assemble a path buf with the full path to this particular file in one read path
+            let mut full_path_buf = PathBuf::from(read_path);
+            full_path_buf.push(sub_path_plus_dirent);
+            let full_path_buf = full_path_buf;
 
-                            break;
-                        }
-                        None => {}
-                    }
+            // if the full path file dirent exists, figure out if it's a file or directory
+            if full_path_buf.exists() {
+                if full_path_buf.is_dir() {
+                    is_dir = true;
+                } else if full_path_buf.is_file() {
+                    // if it's a file, we'll probably need to refer to it again soon
+                    files_found_or_placeholders.push(Some(full_path_buf));
+                    is_file = true;
+                    found_file_this_time = true;
+                    actual_files_found += 1;
                 }
             }
         }
 
-        // directory
-        if is_dir {
-            match __discover_files(
-                thread_run_ctx,
-                xfer_req_tx,
-                copy_to_dest_req_tx,
-                hash_req_tx_vec,
-                read_paths,
-                write_paths,
-                &sub_path_plus_dirent,
-            ) {
-                Ok(()) => {}
-                Err(e) => return Err(e),
-            }
+        // if we didn't find a file, add a None placeholder
+        if !found_file_this_time {
+            files_found_or_placeholders.push(None);
         }
     }
 
-    Ok(())
+    // file and directory
+    if is_file && is_dir {
+        let err_str = format!(
+            "path must be a file or directory, not both: '{}'",
+            sub_path_plus_dirent
+        );
+        return dit_error(&err_str);
+    }
+
+    // neither file nor directory
+    if (!is_file) && (!is_dir) {
+        let err_str = format!(
+            "path must be a file or directory: '{}'",
+            sub_path_plus_dirent
+        );
+        return dit_error(&err_str);
+    }
+
+    Ok(DirentInstances {
+        is_file,
+        is_dir,
+        files_found_or_placeholders,
+        actual_files_found,
+    })
 }
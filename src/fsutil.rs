@@ -6,14 +6,42 @@ use nix::sys::time::TimeSpec;
 use sha2::{Digest, Sha256};
 use std::error::Error;
 use std::ffi::CString;
+use std::fmt::{Display, Formatter};
 use std::fs;
-use std::fs::File;
+use std::fs::{File, Metadata};
 use std::io::{ErrorKind, Read};
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::FromRawFd;
 use std::path::{Path, PathBuf};
 
-const BUF_SIZE: usize = 8192;
+/**
+ * Default size of the buffer used to read files for hashing, and for copying when there is no
+ * faster way to do it.
+ */
+pub const DEFAULT_BUF_SIZE: usize = 1024 * 1024;
+
+// alignment of buffers, offsets and sizes for O_DIRECT reads, which covers the logical block size
+// of any device we're likely to see
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const DIRECT_IO_ALIGN: usize = 4096;
+
+// size of the buffer of zeros that holes in sparse files are hashed from
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const ZEROS_SIZE: usize = 64 * 1024;
+
+// how much to copy between progress callbacks
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const COPY_CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/**
+ * Callback made after each chunk of a file is read or copied, with the number of bytes in the
+ * chunk. Returning an error stops the operation early.
+ */
+pub type ProgressFn<'a> = dyn FnMut(u64) -> Result<(), Box<dyn Error>> + 'a;
+
+// callback made with each chunk of data read by read_segment()
+#[cfg(any(target_os = "android", target_os = "linux"))]
+type ChunkFn<'a> = dyn FnMut(&[u8]) -> Result<(), Box<dyn Error>> + 'a;
 
 /**
  * Wrapper around the POSIX rename() function.
@@ -43,14 +71,94 @@ pub fn atomic_rename(src: &Path, dest: &Path) -> Result<(), Box<dyn Error>> {
     dit_error(&err_str)
 }
 
+/**
+ * Combine several hashes (e.g. from hash_file()) into one, in the given order.
+ *
+ * A single hash is returned as it is.
+ */
+pub fn hash_hashes(hashes: &[&str]) -> String {
+    if 1 == hashes.len() {
+        return String::from(hashes[0]);
+    }
+
+    let mut hasher = Sha256::new();
+    for hash in hashes {
+        hasher.update(hash.as_bytes());
+        hasher.update(b"\n");
+    }
+    format!("{:x}", hasher.finalize())
+}
+
 /**
  * Analyze the given file, and return a string with an sha256 hex digest hash.
  */
 pub fn hash_file(path: &Path) -> Result<String, Box<dyn Error>> {
+    hash_file_with_progress(path, DEFAULT_BUF_SIZE, &mut |_| Ok(()))
+}
+
+/**
+ * Same as hash_file(), reading buf_size bytes at a time, and calling on_progress after each
+ * buffer is read.
+ *
+ * The file's pages are dropped from the page cache once it has been hashed.
+ */
+pub fn hash_file_with_progress(
+    path: &Path,
+    buf_size: usize,
+    on_progress: &mut ProgressFn,
+) -> Result<String, Box<dyn Error>> {
+    let f = File::open(path)?;
+    let result = hash_open_file(&f, path, buf_size, on_progress);
+    drop_cached_pages(&f);
+
+    result
+}
+
+/**
+ * Same as hash_file_with_progress(), but leaving the file's pages in the page cache, for when
+ * something else is about to read it again.
+ */
+pub fn hash_file_keep_cached(
+    path: &Path,
+    buf_size: usize,
+    on_progress: &mut ProgressFn,
+) -> Result<String, Box<dyn Error>> {
+    let f = File::open(path)?;
+    hash_open_file(&f, path, buf_size, on_progress)
+}
+
+/**
+ * Hash an open file, from the start.
+ */
+fn hash_open_file(
+    mut f: &File,
+    path: &Path,
+    buf_size: usize,
+    on_progress: &mut ProgressFn,
+) -> Result<String, Box<dyn Error>> {
     let mut hasher = Sha256::new();
+    let mut buf = vec![0; buf_size];
+
+    advise_sequential(f);
 
-    let mut f = File::open(path)?;
-    let mut buf = [0; BUF_SIZE];
+    // sparse files: hash the holes as the zeros they read back as, without reading them, so the
+    // hash is the same as if the file were fully allocated
+    #[cfg(any(target_os = "android", target_os = "linux"))]
+    if is_sparse(&f.metadata()?) {
+        let len = f.metadata()?.len();
+        let mut pos = 0;
+        for (start, end) in data_segments(f, len)? {
+            hash_zeros(&mut hasher, start - pos);
+            read_segment(f, start, end, &mut buf, &mut |chunk| {
+                hasher.update(chunk);
+                on_progress(chunk.len() as u64)
+            })?;
+            pos = end;
+        }
+        hash_zeros(&mut hasher, len - pos);
+
+        return Ok(format!("{:x}", hasher.finalize()));
+    }
 
     loop {
         match f.read(&mut buf) {
@@ -59,6 +167,7 @@ pub fn hash_file(path: &Path) -> Result<String, Box<dyn Error>> {
                     break;
                 }
                 hasher.update(&buf[..bytes_read]);
+                on_progress(bytes_read as u64)?;
             }
             Err(e) => match e.kind() {
                 ErrorKind::UnexpectedEof => break,
@@ -78,6 +187,265 @@ pub fn hash_file(path: &Path) -> Result<String, Box<dyn Error>> {
     Ok(hex_digest_str)
 }
 
+/**
+ * Same as hash_file(), but the data is read from the storage device, and not from the page
+ * cache, using O_DIRECT with an aligned buffer of (at least) buf_size bytes.
+ *
+ * Filesystems that don't support O_DIRECT fall back to flushing the file to disk, dropping it
+ * from the page cache, and hashing it normally.
+ */
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn hash_file_uncached(path: &Path, buf_size: usize) -> Result<String, Box<dyn Error>> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut f = match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)
+    {
+        Ok(f) => f,
+        Err(e) if Some(libc::EINVAL) == e.raw_os_error() => {
+            let f = File::open(path)?;
+            f.sync_data()?;
+            drop_cached_pages(&f);
+            return hash_file_with_progress(path, buf_size, &mut |_| Ok(()));
+        }
+        Err(e) => return Err(Box::new(e)),
+    };
+
+    // every read has to go into an aligned buffer, and be a multiple of the alignment
+    let len = buf_size.max(1).div_ceil(DIRECT_IO_ALIGN) * DIRECT_IO_ALIGN;
+    let mut storage = vec![0; len + DIRECT_IO_ALIGN];
+    let offset = storage.as_ptr().align_offset(DIRECT_IO_ALIGN);
+    let buf = &mut storage[offset..offset + len];
+
+    let mut hasher = Sha256::new();
+    loop {
+        match f.read(buf) {
+            Ok(0) => break,
+            Ok(bytes_read) => hasher.update(&buf[..bytes_read]),
+            Err(e) if ErrorKind::Interrupted == e.kind() => continue,
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub fn hash_file_uncached(path: &Path, buf_size: usize) -> Result<String, Box<dyn Error>> {
+    let f = File::open(path)?;
+    f.sync_data()?;
+    hash_file_with_progress(path, buf_size, &mut |_| Ok(()))
+}
+
+/**
+ * Tell the kernel that the file will be read from start to end, so it reads further ahead.
+ *
+ * This is only a hint, so errors are ignored.
+ */
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn advise_sequential(file: &File) {
+    use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+    use std::os::unix::io::AsRawFd;
+
+    let _ = posix_fadvise(
+        file.as_raw_fd(),
+        0,
+        0,
+        PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL,
+    );
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub fn advise_sequential(_file: &File) {}
+
+/**
+ * Tell the kernel that the file's data won't be needed again, so its pages can be dropped from
+ * the page cache, instead of pushing out everything else on the machine during a large copy.
+ * Dirty pages can't be dropped until they have been written back (see flush_cached_pages()).
+ *
+ * This is only a hint, so errors are ignored.
+ */
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn drop_cached_pages(file: &File) {
+    use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+    use std::os::unix::io::AsRawFd;
+
+    let _ = posix_fadvise(
+        file.as_raw_fd(),
+        0,
+        0,
+        PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+    );
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub fn drop_cached_pages(_file: &File) {}
+
+/**
+ * Write a file's dirty pages back to disk, and wait for them, then drop them from the page cache
+ * with drop_cached_pages(). The file's metadata isn't flushed.
+ */
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn flush_cached_pages(file: &File) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::io::AsRawFd;
+
+    let flags = libc::SYNC_FILE_RANGE_WAIT_BEFORE
+        | libc::SYNC_FILE_RANGE_WRITE
+        | libc::SYNC_FILE_RANGE_WAIT_AFTER;
+    let result = unsafe { libc::sync_file_range(file.as_raw_fd(), 0, 0, flags) };
+    if 0 != result {
+        return Err(Box::new(std::io::Error::last_os_error()));
+    }
+
+    drop_cached_pages(file);
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub fn flush_cached_pages(_file: &File) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+/**
+ * Get the number of bytes actually allocated on disk for a file. This is less than its length
+ * if the file is sparse (has holes that read back as zeros, but take up no space).
+ */
+pub fn allocated_len(metadata: &Metadata) -> u64 {
+    (metadata.blocks() * 512).min(metadata.len())
+}
+
+/**
+ * Does the file have holes in it?
+ */
+pub fn is_sparse(metadata: &Metadata) -> bool {
+    allocated_len(metadata) < metadata.len()
+}
+
+/**
+ * Get the number of bytes a copy of a file will take up in a destination: the bytes allocated
+ * for its data, or its whole length if the destination's filesystem can't keep holes (see
+ * keeps_holes()).
+ */
+pub fn written_len(metadata: &Metadata, keeps_holes: bool) -> u64 {
+    if keeps_holes {
+        allocated_len(metadata)
+    } else {
+        metadata.len()
+    }
+}
+
+/**
+ * Can the filesystem containing path keep holes in files? Filesystems without sparse files, like
+ * FAT, exFAT and HFS+, write out the holes as zeros.
+ *
+ * Returns false if the filesystem can't be identified.
+ */
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn keeps_holes(path: &Path) -> bool {
+    use nix::sys::statfs::{FsType, MSDOS_SUPER_MAGIC};
+
+    // filesystem magic numbers that nix doesn't have (see statfs(2))
+    const EXFAT_SUPER_MAGIC: FsType = FsType(0x2011bab0);
+    const HFSPLUS_SUPER_MAGIC: FsType = FsType(0x482b);
+
+    match nix::sys::statfs::statfs(path) {
+        Ok(stat) => ![MSDOS_SUPER_MAGIC, EXFAT_SUPER_MAGIC, HFSPLUS_SUPER_MAGIC]
+            .contains(&stat.filesystem_type()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub fn keeps_holes(_path: &Path) -> bool {
+    true
+}
+
+/**
+ * Find the ranges of a file that contain data, as a list of (start, end) offsets, using
+ * SEEK_DATA and SEEK_HOLE. Everything between the ranges is a hole.
+ */
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn data_segments(file: &File, len: u64) -> Result<Vec<(u64, u64)>, Box<dyn Error>> {
+    use nix::unistd::{lseek, Whence};
+    use std::os::unix::io::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut segments = vec![];
+    let mut pos = 0;
+
+    while pos < len {
+        let start = match lseek(fd, pos as libc::off_t, Whence::SeekData) {
+            Ok(start) => start as u64,
+            // no more data after pos, the rest of the file is a hole
+            Err(nix::errno::Errno::ENXIO) => break,
+            Err(e) => return Err(Box::new(e)),
+        };
+        let end = (lseek(fd, start as libc::off_t, Whence::SeekHole)? as u64).min(len);
+
+        segments.push((start, end));
+        pos = end;
+    }
+
+    Ok(segments)
+}
+
+/**
+ * Read the given range of a file one buffer at a time, passing each chunk to on_chunk.
+ */
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn read_segment(
+    file: &File,
+    start: u64,
+    end: u64,
+    buf: &mut [u8],
+    on_chunk: &mut ChunkFn,
+) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::FileExt;
+
+    let mut pos = start;
+    while pos < end {
+        let count = ((end - pos) as usize).min(buf.len());
+        match file.read_at(&mut buf[..count], pos) {
+            // the file got shorter while we were reading it
+            Ok(0) => break,
+            Ok(bytes_read) => {
+                on_chunk(&buf[..bytes_read])?;
+                pos += bytes_read as u64;
+            }
+            Err(e) if ErrorKind::Interrupted == e.kind() => continue,
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+
+    Ok(())
+}
+
+/**
+ * Feed the given number of zero bytes into a hasher.
+ */
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn hash_zeros(hasher: &mut Sha256, count: u64) {
+    let zeros = [0; ZEROS_SIZE];
+    let mut remaining = count;
+    while remaining > 0 {
+        let n = remaining.min(ZEROS_SIZE as u64);
+        hasher.update(&zeros[..n as usize]);
+        remaining -= n;
+    }
+}
+
+/**
+ * Get the number of bytes available to unprivileged users on the filesystem containing path.
+ */
+pub fn free_space(path: &Path) -> Result<u64, Box<dyn Error>> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
 /**
  * Copy the access time and modification time from the source file to the destination file.
  */
@@ -103,10 +471,13 @@ pub fn copy_file_time_metadata(src: &Path, dest: &Path) -> Result<(), Box<dyn Er
 
 /**
  * Create the given directory.
+ *
+ * If a mode is given, it is applied with chmod() after the directory is created, so it is not
+ * affected by the umask, and can include the setgid bit.
  */
-pub fn mkdir(path: &Path) -> Result<(), Box<dyn Error>> {
+pub fn mkdir(path: &Path, mode: Option<u32>) -> Result<(), Box<dyn Error>> {
     match fs::create_dir(path) {
-        Ok(_) => Ok(()),
+        Ok(_) => {}
         Err(e) => {
             let err_msg = format!(
                 "could not create directory: '{}': '{}'",
@@ -116,23 +487,64 @@ pub fn mkdir(path: &Path) -> Result<(), Box<dyn Error>> {
             return dit_error(&err_msg);
         }
     }
+
+    match mode {
+        Some(mode) => chmod_verified(path, mode),
+        None => Ok(()),
+    }
 }
 
 /**
  * Create the given directory, recursively.
+ *
+ * If a mode is given, it is applied to each directory that gets created, the same way as mkdir().
  */
-pub fn mkdir_p(path: &Path) -> Result<(), Box<dyn Error>> {
-    match fs::create_dir_all(path) {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            let err_msg = format!(
-                "could not recursively create directory: '{}': '{}'",
-                path.to_str().unwrap(),
-                e
-            );
-            return dit_error(&err_msg);
+pub fn mkdir_p(path: &Path, mode: Option<u32>) -> Result<(), Box<dyn Error>> {
+    if mode.is_none() {
+        return match fs::create_dir_all(path) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                let err_msg = format!(
+                    "could not recursively create directory: '{}': '{}'",
+                    path.to_str().unwrap(),
+                    e
+                );
+                dit_error(&err_msg)
+            }
+        };
+    }
+
+    // create each missing directory from the top down, so each one can be chmod-ed
+    let mut missing = vec![];
+    for ancestor in path.ancestors() {
+        if ancestor.as_os_str().is_empty() || ancestor.is_dir() {
+            break;
         }
+        missing.push(ancestor);
     }
+
+    for dir in missing.iter().rev() {
+        // another copy thread can create the same directory between the is_dir() check above and
+        // here, and it applies the mode itself
+        match fs::create_dir(dir) {
+            Ok(_) => {}
+            Err(e) if ErrorKind::AlreadyExists == e.kind() && dir.is_dir() => continue,
+            Err(e) => {
+                let err_msg = format!(
+                    "could not create directory: '{}': '{}'",
+                    dir.to_str().unwrap(),
+                    e
+                );
+                return dit_error(&err_msg);
+            }
+        }
+
+        if let Some(mode) = mode {
+            chmod_verified(dir, mode)?;
+        }
+    }
+
+    Ok(())
 }
 
 /**
@@ -168,13 +580,13 @@ pub fn mkstemp(base_dir: &Path) -> Result<(File, PathBuf), Box<dyn Error>> {
 }
 
 /**
- * Basic chmod 644 operation for files.
+ * Basic chmod operation for files (e.g. 0o644).
  */
-pub fn chmod(path: &Path) -> Result<(), Box<dyn Error>> {
+pub fn chmod(path: &Path, mode: u32) -> Result<(), Box<dyn Error>> {
     let path_str = String::from(path.to_str().unwrap());
     let c_str_path = CString::new(path_str)?;
 
-    let result = unsafe { libc::chmod(c_str_path.as_ptr(), 0o644) };
+    let result = unsafe { libc::chmod(c_str_path.as_ptr(), mode as libc::mode_t) };
     if 0 != result {
         let err = format!(
             "could not chmod file: '{}', chmod() returned {}",
@@ -187,32 +599,406 @@ pub fn chmod(path: &Path) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/**
+ * Same as chmod(), but also reads the mode back afterwards, to make sure that the filesystem
+ * actually applied it (some filesystems silently ignore permissions).
+ */
+pub fn chmod_verified(path: &Path, mode: u32) -> Result<(), Box<dyn Error>> {
+    chmod(path, mode)?;
+
+    let actual_mode = path.metadata()?.mode() & 0o7777;
+    if actual_mode != mode {
+        let err = format!(
+            "could not chmod: '{}', mode is {:o} instead of {:o}",
+            path.to_str().unwrap(),
+            actual_mode,
+            mode
+        );
+        return dit_error(&err);
+    }
+
+    Ok(())
+}
+
+/**
+ * Basic chown operation for files, without following symlinks.
+ */
+pub fn chown(path: &Path, uid: u32, gid: u32) -> Result<(), Box<dyn Error>> {
+    let path_str = String::from(path.to_str().unwrap());
+    let c_str_path = CString::new(path_str)?;
+
+    let result = unsafe { libc::lchown(c_str_path.as_ptr(), uid, gid) };
+    if 0 != result {
+        let err = format!(
+            "could not chown file: '{}': {}",
+            path.to_str().unwrap(),
+            std::io::Error::last_os_error()
+        );
+        return dit_error(&err);
+    }
+
+    Ok(())
+}
+
+/**
+ * Copy the extended attributes whose names start with one of the given prefixes
+ * (e.g. "user.") from the source file to the destination file.
+ *
+ * POSIX ACLs are stored in the "system.posix_acl_access" extended attribute, so they can be
+ * copied this way too.
+ *
+ * Attributes that the destination filesystem doesn't support (ENOTSUP), or doesn't let us set
+ * (EPERM), are skipped, and returned with their errors. A source filesystem without extended
+ * attributes has none to copy.
+ */
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn copy_xattrs(
+    src: &Path,
+    dest: &Path,
+    prefixes: &[&str],
+) -> Result<Vec<(String, std::io::Error)>, Box<dyn Error>> {
+    let c_src = CString::new(src.to_str().unwrap())?;
+    let c_dest = CString::new(dest.to_str().unwrap())?;
+    let mut skipped = vec![];
+
+    // the list of names is a sequence of NUL-terminated strings
+    let size = unsafe { libc::llistxattr(c_src.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let os_error = std::io::Error::last_os_error();
+        if Some(libc::ENOTSUP) == os_error.raw_os_error() {
+            return Ok(skipped);
+        }
+        return xattr_error("list", src, "", os_error);
+    }
+    let mut names = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::llistxattr(
+            c_src.as_ptr(),
+            names.as_mut_ptr() as *mut libc::c_char,
+            names.len(),
+        )
+    };
+    if size < 0 {
+        return xattr_error("list", src, "", std::io::Error::last_os_error());
+    }
+    names.truncate(size as usize);
+
+    for name in names.split(|b| 0 == *b).filter(|name| !name.is_empty()) {
+        let name_str = String::from_utf8_lossy(name);
+        if !prefixes.iter().any(|prefix| name_str.starts_with(prefix)) {
+            continue;
+        }
+        let c_name = CString::new(name)?;
+
+        let size =
+            unsafe { libc::lgetxattr(c_src.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return xattr_error("read", src, &name_str, std::io::Error::last_os_error());
+        }
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe {
+            libc::lgetxattr(
+                c_src.as_ptr(),
+                c_name.as_ptr(),
+                value.as_mut_ptr() as *mut libc::c_void,
+                value.len(),
+            )
+        };
+        if size < 0 {
+            return xattr_error("read", src, &name_str, std::io::Error::last_os_error());
+        }
+
+        let result = unsafe {
+            libc::lsetxattr(
+                c_dest.as_ptr(),
+                c_name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                size as usize,
+                0,
+            )
+        };
+        if 0 != result {
+            let os_error = std::io::Error::last_os_error();
+            match os_error.raw_os_error() {
+                Some(libc::ENOTSUP) | Some(libc::EPERM) => {
+                    skipped.push((name_str.into_owned(), os_error));
+                }
+                _ => return xattr_error("write", dest, &name_str, os_error),
+            }
+        }
+    }
+
+    Ok(skipped)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub fn copy_xattrs(
+    _src: &Path,
+    _dest: &Path,
+    _prefixes: &[&str],
+) -> Result<Vec<(String, std::io::Error)>, Box<dyn Error>> {
+    dit_error("copying extended attributes is not supported on this platform")
+}
+
+/**
+ * Error for a failed extended attribute call, with the OS error (and its errno) in the message,
+ * and the same ErrorKind.
+ */
+#[cfg(any(target_os = "android", target_os = "linux"))]
+fn xattr_error<T>(
+    action: &str,
+    path: &Path,
+    name: &str,
+    os_error: std::io::Error,
+) -> Result<T, Box<dyn Error>> {
+    let err = if name.is_empty() {
+        format!(
+            "could not {} extended attributes on '{}': {}",
+            action,
+            path.to_str().unwrap(),
+            os_error
+        )
+    } else {
+        format!(
+            "could not {} extended attribute '{}' on '{}': {}",
+            action,
+            name,
+            path.to_str().unwrap(),
+            os_error
+        )
+    };
+    Err(Box::new(std::io::Error::new(os_error.kind(), err)))
+}
+
+/**
+ * Allocate disk space for the whole destination file up front, before copying the source file
+ * into it, without changing its size where the filesystem allows it. This keeps large files from
+ * getting fragmented when several are written to the same disk at once, and makes a full disk
+ * fail right away instead of halfway through the file.
+ *
+ * Skipped for sparse source files, whose holes shouldn't be allocated, and for files on the same
+ * filesystem, which might be reflinked instead of copied. Filesystems that don't support
+ * fallocate() are silently skipped too.
+ */
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn preallocate(src: &File, dest: &File) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::io::AsRawFd;
+
+    let src_metadata = src.metadata()?;
+    let len = src_metadata.len();
+
+    if 0 == len || is_sparse(&src_metadata) || src_metadata.dev() == dest.metadata()?.dev() {
+        return Ok(());
+    }
+
+    // keep the file size as it is, so the copy sets it, and a copy that stops early doesn't leave
+    // a file of the full length behind
+    let mut result = unsafe {
+        libc::fallocate(
+            dest.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            len as libc::off_t,
+        )
+    };
+
+    // some filesystems, like exFAT, can only allocate space by extending the file, which is fine
+    // for a temp file that the copy overwrites from the start (and that is removed if it fails)
+    if 0 != result && Some(libc::EOPNOTSUPP) == std::io::Error::last_os_error().raw_os_error() {
+        result = unsafe { libc::fallocate(dest.as_raw_fd(), 0, 0, len as libc::off_t) };
+    }
+
+    if 0 != result {
+        let err = std::io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(()),
+            _ => Err(Box::new(err)),
+        };
+    }
+
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub fn preallocate(_src: &File, _dest: &File) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+/**
+ * How a file was copied by copy_file().
+ */
+#[derive(Clone, Copy, PartialEq)]
+pub enum CopyMethod {
+    // the destination shares the source's data blocks (copy-on-write)
+    Reflink,
+
+    // only the data in a sparse file is copied, and the holes are left as holes
+    Sparse,
+
+    // in-kernel copy, which some filesystems offload to the storage device
+    CopyFileRange,
+
+    // in-kernel copy through the page cache
+    Sendfile,
+
+    // plain read() and write() through a buffer
+    ReadWrite,
+}
+
+impl Display for CopyMethod {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            CopyMethod::Reflink => "reflink",
+            CopyMethod::Sparse => "sparse",
+            CopyMethod::CopyFileRange => "copy_file_range",
+            CopyMethod::Sendfile => "sendfile",
+            CopyMethod::ReadWrite => "read/write",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// FICLONE ioctl request, _IOW(0x94, 9, int) in the generic ioctl encoding used by x86, ARM and
+// RISC-V (libc only defines it on x86), platforms that encode it differently just fall back
+#[cfg(any(target_os = "android", target_os = "linux"))]
+const FICLONE: u32 = 0x40049409;
+
 /**
  * Copy a source file to a destination file, creating or overwriting the destination file.
+ *
+ * The fastest method the filesystems support is used: first a reflink (when both files are on
+ * the same btrfs/XFS filesystem), then copy_file_range(), then sendfile(), and finally plain
+ * reads and writes. Each method falls back to the next one only if it fails before copying
+ * anything.
+ *
+ * If the source file is sparse (and can't be reflinked), only its data is copied, so the holes
+ * stay unallocated in the destination file too.
+ *
+ * The file is copied in chunks, and on_progress is called after each one. Plain reads and
+ * writes use a buffer of buf_size bytes.
+ *
+ * Returns the method that was used.
  */
 #[cfg(any(target_os = "android", target_os = "linux"))]
-pub fn copy_file(src: &File, dest: &File) -> Result<(), Box<dyn Error>> {
+pub fn copy_file(
+    src: &File,
+    dest: &File,
+    buf_size: usize,
+    on_progress: &mut ProgressFn,
+) -> Result<CopyMethod, Box<dyn Error>> {
+    use std::os::unix::fs::FileExt;
     use std::os::unix::io::AsRawFd;
 
     let src_fd = src.as_raw_fd();
     let dest_fd = dest.as_raw_fd();
+
+    let len = src.metadata()?.len();
+
+    // reflink the whole file in one go
+    let result = unsafe { libc::ioctl(dest_fd, FICLONE as _, src_fd) };
+    if 0 == result {
+        on_progress(len)?;
+        return Ok(CopyMethod::Reflink);
+    }
+
+    // only copy the data in sparse files, and leave the holes as holes
+    if is_sparse(&src.metadata()?) {
+        let mut buf = vec![0; COPY_CHUNK_SIZE as usize];
+        for (start, end) in data_segments(src, len)? {
+            let mut pos = start;
+            read_segment(src, start, end, &mut buf, &mut |chunk| {
+                dest.write_all_at(chunk, pos)?;
+                pos += chunk.len() as u64;
+                on_progress(chunk.len() as u64)
+            })?;
+        }
+
+        // extend the file to its full length, in case it ends with a hole
+        dest.set_len(len)?;
+
+        return Ok(CopyMethod::Sparse);
+    }
+
+    // copy_file_range() advances offset by the number of bytes copied
+    let mut offset: libc::loff_t = 0;
+    while (offset as u64) < len {
+        let count = (len - offset as u64).min(COPY_CHUNK_SIZE) as usize;
+        let mut dest_offset = offset;
+
+        match nix::fcntl::copy_file_range(
+            src_fd,
+            Some(&mut offset),
+            dest_fd,
+            Some(&mut dest_offset),
+            count,
+        ) {
+            // the source file got shorter while we were copying it
+            Ok(0) => break,
+            Ok(n) => on_progress(n as u64)?,
+            Err(nix::errno::Errno::EINTR) => continue,
+            // not supported between these files, try the next method
+            Err(_) if 0 == offset => break,
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+    if offset > 0 {
+        return Ok(CopyMethod::CopyFileRange);
+    }
+
+    // sendfile() advances offset by the number of bytes copied
     let mut offset: libc::off_t = 0;
+    while (offset as u64) < len {
+        let count = (len - offset as u64).min(COPY_CHUNK_SIZE) as libc::size_t;
 
-    let count = src.metadata()?.len() as libc::size_t;
+        let n = unsafe { libc::sendfile(dest_fd, src_fd, &mut offset, count) };
+        if -1 == n {
+            let err = std::io::Error::last_os_error();
+            if ErrorKind::Interrupted == err.kind() {
+                continue;
+            }
+            // not supported between these files, try the next method
+            if 0 == offset {
+                break;
+            }
+            return Err(Box::new(err));
+        }
 
-    let n = unsafe { libc::sendfile(dest_fd, src_fd, &mut offset, count) };
-    if -1 == n {
-        return Err(Box::new(std::io::Error::last_os_error()));
+        // the source file got shorter while we were copying it
+        if 0 == n {
+            break;
+        }
+
+        on_progress(n as u64)?;
+    }
+    if offset > 0 || 0 == len {
+        return Ok(CopyMethod::Sendfile);
     }
 
-    Ok(())
+    copy_file_read_write(src, dest, buf_size, on_progress)
 }
 
 #[cfg(not(any(target_os = "android", target_os = "linux")))]
-pub fn copy_file(mut src: &File, mut dest: &File) -> Result<(), Box<dyn Error>> {
+pub fn copy_file(
+    src: &File,
+    dest: &File,
+    buf_size: usize,
+    on_progress: &mut ProgressFn,
+) -> Result<CopyMethod, Box<dyn Error>> {
+    copy_file_read_write(src, dest, buf_size, on_progress)
+}
+
+/**
+ * Copy a source file to a destination file with plain reads and writes.
+ */
+fn copy_file_read_write(
+    mut src: &File,
+    mut dest: &File,
+    buf_size: usize,
+    on_progress: &mut ProgressFn,
+) -> Result<CopyMethod, Box<dyn Error>> {
     use std::io::Write;
 
-    let mut buf = [0; BUF_SIZE];
+    let mut buf = vec![0; buf_size];
 
     loop {
         match src.read(&mut buf) {
@@ -227,6 +1013,8 @@ pub fn copy_file(mut src: &File, mut dest: &File) -> Result<(), Box<dyn Error>>
                         return common::dit_error("write error");
                     }
                 }
+
+                on_progress(bytes_read as u64)?;
             }
             Err(e) => match e.kind() {
                 ErrorKind::UnexpectedEof => break,
@@ -238,5 +1026,5 @@ pub fn copy_file(mut src: &File, mut dest: &File) -> Result<(), Box<dyn Error>>
         }
     }
 
-    Ok(())
+    Ok(CopyMethod::ReadWrite)
 }
@@ -0,0 +1,363 @@
+use crate::common::dit_error;
+use crate::media;
+use crate::media::MediaType;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+
+// JPEG markers
+const JPEG_SOI: u8 = 0xd8;
+const JPEG_EOI: u8 = 0xd9;
+const JPEG_SOS: u8 = 0xda;
+const JPEG_TEM: u8 = 0x01;
+const JPEG_RST0: u8 = 0xd0;
+const JPEG_RST7: u8 = 0xd7;
+
+/**
+ * Check the structure of a JPEG or ISO-BMFF (MP4, MOV, CR3, HEIF) file, to catch files that are
+ * damaged or truncated (like a clip from a camera that lost power while recording), even when
+ * there is only one copy to compare against.
+ *
+ * For JPEGs, this checks for the SOI marker, walks the segments up to the image data checking
+ * their lengths, and looks for the EOI marker after the image data. For ISO-BMFF files, it walks
+ * the top-level boxes, and checks that their sizes add up to the size of the file, and that
+ * movies (and CR3 files) have a moov box. Other files always pass.
+ *
+ * The type of file comes from its contents, or else from its extension (see media::classify()).
+ * Returns an error describing the first problem found.
+ */
+pub fn check(path: &Path) -> Result<(), Box<dyn Error>> {
+    let classification = media::classify(path);
+    let media_type = classification.by_contents.or(classification.by_extension);
+
+    match media_type {
+        Some(MediaType::Jpeg) => check_jpeg(path),
+        Some(MediaType::Movie) | Some(MediaType::Cr3) => check_bmff(path, true),
+        Some(MediaType::Heif) => check_bmff(path, false),
+        _ => Ok(()),
+    }
+}
+
+/**
+ * Check the markers and segment lengths of a JPEG file.
+ */
+fn check_jpeg(path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    match (read_byte(&mut reader)?, read_byte(&mut reader)?) {
+        (Some(0xff), Some(JPEG_SOI)) => {}
+        _ => return dit_error("no JPEG SOI marker at the start"),
+    }
+
+    // segments before the image data, each with a 2 byte length (that counts itself)
+    loop {
+        let marker = match read_marker(&mut reader)? {
+            Some(marker) => marker,
+            None => return dit_error("truncated before the JPEG image data"),
+        };
+
+        match marker {
+            JPEG_EOI => return dit_error("JPEG EOI marker before the image data"),
+            JPEG_TEM | JPEG_RST0..=JPEG_RST7 => continue,
+            _ => {}
+        }
+
+        let mut len_bytes = [0; 2];
+        if !read_fully(&mut reader, &mut len_bytes)? {
+            return dit_error("truncated in a JPEG segment header");
+        }
+        let len = u16::from_be_bytes(len_bytes) as i64;
+        if len < 2 {
+            let err_msg = format!(
+                "invalid JPEG segment length {} (marker 0x{:02x})",
+                len, marker
+            );
+            return dit_error(&err_msg);
+        }
+
+        let pos = reader.stream_position()?;
+        let file_len = reader.get_ref().metadata()?.len();
+        if pos + len as u64 - 2 > file_len {
+            let err_msg = format!("truncated in a JPEG segment (marker 0x{:02x})", marker);
+            return dit_error(&err_msg);
+        }
+        reader.seek_relative(len - 2)?;
+
+        if JPEG_SOS == marker {
+            break;
+        }
+    }
+
+    // image data, where 0xff is followed by 0x00 (an escaped 0xff), a restart marker, or the
+    // marker that ends the scan (usually EOI, or SOS/DHT/... in progressive JPEGs)
+    let mut prev = 0;
+    while let Some(b) = read_byte(&mut reader)? {
+        if 0xff == prev && JPEG_EOI == b {
+            return Ok(());
+        }
+        prev = b;
+    }
+
+    dit_error("truncated: no JPEG EOI marker after the image data")
+}
+
+/**
+ * Check that the top-level boxes of an ISO-BMFF file add up to the size of the file, and
+ * optionally that it has a moov box.
+ */
+fn check_bmff(path: &Path, need_moov: bool) -> Result<(), Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+
+    let mut has_moov = false;
+    let mut offset = 0;
+    while offset < file_len {
+        let mut header = [0; 16];
+        let header = if file_len - offset >= 16 {
+            &mut header[..]
+        } else {
+            &mut header[..8]
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        if !read_fully(&mut file, header)? {
+            let err_msg = format!("truncated: {} stray bytes at the end", file_len - offset);
+            return dit_error(&err_msg);
+        }
+
+        let box_type = String::from_utf8_lossy(&header[4..8]).into_owned();
+        let box_len = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // extends to the end of the file
+            0 => file_len - offset,
+
+            // 64 bit size after the type
+            1 if header.len() >= 16 => u64::from_be_bytes(header[8..16].try_into().unwrap()),
+
+            n => n as u64,
+        };
+        if box_len < 8 {
+            let err_msg = format!(
+                "invalid size {} for '{}' box at offset {}",
+                box_len, box_type, offset
+            );
+            return dit_error(&err_msg);
+        }
+        if box_len > file_len - offset {
+            let err_msg = format!(
+                "truncated: '{}' box at offset {} needs {} bytes, but only {} are left",
+                box_type,
+                offset,
+                box_len,
+                file_len - offset
+            );
+            return dit_error(&err_msg);
+        }
+
+        if "moov" == box_type {
+            has_moov = true;
+        }
+        offset += box_len;
+    }
+
+    if need_moov && !has_moov {
+        return dit_error("truncated: no 'moov' box (the recording may not have been finished)");
+    }
+
+    Ok(())
+}
+
+/**
+ * Read the next JPEG marker, skipping any 0xff fill bytes before it, or None at the end of the
+ * file.
+ */
+fn read_marker(reader: &mut impl Read) -> Result<Option<u8>, Box<dyn Error>> {
+    match read_byte(reader)? {
+        Some(0xff) => {}
+        Some(b) => {
+            let err_msg = format!("expected a JPEG marker, found 0x{:02x}", b);
+            return dit_error(&err_msg);
+        }
+        None => return Ok(None),
+    }
+
+    loop {
+        match read_byte(reader)? {
+            Some(0xff) => continue,
+            b => return Ok(b),
+        }
+    }
+}
+
+/**
+ * Read a single byte, or None at the end of the file.
+ */
+fn read_byte(reader: &mut impl Read) -> Result<Option<u8>, Box<dyn Error>> {
+    let mut buf = [0; 1];
+    if !read_fully(reader, &mut buf)? {
+        return Ok(None);
+    }
+
+    Ok(Some(buf[0]))
+}
+
+/**
+ * Fill the buffer, or return false if the file ends first.
+ */
+fn read_fully(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, Box<dyn Error>> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if ErrorKind::UnexpectedEof == e.kind() => Ok(false),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // the error message of a check
+    type CheckResult = Result<(), String>;
+
+    // SOI, a JFIF APP0 segment, SOS, image data with an escaped 0xff and a restart marker, EOI
+    const JPEG: &[u8] = b"\xff\xd8\
+        \xff\xe0\x00\x10JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00\
+        \xff\xda\x00\x08\x01\x01\x00\x00\x3f\x00\
+        \x12\x34\xff\x00\x56\xff\xd0\x78\
+        \xff\xd9";
+
+    fn bmff_box(box_type: &[u8; 4], payload_len: usize) -> Vec<u8> {
+        let mut bytes = ((8 + payload_len) as u32).to_be_bytes().to_vec();
+        bytes.extend(box_type);
+        bytes.extend(vec![0; payload_len]);
+        bytes
+    }
+
+    fn movie() -> Vec<u8> {
+        let mut bytes = bmff_box(b"ftyp", 8);
+        bytes.extend(bmff_box(b"moov", 16));
+        bytes.extend(bmff_box(b"mdat", 32));
+        bytes
+    }
+
+    fn check_bytes(
+        name: &str,
+        bytes: &[u8],
+        check_fn: impl Fn(&Path) -> CheckResult,
+    ) -> CheckResult {
+        let path = std::env::temp_dir().join(format!(
+            "dit-test-integrity-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, bytes).unwrap();
+        let result = check_fn(&path);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    fn jpeg(name: &str, bytes: &[u8]) -> CheckResult {
+        check_bytes(name, bytes, |path| {
+            check_jpeg(path).map_err(|e| e.to_string())
+        })
+    }
+
+    fn bmff(name: &str, bytes: &[u8], need_moov: bool) -> CheckResult {
+        check_bytes(name, bytes, |path| {
+            check_bmff(path, need_moov).map_err(|e| e.to_string())
+        })
+    }
+
+    #[test]
+    fn jpeg_valid() {
+        assert_eq!(Ok(()), jpeg("jpeg-valid", JPEG));
+
+        // fill bytes before a marker
+        let mut filled = JPEG[..2].to_vec();
+        filled.extend(b"\xff\xff");
+        filled.extend(&JPEG[2..]);
+        assert_eq!(Ok(()), jpeg("jpeg-fill", &filled));
+    }
+
+    #[test]
+    fn jpeg_truncated() {
+        let err = jpeg("jpeg-no-eoi", &JPEG[..JPEG.len() - 2]).unwrap_err();
+        assert!(err.contains("no JPEG EOI marker"), "{}", err);
+
+        let err = jpeg("jpeg-in-segment", &JPEG[..10]).unwrap_err();
+        assert!(err.contains("truncated in a JPEG segment"), "{}", err);
+
+        let err = jpeg("jpeg-in-header", &JPEG[..5]).unwrap_err();
+        assert!(
+            err.contains("truncated in a JPEG segment header"),
+            "{}",
+            err
+        );
+
+        let err = jpeg("jpeg-before-data", &JPEG[..2]).unwrap_err();
+        assert!(
+            err.contains("truncated before the JPEG image data"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn jpeg_damaged() {
+        let err = jpeg("jpeg-zeros", &[0; 64]).unwrap_err();
+        assert!(err.contains("no JPEG SOI marker"), "{}", err);
+
+        let err = jpeg("jpeg-early-eoi", b"\xff\xd8\xff\xd9").unwrap_err();
+        assert!(err.contains("EOI marker before the image data"), "{}", err);
+
+        let err = jpeg("jpeg-bad-length", b"\xff\xd8\xff\xe0\x00\x01").unwrap_err();
+        assert!(err.contains("invalid JPEG segment length 1"), "{}", err);
+
+        let err = jpeg("jpeg-no-marker", b"\xff\xd8\x00\x00").unwrap_err();
+        assert!(err.contains("expected a JPEG marker"), "{}", err);
+    }
+
+    #[test]
+    fn bmff_valid() {
+        assert_eq!(Ok(()), bmff("bmff-valid", &movie(), true));
+
+        // a 64 bit box size
+        let mut large = bmff_box(b"ftyp", 8);
+        large.extend(bmff_box(b"moov", 0));
+        large.extend(b"\x00\x00\x00\x01mdat");
+        large.extend(24u64.to_be_bytes());
+        large.extend([0; 8]);
+        assert_eq!(Ok(()), bmff("bmff-large", &large, true));
+
+        // a last box that extends to the end of the file
+        let mut to_end = movie();
+        to_end.extend(b"\x00\x00\x00\x00free\x01\x02\x03");
+        assert_eq!(Ok(()), bmff("bmff-to-end", &to_end, true));
+
+        // HEIF files don't need a moov box
+        let heif = bmff_box(b"ftyp", 8);
+        assert_eq!(Ok(()), bmff("bmff-heif", &heif, false));
+    }
+
+    #[test]
+    fn bmff_truncated() {
+        let movie = movie();
+        let err = bmff("bmff-cut", &movie[..movie.len() - 1], true).unwrap_err();
+        assert!(
+            err.contains("'mdat' box at offset 40 needs 40 bytes"),
+            "{}",
+            err
+        );
+
+        let mut stray = movie.clone();
+        stray.extend([1, 2, 3]);
+        let err = bmff("bmff-stray", &stray, true).unwrap_err();
+        assert!(err.contains("3 stray bytes at the end"), "{}", err);
+
+        let err = bmff("bmff-no-moov", &bmff_box(b"ftyp", 8), true).unwrap_err();
+        assert!(err.contains("no 'moov' box"), "{}", err);
+
+        let err = bmff("bmff-bad-size", b"\x00\x00\x00\x04ftyp", true).unwrap_err();
+        assert!(err.contains("invalid size 4 for 'ftyp' box"), "{}", err);
+    }
+}
@@ -0,0 +1,376 @@
+use crate::common::dit_error;
+use crate::media;
+use crate::media::CaptureInfo;
+use crate::volume::UNKNOWN_CARD;
+use std::error::Error;
+use std::path::PathBuf;
+
+// fills in {camera} for files without a camera name in their metadata
+const UNKNOWN_CAMERA: &str = "unknown";
+
+/**
+ * One piece of a layout template.
+ */
+#[derive(Clone)]
+enum Part {
+    Text(String),
+    Year,
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+    Camera,
+    Card,
+    Filename,
+}
+
+/**
+ * Destination layout template, which says where each source file goes in the destinations,
+ * e.g. "{year}/{year}-{month}-{day}/{camera}/{filename}", instead of mirroring its sub path in the
+ * source.
+ *
+ * Tokens are filled in from the capture time and camera in the metadata of the file, or of the
+ * photo or video it belongs with (see media::capture_info() and sub_paths()), and {card} from the
+ * card or volume of the file's read path (see Volume::card_name()).
+ */
+#[derive(Clone)]
+pub struct Layout {
+    parts: Vec<Part>,
+
+    // name of the card that each read path is on, for {card}
+    cards: Vec<String>,
+}
+
+impl Layout {
+    /**
+     * Parse a layout template.
+     *
+     * Returns an error for unknown tokens, unbalanced braces, templates without a {filename}
+     * (which would put every file at the same path), and templates that could reach outside
+     * of the destinations.
+     */
+    pub fn new(template: &str) -> Result<Layout, Box<dyn Error>> {
+        let mut parts = vec![];
+        let mut text = String::new();
+
+        let mut rest = template;
+        while let Some(c) = rest.chars().next() {
+            match c {
+                '{' => {
+                    let end = match rest.find('}') {
+                        Some(end) => end,
+                        None => return layout_error(template, "unclosed '{'"),
+                    };
+
+                    let part = match &rest[1..end] {
+                        "year" => Part::Year,
+                        "month" => Part::Month,
+                        "day" => Part::Day,
+                        "hour" => Part::Hour,
+                        "minute" => Part::Minute,
+                        "second" => Part::Second,
+                        "camera" => Part::Camera,
+                        "card" => Part::Card,
+                        "filename" => Part::Filename,
+                        token => {
+                            let reason = format!("unknown token '{{{}}}'", token);
+                            return layout_error(template, &reason);
+                        }
+                    };
+
+                    if !text.is_empty() {
+                        parts.push(Part::Text(text));
+                        text = String::new();
+                    }
+                    parts.push(part);
+                    rest = &rest[end + 1..];
+                }
+                '}' => return layout_error(template, "unmatched '}'"),
+                _ => {
+                    text.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+
+        if !parts.iter().any(|part| matches!(part, Part::Filename)) {
+            return layout_error(template, "it must include {filename}");
+        }
+
+        if template.starts_with('/') || template.split('/').any(|c| ".." == c || "." == c) {
+            return layout_error(template, "it must be a relative path, without '.' or '..'");
+        }
+
+        Ok(Layout {
+            parts,
+            cards: vec![],
+        })
+    }
+
+    /**
+     * Set the name of the card that each read path is on (in the same order as the read paths),
+     * to fill in {card}.
+     */
+    pub fn with_cards(mut self, cards: Vec<String>) -> Layout {
+        self.cards = cards;
+        self
+    }
+
+    /**
+     * Get the sub paths in the destinations for a family of source files (see
+     * common::family_stem()), from the read path with the given index.
+     *
+     * The whole family takes its capture time and camera from its first photo or video file (by
+     * extension), so sidecars like XMP files, which don't have their own, end up next to it. A
+     * family without one takes them from its first file.
+     */
+    pub fn sub_paths(
+        &self,
+        family: &[PathBuf],
+        read_path_index: usize,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let primary = family
+            .iter()
+            .find(|src_path| media::classify_extension(src_path).is_some())
+            .or(family.first());
+        let CaptureInfo { time, camera } = match primary {
+            Some(primary) => media::capture_info(primary)?,
+            None => return Ok(vec![]),
+        };
+
+        let mut sub_paths = vec![];
+        for src_path in family {
+            let mut sub_path = String::new();
+            for part in &self.parts {
+                match part {
+                    Part::Text(text) => sub_path.push_str(text),
+                    Part::Year => sub_path.push_str(&format!("{:04}", time.year)),
+                    Part::Month => sub_path.push_str(&format!("{:02}", time.month)),
+                    Part::Day => sub_path.push_str(&format!("{:02}", time.day)),
+                    Part::Hour => sub_path.push_str(&format!("{:02}", time.hour)),
+                    Part::Minute => sub_path.push_str(&format!("{:02}", time.minute)),
+                    Part::Second => sub_path.push_str(&format!("{:02}", time.second)),
+                    Part::Camera => match &camera {
+                        Some(camera) => sub_path.push_str(&path_safe(camera, UNKNOWN_CAMERA)),
+                        None => sub_path.push_str(UNKNOWN_CAMERA),
+                    },
+                    Part::Card => match self.cards.get(read_path_index) {
+                        Some(card) => sub_path.push_str(&path_safe(card, UNKNOWN_CARD)),
+                        None => sub_path.push_str(UNKNOWN_CARD),
+                    },
+                    Part::Filename => {
+                        sub_path.push_str(src_path.file_name().unwrap().to_str().unwrap())
+                    }
+                }
+            }
+            sub_paths.push(sub_path);
+        }
+
+        Ok(sub_paths)
+    }
+}
+
+/**
+ * Make a string from a file's metadata, or a card name, safe to use as a single path component.
+ *
+ * If nothing is left of it, the given fallback is used instead, like when it is missing.
+ */
+pub fn path_safe(s: &str, fallback: &str) -> String {
+    let s: String = s
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | '\0' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    // don't create hidden directories, or "." and ".." components
+    let s = s.trim().trim_start_matches('.');
+    if s.is_empty() {
+        return String::from(fallback);
+    }
+
+    String::from(s)
+}
+
+fn layout_error(template: &str, reason: &str) -> Result<Layout, Box<dyn Error>> {
+    let err_msg = format!("invalid layout '{}': {}", template, reason);
+    dit_error(&err_msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // a JPEG with only an EXIF segment, with the given make, model and DateTimeOriginal
+    fn exif_jpeg(make: &str, model: &str, date_time: &str) -> Vec<u8> {
+        let strings = [make, model, date_time].map(|s| format!("{}\0", s));
+        let entry = |tiff: &mut Vec<u8>, tag: u16, kind: u16, count: usize, value: usize| {
+            tiff.extend(tag.to_le_bytes());
+            tiff.extend(kind.to_le_bytes());
+            tiff.extend((count as u32).to_le_bytes());
+            tiff.extend((value as u32).to_le_bytes());
+        };
+
+        // header, IFD0 at 8 (3 entries), EXIF IFD at 50 (1 entry), then the strings at 68
+        let mut tiff = b"II*\0\x08\0\0\0".to_vec();
+        tiff.extend(3u16.to_le_bytes());
+        entry(&mut tiff, 0x010f, 2, strings[0].len(), 68);
+        entry(
+            &mut tiff,
+            0x0110,
+            2,
+            strings[1].len(),
+            68 + strings[0].len(),
+        );
+        entry(&mut tiff, 0x8769, 4, 1, 50);
+        tiff.extend(0u32.to_le_bytes());
+        tiff.extend(1u16.to_le_bytes());
+        let date_time_offset = 68 + strings[0].len() + strings[1].len();
+        entry(&mut tiff, 0x9003, 2, strings[2].len(), date_time_offset);
+        tiff.extend(0u32.to_le_bytes());
+        for s in &strings {
+            tiff.extend(s.as_bytes());
+        }
+
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1];
+        jpeg.extend(((2 + 6 + tiff.len()) as u16).to_be_bytes());
+        jpeg.extend(b"Exif\0\0");
+        jpeg.extend(tiff);
+        jpeg.extend([0xff, 0xd9]);
+        jpeg
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dit-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn layout_error_of(template: &str) -> String {
+        match Layout::new(template) {
+            Ok(_) => panic!("'{}' should be invalid", template),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn new_rejects_bad_templates() {
+        assert!(layout_error_of("{year}/{name}").contains("unknown token '{name}'"));
+        assert!(layout_error_of("{year/{filename}").contains("unknown token"));
+        assert!(layout_error_of("{year}/{filename").contains("unclosed '{'"));
+        assert!(layout_error_of("year}/{filename}").contains("unmatched '}'"));
+        assert!(layout_error_of("{year}/{month}").contains("{filename}"));
+        assert!(layout_error_of("/{filename}").contains("relative path"));
+        assert!(layout_error_of("../{filename}").contains("relative path"));
+        assert!(layout_error_of("{year}/./{filename}").contains("relative path"));
+    }
+
+    #[test]
+    fn new_accepts_tokens_and_text() {
+        let template =
+            "{year}/{year}-{month}-{day}/{hour}{minute}{second} {camera}/{card}/{filename}";
+        assert!(Layout::new(template).is_ok());
+        assert!(Layout::new("{filename}").is_ok());
+        assert!(Layout::new("photos/{filename}.bak").is_ok());
+    }
+
+    #[test]
+    fn sub_paths_fill_in_tokens() {
+        let dir = test_dir("layout-tokens");
+        let src_path = dir.join("IMG_0001.JPG");
+        fs::write(
+            &src_path,
+            exif_jpeg("Canon", "Canon EOS R5", "2024:03:09 14:05:06"),
+        )
+        .unwrap();
+
+        let layout = Layout::new(
+            "{year}/{year}-{month}-{day}/{hour}{minute}{second}/{camera}/{card}/{filename}",
+        )
+        .unwrap()
+        .with_cards(vec![String::from("CARD/1")]);
+        let sub_paths = layout
+            .sub_paths(std::slice::from_ref(&src_path), 0)
+            .unwrap();
+        assert_eq!(
+            vec!["2024/2024-03-09/140506/Canon EOS R5/CARD_1/IMG_0001.JPG"],
+            sub_paths
+        );
+
+        // no card name for the read path
+        let sub_paths = layout.sub_paths(&[src_path], 1).unwrap();
+        assert_eq!(
+            vec![format!(
+                "2024/2024-03-09/140506/Canon EOS R5/{}/IMG_0001.JPG",
+                UNKNOWN_CARD
+            )],
+            sub_paths
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sub_paths_place_sidecars_with_their_photo() {
+        let dir = test_dir("layout-family");
+        let xmp_path = dir.join("DSCF0001.RAF.xmp");
+        let jpeg_path = dir.join("DSCF0001.JPG");
+        fs::write(&xmp_path, "<x:xmpmeta/>").unwrap();
+        fs::write(
+            &jpeg_path,
+            exif_jpeg("FUJIFILM", "X-T4", "2023:12:31 23:59:59"),
+        )
+        .unwrap();
+
+        // the sidecar comes first, but the photo has the time and camera
+        let layout = Layout::new("{year}/{camera}/{filename}").unwrap();
+        let sub_paths = layout.sub_paths(&[xmp_path, jpeg_path], 0).unwrap();
+        assert_eq!(
+            vec![
+                "2023/FUJIFILM X-T4/DSCF0001.RAF.xmp",
+                "2023/FUJIFILM X-T4/DSCF0001.JPG"
+            ],
+            sub_paths
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sub_paths_without_exif_camera() {
+        let dir = test_dir("layout-no-camera");
+        let src_path = dir.join("notes.txt");
+        fs::write(&src_path, "not a photo").unwrap();
+
+        let layout = Layout::new("{camera}/{filename}").unwrap();
+        let sub_paths = layout
+            .sub_paths(std::slice::from_ref(&src_path), 0)
+            .unwrap();
+        assert_eq!(vec!["unknown/notes.txt"], sub_paths);
+
+        // a file that can't be read can't be placed
+        fs::remove_file(&src_path).unwrap();
+        assert!(layout.sub_paths(&[src_path], 0).is_err());
+        assert!(layout.sub_paths(&[], 0).unwrap().is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn path_safe_components() {
+        assert_eq!("Foo_Bar", path_safe("Foo/Bar", UNKNOWN_CAMERA));
+        assert_eq!("a_b", path_safe("a\\b", UNKNOWN_CAMERA));
+        assert_eq!("tab_here", path_safe("tab\there", UNKNOWN_CAMERA));
+        assert_eq!("hidden", path_safe("..hidden", UNKNOWN_CAMERA));
+        assert_eq!(UNKNOWN_CAMERA, path_safe(" .. ", UNKNOWN_CAMERA));
+        assert_eq!(UNKNOWN_CARD, path_safe(" .. ", UNKNOWN_CARD));
+    }
+}
@@ -0,0 +1,176 @@
+use crate::common::dit_error;
+use crate::media;
+use crate::volume::Volume;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// name of the ledger file at the top of each write path
+// (it starts with a dot, so dit skips it if the write path is ever used as a read path)
+const LEDGER_FILE_NAME: &str = ".dit-ledger";
+
+// first line of a new ledger file
+const LEDGER_HEADER: &str = "# dit ledger: hash, capture time, source, destination, card\n";
+
+/**
+ * Ingest history for each write path, kept in an append-only file at the top of the write path.
+ *
+ * Each file that is written to a write path (or found to be there already) gets a line with its
+ * hash, capture time (see media::capture_info()), source path, sub path in the write path, and the
+ * card or volume that the source path is on (see Volume), separated by tabs. Files whose hash is
+ * already in a write path's ledger aren't copied to it again, even if they would now go to a
+ * different sub path.
+ */
+pub struct Ledger {
+    read_paths: Vec<PathBuf>,
+    write_paths: Vec<PathBuf>,
+
+    // card or volume that each read path is on, as it's written in the ledger
+    read_volumes: Vec<String>,
+
+    // hashes in each write path's ledger when it was opened
+    hashes: Vec<HashSet<String>>,
+
+    // each write path's ledger file, opened for appending
+    files: Vec<Mutex<File>>,
+}
+
+impl Ledger {
+    /**
+     * Read the ledger for each write path, and open it for appending, creating it if needed.
+     */
+    pub fn open(
+        read_paths: &[PathBuf],
+        read_volumes: &[Volume],
+        write_paths: &[PathBuf],
+    ) -> Result<Ledger, Box<dyn Error>> {
+        let mut hashes = vec![];
+        let mut files = vec![];
+
+        for write_path in write_paths {
+            let mut ledger_path = PathBuf::from(write_path);
+            ledger_path.push(LEDGER_FILE_NAME);
+
+            let mut ledger_hashes = HashSet::new();
+            if ledger_path.exists() {
+                let contents = match fs::read_to_string(&ledger_path) {
+                    Ok(contents) => contents,
+                    Err(e) => return ledger_error(&ledger_path, &e.to_string()),
+                };
+
+                for line in contents.lines() {
+                    if line.is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+
+                    // the hash is the first field
+                    if let Some(hash) = line.split('\t').next() {
+                        ledger_hashes.insert(String::from(hash));
+                    }
+                }
+            }
+
+            let mut file = match OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&ledger_path)
+            {
+                Ok(file) => file,
+                Err(e) => return ledger_error(&ledger_path, &e.to_string()),
+            };
+
+            if 0 == file.metadata()?.len() {
+                file.write_all(LEDGER_HEADER.as_bytes())?;
+            }
+
+            hashes.push(ledger_hashes);
+            files.push(Mutex::new(file));
+        }
+
+        Ok(Ledger {
+            read_paths: read_paths.to_vec(),
+            write_paths: write_paths.to_vec(),
+            read_volumes: read_volumes
+                .iter()
+                .map(|v| escape(&v.to_string()))
+                .collect(),
+            hashes,
+            files,
+        })
+    }
+
+    /**
+     * Was a file with the given hash already written to the given write path, in an earlier run?
+     */
+    pub fn contains(&self, write_path_index: usize, hash: &str) -> bool {
+        self.hashes[write_path_index].contains(hash)
+    }
+
+    /**
+     * Add a line for a file that has been written to the given write path.
+     */
+    pub fn record(
+        &self,
+        write_path_index: usize,
+        hash: &str,
+        src_path: &Path,
+        dest_path: &Path,
+    ) -> Result<(), Box<dyn Error>> {
+        let capture_time = match media::capture_info(src_path) {
+            Ok(capture_info) => {
+                let t = capture_info.time;
+                format!(
+                    "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                    t.year, t.month, t.day, t.hour, t.minute, t.second
+                )
+            }
+            Err(_) => String::from("-"),
+        };
+
+        let dest_sub_path = dest_path
+            .strip_prefix(&self.write_paths[write_path_index])
+            .unwrap_or(dest_path);
+
+        let read_volume = match self.read_paths.iter().position(|p| src_path.starts_with(p)) {
+            Some(i) => &self.read_volumes[i],
+            None => "-",
+        };
+
+        let line = format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            hash,
+            capture_time,
+            escape(src_path.to_str().unwrap()),
+            escape(dest_sub_path.to_str().unwrap()),
+            read_volume
+        );
+
+        // a single write, so lines from different copy threads don't get mixed together
+        let mut file = self.files[write_path_index].lock().unwrap();
+        file.write_all(line.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+/**
+ * Escape the characters that would break up a ledger line.
+ */
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn ledger_error(ledger_path: &Path, reason: &str) -> Result<Ledger, Box<dyn Error>> {
+    let err_msg = format!(
+        "error reading ledger '{}': {}",
+        ledger_path.to_str().unwrap(),
+        reason
+    );
+    dit_error(&err_msg)
+}
@@ -2,11 +2,30 @@ mod api;
 mod common;
 mod discover;
 mod fsutil;
+mod integrity;
+mod layout;
+mod ledger;
+mod media;
 mod message;
+mod progress;
 mod threads;
+mod volume;
+mod watch;
+mod xmp;
 
 // export public API symbols
 pub use api::copy;
+pub use api::get_cli_copy_options;
 pub use api::get_cli_read_write_paths;
+pub use api::get_cli_watch_profile;
+pub use common::CancelHandle;
+pub use common::CopyOptions;
+pub use common::Dedupe;
+pub use common::MatchBy;
 pub use common::MergeResult;
+pub use common::Preserve;
 pub use common::ReadWritePaths;
+pub use progress::{DestProgress, ProgressReport, SourceProgress};
+pub use volume::Volume;
+pub use watch::watch;
+pub use watch::Profile;
@@ -1,54 +1,218 @@
-use dit::MergeResult;
+use dit::{CancelHandle, CopyOptions, MergeResult, ProgressReport};
+use nix::sys::signal::{SigSet, Signal};
 use std::env;
+use std::error::Error;
 use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
 
 const PROGRAM_NAME: &str = "dit";
 const EXIT_OK: i32 = 0;
 const EXIT_FAIL: i32 = 1;
 const EXIT_WARN: i32 = 2;
+const EXIT_CANCEL: i32 = 3;
+
+// how often to show progress when stdout is not a terminal
+const PLAIN_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+// number of lines drawn by the last live progress update, so the next one can draw over them
+static PROGRESS_LINES: AtomicUsize = AtomicUsize::new(0);
 
 fn log_info(s: &str) {
     println!("{}", s);
 }
 
+// used instead of log_info while the live progress display owns the terminal
+fn log_quiet(_: &str) {}
+
 fn log_warn(s: &str) {
     eprintln!("{}: {}", PROGRAM_NAME, s);
 }
 
 pub fn show_usage() {
     eprintln!("{}", PROGRAM_NAME);
-    eprintln!("Usage: {} read <src...> write <dest...>", PROGRAM_NAME);
+    eprintln!(
+        "Usage: {} [options] read <src...> write <dest...>",
+        PROGRAM_NAME
+    );
+    eprintln!("       {} watch --profile <file>", PROGRAM_NAME);
     process::exit(EXIT_FAIL);
 }
 
-fn main() {
-    // get all command-line arguments
-    let args: Vec<String> = env::args().collect();
+fn stdout_is_tty() -> bool {
+    unsafe { 1 == libc::isatty(libc::STDOUT_FILENO) }
+}
 
-    // extract read/write paths from the command-line arguments
-    let read_write_paths = match dit::get_cli_read_write_paths(&args[1..]) {
-        Ok(read_write_paths) => read_write_paths,
-        Err(_) => {
-            show_usage();
-            // can't happen, show_usage() quits the program
-            panic!();
-        }
+fn format_bytes(bytes: f64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < units.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600,
+        (secs / 60) % 60,
+        secs % 60
+    )
+}
+
+fn format_progress_lines(report: &ProgressReport) -> Vec<String> {
+    let mut lines = vec![];
+
+    for dest in &report.destinations {
+        let percent = if dest.bytes_total > 0 {
+            100.0 * (dest.bytes_done as f64) / (dest.bytes_total as f64)
+        } else {
+            100.0
+        };
+        lines.push(format!(
+            "write {}: {} / {} ({:.0}%), {} / {} files, {}/s",
+            dest.write_path.to_str().unwrap(),
+            format_bytes(dest.bytes_done as f64),
+            format_bytes(dest.bytes_total as f64),
+            percent.min(100.0),
+            dest.files_done,
+            dest.files_total,
+            format_bytes(dest.bytes_per_sec)
+        ));
+    }
+
+    for src in &report.sources {
+        lines.push(format!(
+            "read {}: {} read, {}/s",
+            src.read_path.to_str().unwrap(),
+            format_bytes(src.bytes_read as f64),
+            format_bytes(src.bytes_per_sec)
+        ));
+    }
+
+    let eta = match report.eta {
+        Some(eta) => format_duration(eta),
+        None => String::from("--:--:--"),
     };
+    if report.finished {
+        lines.push(format!("done in {}", format_duration(report.elapsed)));
+    } else {
+        lines.push(format!(
+            "elapsed {}, ETA {}",
+            format_duration(report.elapsed),
+            eta
+        ));
+    }
 
-    // copy the files, and exit the program with a suitable exit code
-    match dit::copy(log_info, log_warn, &read_write_paths) {
+    lines
+}
+
+/**
+ * Show a progress report, redrawing it in place on a terminal, or as plain lines otherwise.
+ */
+fn log_progress(report: &ProgressReport) {
+    let lines = format_progress_lines(report);
+
+    if !stdout_is_tty() {
+        for line in &lines {
+            println!("progress: {}", line);
+        }
+        return;
+    }
+
+    // move the cursor back up over the previous report, and clear each line as we redraw it
+    let prev_lines = PROGRESS_LINES.swap(lines.len(), Ordering::Relaxed);
+    let mut out = String::new();
+    if prev_lines > 0 {
+        out.push_str(&format!("\x1b[{}A", prev_lines));
+    }
+    for line in &lines {
+        out.push_str("\x1b[2K");
+        out.push_str(line);
+        out.push('\n');
+    }
+    print!("{}", out);
+}
+
+/**
+ * Block SIGINT and SIGTERM, and handle them on a dedicated thread instead.
+ *
+ * The first signal cancels the copy, which lets in-flight copies roll back and remove their temp
+ * files. A second signal exits immediately.
+ *
+ * Must be called before any other threads are spawned, so they inherit the signal mask.
+ */
+fn install_signal_handlers(cancel_handle: &CancelHandle) {
+    let mut sigset = SigSet::empty();
+    sigset.add(Signal::SIGINT);
+    sigset.add(Signal::SIGTERM);
+
+    if let Err(e) = sigset.thread_block() {
+        log_warn(&format!("could not block signals: {}", e));
+        return;
+    }
+
+    let cancel_handle = cancel_handle.clone();
+    thread::spawn(move || loop {
+        match sigset.wait() {
+            Ok(signal) => {
+                if cancel_handle.is_cancelled() {
+                    log_warn(&format!("caught {} again, exiting immediately", signal));
+                    process::exit(EXIT_CANCEL);
+                }
+                log_warn(&format!("caught {}, cancelling", signal));
+                cancel_handle.cancel();
+            }
+            Err(e) => {
+                log_warn(&format!("error waiting for signals: {}", e));
+                return;
+            }
+        }
+    });
+}
+
+/**
+ * Use the live progress display, or plain progress lines when stdout is not a terminal, if
+ * --progress was given. Returns the function to use for log_info.
+ */
+fn setup_progress(copy_options: &mut CopyOptions) -> fn(&str) {
+    // the live progress display replaces the list of files as they are copied
+    if copy_options.progress {
+        if stdout_is_tty() {
+            return log_quiet;
+        }
+        copy_options.progress_interval = PLAIN_PROGRESS_INTERVAL;
+    }
+
+    log_info
+}
+
+/**
+ * Exit the program with a suitable exit code.
+ */
+fn exit_with(result: Result<MergeResult, Box<dyn Error>>) -> ! {
+    match result {
         Ok(merge_result) => match merge_result {
             MergeResult::Ok => {
                 process::exit(EXIT_OK);
             }
             MergeResult::Conflict => {
-                log_warn("merge conflicts encountered");
+                log_warn("merge conflicts or other problems encountered (see the warnings above)");
                 process::exit(EXIT_WARN);
             }
             MergeResult::Error => {
                 log_warn("fatal error");
                 process::exit(EXIT_FAIL);
             }
+            MergeResult::Cancelled => {
+                log_warn("cancelled");
+                process::exit(EXIT_CANCEL);
+            }
         },
         Err(e) => {
             log_warn(&e.to_string());
@@ -56,3 +220,80 @@ fn main() {
         }
     }
 }
+
+/**
+ * Watch for new cards or volumes, and copy each one as described by a profile, until SIGINT or
+ * SIGTERM.
+ */
+fn watch_main(args: &[String]) {
+    // read the profile named on the command line
+    let mut profile = match dit::get_cli_watch_profile(args) {
+        Ok(profile) => profile,
+        Err(e) => {
+            log_warn(&e.to_string());
+            show_usage();
+            // can't happen, show_usage() quits the program
+            panic!();
+        }
+    };
+
+    let log_info_fn = setup_progress(&mut profile.copy_options);
+
+    // stop watching (cancelling the copy in progress, if any) on SIGINT/SIGTERM
+    let cancel_handle = CancelHandle::new();
+    install_signal_handlers(&cancel_handle);
+
+    exit_with(dit::watch(
+        log_info_fn,
+        log_warn,
+        log_progress,
+        &profile,
+        &cancel_handle,
+    ));
+}
+
+fn main() {
+    // get all command-line arguments
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() > 1 && "watch" == args[1] {
+        watch_main(&args[2..]);
+    }
+
+    // extract read/write paths from the command-line arguments
+    let read_write_paths = match dit::get_cli_read_write_paths(&args[1..]) {
+        Ok(read_write_paths) => read_write_paths,
+        Err(_) => {
+            show_usage();
+            // can't happen, show_usage() quits the program
+            panic!();
+        }
+    };
+
+    // extract options from the command-line arguments
+    let mut copy_options = match dit::get_cli_copy_options(&args[1..]) {
+        Ok(copy_options) => copy_options,
+        Err(e) => {
+            log_warn(&e.to_string());
+            show_usage();
+            // can't happen, show_usage() quits the program
+            panic!();
+        }
+    };
+
+    let log_info_fn = setup_progress(&mut copy_options);
+
+    // cancel the copy cleanly on SIGINT/SIGTERM
+    let cancel_handle = CancelHandle::new();
+    install_signal_handlers(&cancel_handle);
+
+    // copy the files, and exit the program with a suitable exit code
+    exit_with(dit::copy(
+        log_info_fn,
+        log_warn,
+        log_progress,
+        &read_write_paths,
+        &copy_options,
+        &cancel_handle,
+    ));
+}
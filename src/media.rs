@@ -0,0 +1,1009 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+// seconds between the QuickTime epoch (1904-01-01) and the UNIX epoch (1970-01-01)
+const QUICKTIME_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+// limits on how much of a damaged or unusual file we're willing to walk through
+const MAX_IFD_ENTRIES: u16 = 1000;
+const MAX_ASCII_LEN: u32 = 256;
+const MAX_BOXES: usize = 1000;
+
+// bytes read from the start of a file to recognize its type, enough for the second packet of an
+// MPEG transport stream
+const HEADER_LEN: u64 = 200;
+
+// MPEG transport stream packets, which start with a sync byte (M2TS packets have a 4-byte
+// timestamp in front)
+const TS_SYNC_BYTE: u8 = 0x47;
+const TS_PACKET_LEN: usize = 188;
+const M2TS_PACKET_LEN: usize = 192;
+
+// cameras that give each chapter of a spanned clip the next file number split a recording just
+// before a file reaches the 4 GiB limit of FAT32 cards, so every chapter but the last ends up
+// within SPLIT_MARGIN of it
+const FAT32_MAX_FILE_SIZE: u64 = 4 * 1024 * 1024 * 1024 - 1;
+const SPLIT_MARGIN: u64 = 128 * 1024 * 1024;
+
+// most seconds between the end of one chapter and the start of the next, to allow for the
+// creation times only being to the second
+const MAX_CHAPTER_GAP: f64 = 1.0;
+
+// TIFF tags
+const TAG_MAKE: u16 = 0x010f;
+const TAG_MODEL: u16 = 0x0110;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+
+/**
+ * A local date and time, to the second.
+ */
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CaptureTime {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+/**
+ * When, and with what, a photo or video was taken.
+ */
+pub struct CaptureInfo {
+    pub time: CaptureTime,
+
+    // "<make> <model>" from the EXIF data, if the file has any
+    pub camera: Option<String>,
+}
+
+/**
+ * Read the capture time and camera from a photo or video file's own metadata.
+ *
+ * The capture time comes from the EXIF DateTimeOriginal of JPEGs, TIFF-based RAW files (CR2,
+ * NEF, ARW, DNG, ORF, RW2, ...) and Fujifilm RAF files, or from the creation time in the mvhd
+ * box of MP4/MOV files. Anything else, or anything without a usable time in it, falls back to
+ * the file's modification time.
+ */
+pub fn capture_info(path: &Path) -> Result<CaptureInfo, Box<dyn Error>> {
+    let file = File::open(path)?;
+
+    let (time, camera) = match read_at(&file, 0, 16) {
+        Some(header) => read_exif(&file, &header)
+            .or_else(|| read_mvhd_time(&file).map(|time| (Some(time), None)))
+            .unwrap_or((None, None)),
+        None => (None, None),
+    };
+
+    let time = match time {
+        Some(time) => time,
+        None => {
+            let metadata = file.metadata()?;
+            match local_time(metadata.mtime()) {
+                Some(time) => time,
+                None => {
+                    let err_msg = format!("invalid modification time: '{}'", path.display());
+                    return crate::common::dit_error(&err_msg);
+                }
+            }
+        }
+    };
+
+    Ok(CaptureInfo { time, camera })
+}
+
+/**
+ * Kind of photo, video or audio file, as far as dit can tell them apart by their extension or
+ * their first few bytes.
+ */
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum MediaType {
+    Jpeg,
+    Heif,
+    Png,
+
+    // TIFF, and the raw formats built on it (CR2, NEF, ARW, DNG, ORF, RW2, ...)
+    Tiff,
+
+    // Fujifilm raw
+    Raf,
+
+    // Canon raw, in an ISO-BMFF container like HEIF
+    Cr3,
+
+    // MP4, MOV, and other ISO-BMFF/QuickTime movies (including Blackmagic RAW)
+    Movie,
+
+    // Material Exchange Format, from broadcast and cinema cameras
+    Mxf,
+
+    // MPEG transport stream, like AVCHD (MTS) and Blu-ray (M2TS) video
+    MpegTs,
+
+    Avi,
+
+    // RED raw
+    R3d,
+
+    // WAV, and Broadcast Wave (BWF) from audio recorders
+    Wav,
+}
+
+impl Display for MediaType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            MediaType::Jpeg => "JPEG",
+            MediaType::Heif => "HEIF",
+            MediaType::Png => "PNG",
+            MediaType::Tiff => "TIFF/raw",
+            MediaType::Raf => "RAF",
+            MediaType::Cr3 => "CR3",
+            MediaType::Movie => "MP4/MOV",
+            MediaType::Mxf => "MXF",
+            MediaType::MpegTs => "MPEG-TS",
+            MediaType::Avi => "AVI",
+            MediaType::R3d => "R3D",
+            MediaType::Wav => "WAV",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/**
+ * What a file looks like from its extension, and from its contents.
+ */
+pub struct Classification {
+    pub by_extension: Option<MediaType>,
+    pub by_contents: Option<MediaType>,
+}
+
+impl Classification {
+    /**
+     * Is this a photo, video or audio file, by either its extension or its contents?
+     */
+    pub fn is_media(&self) -> bool {
+        self.by_extension.is_some() || self.by_contents.is_some()
+    }
+
+    /**
+     * Does the extension disagree with the contents? (e.g. a .JPG that is all zeros, or a .CHK
+     * file from a recovery tool that is really a movie)
+     */
+    pub fn is_mismatch(&self) -> bool {
+        self.by_extension != self.by_contents
+    }
+}
+
+/**
+ * Classify a file by its extension and by its first few bytes.
+ */
+pub fn classify(path: &Path) -> Classification {
+    let by_extension = classify_extension(path);
+
+    let by_contents = match File::open(path) {
+        Ok(file) => {
+            let len = file.metadata().map(|m| m.len()).unwrap_or(0);
+            match read_at(&file, 0, len.min(HEADER_LEN) as usize) {
+                Some(header) => media_type_of_header(&header),
+                None => None,
+            }
+        }
+        Err(_) => None,
+    };
+
+    Classification {
+        by_extension,
+        by_contents,
+    }
+}
+
+/**
+ * Classify a file by its extension alone.
+ */
+pub fn classify_extension(path: &Path) -> Option<MediaType> {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) => media_type_of_extension(&extension.to_ascii_lowercase()),
+        None => None,
+    }
+}
+
+/**
+ * Get the media type that a (lowercase) file extension stands for.
+ */
+fn media_type_of_extension(extension: &str) -> Option<MediaType> {
+    match extension {
+        // camera thumbnails (THM) are JPEGs too
+        "jpg" | "jpeg" | "thm" => Some(MediaType::Jpeg),
+        "heic" | "heif" | "hif" => Some(MediaType::Heif),
+        "png" => Some(MediaType::Png),
+        "tif" | "tiff" | "dng" | "cr2" | "nef" | "nrw" | "arw" | "srf" | "sr2" | "orf" | "rw2"
+        | "rwl" | "pef" | "srw" | "3fr" | "iiq" | "erf" | "mos" | "kdc" | "dcr" => {
+            Some(MediaType::Tiff)
+        }
+        "raf" => Some(MediaType::Raf),
+        "cr3" => Some(MediaType::Cr3),
+        // including low resolution proxies from GoPro (LRV) and DJI (LRF) cameras
+        "mp4" | "m4v" | "mov" | "3gp" | "braw" | "lrv" | "lrf" => Some(MediaType::Movie),
+        "mxf" => Some(MediaType::Mxf),
+        "mts" | "m2ts" => Some(MediaType::MpegTs),
+        "avi" => Some(MediaType::Avi),
+        "r3d" => Some(MediaType::R3d),
+        "wav" | "bwf" => Some(MediaType::Wav),
+        _ => None,
+    }
+}
+
+/**
+ * Recognize a media type from the first (up to) HEADER_LEN bytes of a file.
+ */
+fn media_type_of_header(header: &[u8]) -> Option<MediaType> {
+    if header.starts_with(&[0xff, 0xd8, 0xff]) {
+        return Some(MediaType::Jpeg);
+    }
+    if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return Some(MediaType::Png);
+    }
+    if header.starts_with(b"FUJIFILMCCD-RAW") {
+        return Some(MediaType::Raf);
+    }
+
+    // standard TIFF magic numbers, plus the ones used by Olympus ORF and Panasonic RW2 files
+    for magic in [b"II*\0", b"MM\0*", b"IIRO", b"IIRS", b"MMOR", b"IIU\0"] {
+        if header.starts_with(magic) {
+            return Some(MediaType::Tiff);
+        }
+    }
+
+    // MXF files start with a partition pack key
+    if header.starts_with(&[0x06, 0x0e, 0x2b, 0x34]) {
+        return Some(MediaType::Mxf);
+    }
+
+    if header.len() >= 12 && b"WAVE" == &header[8..12] {
+        match &header[..4] {
+            b"RIFF" | b"RF64" | b"BW64" => return Some(MediaType::Wav),
+            _ => {}
+        }
+    }
+    if header.len() >= 12 && header.starts_with(b"RIFF") && b"AVI " == &header[8..12] {
+        return Some(MediaType::Avi);
+    }
+
+    // a single sync byte could be anything, so look for the one at the start of the second
+    // packet too
+    for (offset, packet_len) in [(0, TS_PACKET_LEN), (4, M2TS_PACKET_LEN)] {
+        if header.len() > offset + packet_len
+            && TS_SYNC_BYTE == header[offset]
+            && TS_SYNC_BYTE == header[offset + packet_len]
+        {
+            return Some(MediaType::MpegTs);
+        }
+    }
+
+    if header.len() < 12 {
+        return None;
+    }
+    match &header[4..8] {
+        // ISO-BMFF files start with an ftyp box, and the major brand tells HEIF and CR3 apart
+        // from movies
+        b"ftyp" => match &header[8..12] {
+            b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx" | b"mif1" | b"msf1" => {
+                Some(MediaType::Heif)
+            }
+            b"crx " => Some(MediaType::Cr3),
+            _ => Some(MediaType::Movie),
+        },
+
+        // older QuickTime movies can start with other top-level atoms
+        b"moov" | b"mdat" | b"wide" | b"free" | b"skip" | b"pnot" => Some(MediaType::Movie),
+
+        b"RED1" | b"RED2" => Some(MediaType::R3d),
+        _ => None,
+    }
+}
+
+/**
+ * Name shared by the chapters of a long recording that the camera split into several files
+ * (a spanned clip). The file name of each chapter is prefix + chapter number + suffix, except
+ * for the first chapter of some naming schemes, which has a name of its own.
+ */
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ClipName {
+    prefix: String,
+    suffix: String,
+
+    // number of digits in the chapter number
+    width: usize,
+
+    // number of the first chapter
+    pub first_chapter: u32,
+
+    // name of the first chapter, if it doesn't follow the pattern
+    first_name: Option<String>,
+}
+
+impl ClipName {
+    /**
+     * Get the file name of the given chapter.
+     */
+    pub fn file_name(&self, chapter: u32) -> String {
+        match &self.first_name {
+            Some(first_name) if chapter == self.first_chapter => first_name.clone(),
+            _ => format!(
+                "{}{:0width$}{}",
+                self.prefix,
+                chapter,
+                self.suffix,
+                width = self.width
+            ),
+        }
+    }
+}
+
+/**
+ * Recognize a file name as one chapter of a spanned clip, and return the clip's name and the
+ * chapter number.
+ *
+ * Knows the naming schemes of GoPro cameras (GX010123.MP4, GX020123.MP4, ... or GH01..., and
+ * GOPR0123.MP4, GP010123.MP4, ... on older models), and RED cameras (A001_C001_0101AB_001.R3D,
+ * A001_C001_0101AB_002.R3D, ...). Sony and Canon cameras give each chapter the next file number,
+ * like a separate recording, so their chapters can't be told by their names (see
+ * numbered_clip()).
+ */
+pub fn clip_chapter(file_name: &str) -> Option<(ClipName, u32)> {
+    // cameras only use ASCII names, which also makes it safe to slice them anywhere
+    if !file_name.is_ascii() {
+        return None;
+    }
+
+    let b = file_name.as_bytes();
+    let digits = |range: std::ops::Range<usize>| -> Option<u32> {
+        let s = &file_name[range];
+        if !s.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        s.parse().ok()
+    };
+
+    // GoPro: 8 character names, and the chapter comes before the file number
+    if 12 == b.len() && file_name[8..].eq_ignore_ascii_case(".mp4") {
+        let clip_name = |prefix: &str, first_name: Option<String>| ClipName {
+            prefix: String::from(prefix),
+            suffix: String::from(&file_name[4..]),
+            width: 2,
+            first_chapter: if first_name.is_some() { 0 } else { 1 },
+            first_name,
+        };
+
+        match &file_name[..2] {
+            "GX" | "GH" => {
+                let chapter = digits(2..4)?;
+                digits(4..8)?;
+                return Some((clip_name(&file_name[..2], None), chapter));
+            }
+            "GP" => {
+                let chapter = digits(2..4)?;
+                digits(4..8)?;
+                let first_name = format!("GOPR{}", &file_name[4..]);
+                return Some((clip_name("GP", Some(first_name)), chapter));
+            }
+            _ if file_name.starts_with("GOPR") => {
+                digits(4..8)?;
+                let first_name = String::from(file_name);
+                return Some((clip_name("GP", Some(first_name)), 0));
+            }
+            _ => {}
+        }
+    }
+
+    // RED: the clip name, then "_" and a 3 digit chapter
+    let n = b.len();
+    if n > 8 && file_name[n - 4..].eq_ignore_ascii_case(".r3d") && b'_' == b[n - 8] {
+        let chapter = digits(n - 7..n - 4)?;
+        let name = ClipName {
+            prefix: String::from(&file_name[..n - 7]),
+            suffix: String::from(&file_name[n - 4..]),
+            width: 3,
+            first_chapter: 1,
+            first_name: None,
+        };
+        return Some((name, chapter));
+    }
+
+    None
+}
+
+/**
+ * Recognize a file name that Sony (C0001.MP4) or Canon (MVI_1234.MP4, MVI_1234.MOV) cameras give
+ * a recording, and return the name of the camera's series of files, and the file number.
+ *
+ * These cameras give each chapter of a spanned clip the next file number, so whether a file is
+ * the next chapter of the one before it has to be found out from the files (see
+ * continues_clip()). The first chapter of the returned name is left at 0, for the caller to set
+ * to the file number that a spanned clip starts at.
+ */
+pub fn numbered_clip(file_name: &str) -> Option<(ClipName, u32)> {
+    // cameras only use ASCII names, which also makes it safe to slice them anywhere
+    if !file_name.is_ascii() {
+        return None;
+    }
+
+    // prefix, then a 4 digit file number, then one of the extensions
+    let schemes: [(&str, &[&str]); 2] = [("C", &[".mp4"]), ("MVI_", &[".mp4", ".mov"])];
+    for (prefix, extensions) in schemes {
+        let n = prefix.len();
+        if n + 8 != file_name.len() || !file_name.starts_with(prefix) {
+            continue;
+        }
+
+        let suffix = &file_name[n + 4..];
+        if !extensions.iter().any(|e| suffix.eq_ignore_ascii_case(e)) {
+            continue;
+        }
+
+        let digits = &file_name[n..n + 4];
+        if !digits.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+
+        let name = ClipName {
+            prefix: String::from(prefix),
+            suffix: String::from(suffix),
+            width: 4,
+            first_chapter: 0,
+            first_name: None,
+        };
+        return Some((name, digits.parse().ok()?));
+    }
+
+    None
+}
+
+/**
+ * Was a recording split off at the file size limit of FAT32 cards, so that it goes on in the file
+ * with the next file number (see numbered_clip())?
+ */
+pub fn was_split(path: &Path) -> bool {
+    match path.metadata() {
+        Ok(metadata) => {
+            metadata.len() <= FAT32_MAX_FILE_SIZE
+                && metadata.len() > FAT32_MAX_FILE_SIZE - SPLIT_MARGIN
+        }
+        Err(_) => false,
+    }
+}
+
+/**
+ * Is the file with the next file number (see numbered_clip()) the next chapter of the recording in
+ * the given file?
+ *
+ * It is if the given file was split off at the file size limit (see was_split()), or if the next
+ * file starts recording when the given one stops, going by the creation times and durations in
+ * their mvhd boxes. Stopping a recording and starting another one takes the camera longer than
+ * that.
+ */
+pub fn continues_clip(path: &Path, next_path: &Path) -> bool {
+    if was_split(path) {
+        return true;
+    }
+
+    let read_times = |path: &Path| File::open(path).ok().and_then(|file| read_mvhd(&file));
+    match (read_times(path), read_times(next_path)) {
+        (Some((start, duration)), Some((next_start, _))) if 0 != start && 0 != next_start => {
+            let gap = (next_start - start) as f64 - duration;
+            gap.abs() <= MAX_CHAPTER_GAP
+        }
+        _ => false,
+    }
+}
+
+/**
+ * Find the EXIF data in a JPEG, TIFF or RAF file, and return its (capture time, camera).
+ */
+fn read_exif(file: &File, header: &[u8]) -> Option<(Option<CaptureTime>, Option<String>)> {
+    if header.starts_with(&[0xff, 0xd8]) {
+        let tiff_offset = find_jpeg_exif(file, 0)?;
+        return Some(read_tiff(file, tiff_offset));
+    }
+
+    // RAF files start with a fixed header, which points to a full JPEG preview with the EXIF
+    if header.starts_with(b"FUJIFILMCCD-RAW") {
+        let jpeg_offset = u32::from_be_bytes(read_at(file, 84, 4)?.try_into().ok()?) as u64;
+        let tiff_offset = find_jpeg_exif(file, jpeg_offset)?;
+        return Some(read_tiff(file, tiff_offset));
+    }
+
+    // TIFF-based RAWs don't all use the standard TIFF magic number, but do all start with the
+    // byte order
+    if header.starts_with(b"II") || header.starts_with(b"MM") {
+        return Some(read_tiff(file, 0));
+    }
+
+    None
+}
+
+/**
+ * Walk the segments of the JPEG that starts at the given offset, and return the offset of the
+ * TIFF header in its EXIF segment.
+ */
+fn find_jpeg_exif(file: &File, jpeg_offset: u64) -> Option<u64> {
+    // skip the SOI marker
+    let mut pos = jpeg_offset + 2;
+
+    loop {
+        let marker = read_at(file, pos, 4)?;
+        if 0xff != marker[0] {
+            return None;
+        }
+
+        // start of scan (the image data) or end of image, there are no more metadata segments
+        if 0xda == marker[1] || 0xd9 == marker[1] {
+            return None;
+        }
+
+        let len = u16::from_be_bytes([marker[2], marker[3]]) as u64;
+        if 0xe1 == marker[1] && read_at(file, pos + 4, 6)? == b"Exif\0\0" {
+            return Some(pos + 10);
+        }
+
+        pos += 2 + len;
+    }
+}
+
+/**
+ * Read the capture time and camera from the TIFF structure (IFD0, and its EXIF IFD) at the given
+ * offset.
+ */
+fn read_tiff(file: &File, base: u64) -> (Option<CaptureTime>, Option<String>) {
+    let tiff = match read_at(file, base, 8) {
+        Some(header) => Tiff {
+            file,
+            base,
+            big_endian: header.starts_with(b"MM"),
+        },
+        None => return (None, None),
+    };
+
+    let ifd0 = match tiff.u32_at(4) {
+        Some(offset) => tiff.read_ifd(offset),
+        None => vec![],
+    };
+
+    let make = tiff.ascii(&ifd0, TAG_MAKE);
+    let model = tiff.ascii(&ifd0, TAG_MODEL);
+    let camera = camera_name(make.as_deref(), model.as_deref());
+
+    let exif_ifd = match tiff.entry(&ifd0, TAG_EXIF_IFD) {
+        Some(entry) => tiff.read_ifd(tiff.value_u32(entry)),
+        None => vec![],
+    };
+
+    let time = tiff
+        .ascii(&exif_ifd, TAG_DATE_TIME_ORIGINAL)
+        .or_else(|| tiff.ascii(&ifd0, TAG_DATE_TIME))
+        .and_then(|s| parse_exif_time(&s));
+
+    (time, camera)
+}
+
+// (tag, type, count, value or offset to the value)
+type IfdEntry = (u16, u16, u32, [u8; 4]);
+
+/**
+ * A TIFF structure inside a file, starting at base.
+ */
+struct Tiff<'a> {
+    file: &'a File,
+    base: u64,
+    big_endian: bool,
+}
+
+impl Tiff<'_> {
+    fn u16_from(&self, bytes: [u8; 2]) -> u16 {
+        if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        }
+    }
+
+    fn u32_from(&self, bytes: [u8; 4]) -> u32 {
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    fn u32_at(&self, offset: u32) -> Option<u32> {
+        let bytes = read_at(self.file, self.base + offset as u64, 4)?;
+        Some(self.u32_from(bytes.try_into().ok()?))
+    }
+
+    fn read_ifd(&self, offset: u32) -> Vec<IfdEntry> {
+        let mut entries = vec![];
+
+        let count = match read_at(self.file, self.base + offset as u64, 2) {
+            Some(bytes) => self.u16_from([bytes[0], bytes[1]]),
+            None => return entries,
+        };
+        if count > MAX_IFD_ENTRIES {
+            return entries;
+        }
+
+        let table = match read_at(
+            self.file,
+            self.base + offset as u64 + 2,
+            count as usize * 12,
+        ) {
+            Some(table) => table,
+            None => return entries,
+        };
+        for entry in table.chunks_exact(12) {
+            entries.push((
+                self.u16_from([entry[0], entry[1]]),
+                self.u16_from([entry[2], entry[3]]),
+                self.u32_from([entry[4], entry[5], entry[6], entry[7]]),
+                [entry[8], entry[9], entry[10], entry[11]],
+            ));
+        }
+
+        entries
+    }
+
+    fn entry<'b>(&self, ifd: &'b [IfdEntry], tag: u16) -> Option<&'b IfdEntry> {
+        ifd.iter().find(|(entry_tag, _, _, _)| *entry_tag == tag)
+    }
+
+    fn value_u32(&self, entry: &IfdEntry) -> u32 {
+        self.u32_from(entry.3)
+    }
+
+    /**
+     * Read an ASCII value, which is stored in the entry itself if it fits in 4 bytes.
+     */
+    fn ascii(&self, ifd: &[IfdEntry], tag: u16) -> Option<String> {
+        let entry = self.entry(ifd, tag)?;
+        let (_, _, count, value) = entry;
+        if *count > MAX_ASCII_LEN {
+            return None;
+        }
+
+        let bytes = if *count <= 4 {
+            value[..*count as usize].to_vec()
+        } else {
+            let offset = self.value_u32(entry) as u64;
+            read_at(self.file, self.base + offset, *count as usize)?
+        };
+
+        let s = String::from_utf8_lossy(&bytes);
+        let s = s.trim_end_matches('\0').trim();
+        if s.is_empty() {
+            return None;
+        }
+
+        Some(String::from(s))
+    }
+}
+
+/**
+ * Parse an EXIF date and time ("YYYY:MM:DD HH:MM:SS").
+ */
+fn parse_exif_time(s: &str) -> Option<CaptureTime> {
+    let (date, time) = s.split_once(' ')?;
+
+    let date: Vec<&str> = date.split(':').collect();
+    let time: Vec<&str> = time.split(':').collect();
+    if 3 != date.len() || 3 != time.len() {
+        return None;
+    }
+
+    let capture_time = CaptureTime {
+        year: date[0].parse().ok()?,
+        month: date[1].parse().ok()?,
+        day: date[2].parse().ok()?,
+        hour: time[0].parse().ok()?,
+        minute: time[1].parse().ok()?,
+        second: time[2].parse().ok()?,
+    };
+
+    // cameras with no clock set write all zeros
+    if capture_time.year < 1900
+        || !(1..=12).contains(&capture_time.month)
+        || !(1..=31).contains(&capture_time.day)
+        || capture_time.hour > 23
+        || capture_time.minute > 59
+        || capture_time.second > 60
+    {
+        return None;
+    }
+
+    Some(capture_time)
+}
+
+/**
+ * Combine the EXIF make and model into a camera name, without repeating the make if the model
+ * already starts with it (e.g. "Canon" + "Canon EOS R5").
+ */
+fn camera_name(make: Option<&str>, model: Option<&str>) -> Option<String> {
+    match (make, model) {
+        (Some(make), Some(model)) => {
+            if model.to_lowercase().starts_with(&make.to_lowercase()) {
+                Some(String::from(model))
+            } else {
+                Some(format!("{} {}", make, model))
+            }
+        }
+        (Some(name), None) | (None, Some(name)) => Some(String::from(name)),
+        (None, None) => None,
+    }
+}
+
+/**
+ * Find the creation time in the mvhd box of an MP4/MOV (ISO base media) file.
+ */
+fn read_mvhd_time(file: &File) -> Option<CaptureTime> {
+    let (creation_time, _) = read_mvhd(file)?;
+
+    // no creation time was set
+    if 0 == creation_time {
+        return None;
+    }
+
+    // the creation time is in UTC, so convert it to the local time zone, to match the EXIF times
+    // of photos taken alongside it
+    local_time(creation_time - QUICKTIME_EPOCH_OFFSET)
+}
+
+/**
+ * Find the creation time (in seconds since 1904, in UTC, or 0 if it wasn't set) and the duration
+ * (in seconds) in the mvhd box of an MP4/MOV (ISO base media) file.
+ */
+fn read_mvhd(file: &File) -> Option<(i64, f64)> {
+    let len = file.metadata().ok()?.len();
+
+    let (moov_start, moov_end) = find_box(file, 0, len, b"moov")?;
+    let (mvhd_start, _) = find_box(file, moov_start, moov_end, b"mvhd")?;
+
+    // version (1 byte) and flags (3 bytes), then the creation time, modification time, time scale
+    // and duration, with 64-bit times and duration in version 1
+    let version = read_at(file, mvhd_start, 1)?[0];
+    let (creation_time, time_scale, duration) = if 1 == version {
+        let b = read_at(file, mvhd_start + 4, 28)?;
+        (
+            u64::from_be_bytes(b[0..8].try_into().ok()?) as i64,
+            u32::from_be_bytes(b[16..20].try_into().ok()?),
+            u64::from_be_bytes(b[20..28].try_into().ok()?),
+        )
+    } else {
+        let b = read_at(file, mvhd_start + 4, 16)?;
+        (
+            u32::from_be_bytes(b[0..4].try_into().ok()?) as i64,
+            u32::from_be_bytes(b[8..12].try_into().ok()?),
+            u32::from_be_bytes(b[12..16].try_into().ok()?) as u64,
+        )
+    };
+    if 0 == time_scale {
+        return None;
+    }
+
+    Some((creation_time, duration as f64 / time_scale as f64))
+}
+
+/**
+ * Find the first box of the given type between start and end, and return the (start, end) of its
+ * contents.
+ */
+fn find_box(file: &File, start: u64, end: u64, box_type: &[u8; 4]) -> Option<(u64, u64)> {
+    let mut pos = start;
+
+    for _ in 0..MAX_BOXES {
+        if pos + 8 > end {
+            return None;
+        }
+
+        let header = read_at(file, pos, 8)?;
+        let size = u32::from_be_bytes(header[..4].try_into().ok()?) as u64;
+        let (header_len, size) = match size {
+            // 64-bit size follows the type
+            1 => (
+                16,
+                u64::from_be_bytes(read_at(file, pos + 8, 8)?.try_into().ok()?),
+            ),
+            // box extends to the end of the file
+            0 => (8, end - pos),
+            _ => (8, size),
+        };
+        if size < header_len {
+            return None;
+        }
+
+        if &header[4..8] == box_type {
+            return Some((pos + header_len, (pos + size).min(end)));
+        }
+
+        pos += size;
+    }
+
+    None
+}
+
+/**
+ * Convert seconds since the UNIX epoch to the local date and time.
+ */
+fn local_time(secs: i64) -> Option<CaptureTime> {
+    let time = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+
+    let result = unsafe { libc::localtime_r(&time, &mut tm) };
+    if result.is_null() {
+        return None;
+    }
+
+    Some(CaptureTime {
+        year: tm.tm_year + 1900,
+        month: (tm.tm_mon + 1) as u32,
+        day: tm.tm_mday as u32,
+        hour: tm.tm_hour as u32,
+        minute: tm.tm_min as u32,
+        second: tm.tm_sec as u32,
+    })
+}
+
+/**
+ * Read exactly len bytes at the given offset, or None if the file is too short.
+ */
+fn read_at(file: &File, offset: u64, len: usize) -> Option<Vec<u8>> {
+    let mut buf = vec![0; len];
+    file.read_exact_at(&mut buf, offset).ok()?;
+    Some(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the clip name and chapter, which must be recognized
+    fn chapter_of(file_name: &str) -> (ClipName, u32) {
+        match clip_chapter(file_name) {
+            Some(clip_chapter) => clip_chapter,
+            None => panic!("'{}' should be a chapter of a spanned clip", file_name),
+        }
+    }
+
+    #[test]
+    fn clip_chapter_gopro() {
+        let (name, chapter) = chapter_of("GX020123.MP4");
+        assert_eq!(2, chapter);
+        assert_eq!(1, name.first_chapter);
+        assert_eq!("GX010123.MP4", name.file_name(1));
+        assert_eq!("GX110123.MP4", name.file_name(11));
+        assert!(name == chapter_of("GX010123.MP4").0);
+        assert!(name != chapter_of("GX010124.MP4").0);
+
+        // the extension is kept as it is
+        let (name, chapter) = chapter_of("GH010001.mp4");
+        assert_eq!(1, chapter);
+        assert_eq!("GH030001.mp4", name.file_name(3));
+    }
+
+    #[test]
+    fn clip_chapter_older_gopro() {
+        // the first chapter has a name of its own
+        let (first_name, first_chapter) = chapter_of("GOPR0123.MP4");
+        assert_eq!(0, first_chapter);
+        assert_eq!(0, first_name.first_chapter);
+
+        let (name, chapter) = chapter_of("GP010123.MP4");
+        assert_eq!(1, chapter);
+        assert!(name == first_name);
+        assert_eq!("GOPR0123.MP4", name.file_name(0));
+        assert_eq!("GP020123.MP4", name.file_name(2));
+    }
+
+    #[test]
+    fn clip_chapter_red() {
+        let (name, chapter) = chapter_of("A001_C001_0101AB_002.R3D");
+        assert_eq!(2, chapter);
+        assert_eq!(1, name.first_chapter);
+        assert_eq!("A001_C001_0101AB_001.R3D", name.file_name(1));
+        assert_eq!("A001_C001_0101AB_010.R3D", name.file_name(10));
+        assert!(name == chapter_of("A001_C001_0101AB_001.R3D").0);
+    }
+
+    #[test]
+    fn clip_chapter_other_names() {
+        for file_name in [
+            "DSCF0001.JPG",
+            "C0001.MP4",
+            "MVI_1234.MP4",
+            "GX01012.MP4",
+            "GX010123.MOV",
+            "GXA10123.MP4",
+            "GX01A123.MP4",
+            "GOPRA123.MP4",
+            "GP01é23.MP4",
+            "A001_C001_0101AB_0A1.R3D",
+            "A001_C001_0101AB-001.R3D",
+            "_001.R3D",
+        ] {
+            assert!(
+                clip_chapter(file_name).is_none(),
+                "'{}' shouldn't be a chapter of a spanned clip",
+                file_name
+            );
+        }
+    }
+
+    // an MP4 with only a moov box and its mvhd box, with the given creation time and duration
+    fn mp4(creation_time: u32, duration_secs: u32) -> Vec<u8> {
+        let boxed = |box_type: &[u8; 4], contents: &[u8]| {
+            let mut b = ((8 + contents.len()) as u32).to_be_bytes().to_vec();
+            b.extend(box_type);
+            b.extend(contents);
+            b
+        };
+
+        // version 0, with a time scale of 1000
+        let mut mvhd = vec![0; 4];
+        mvhd.extend(creation_time.to_be_bytes());
+        mvhd.extend(creation_time.to_be_bytes());
+        mvhd.extend(1000u32.to_be_bytes());
+        mvhd.extend((duration_secs * 1000).to_be_bytes());
+        mvhd.resize(100, 0);
+
+        boxed(b"moov", &boxed(b"mvhd", &mvhd))
+    }
+
+    #[test]
+    fn numbered_clip_names() {
+        let (name, number) = numbered_clip("C0002.MP4").unwrap();
+        assert_eq!(2, number);
+        assert_eq!("C0003.MP4", name.file_name(3));
+        assert!(name == numbered_clip("C0100.MP4").unwrap().0);
+
+        let (name, number) = numbered_clip("MVI_1234.MOV").unwrap();
+        assert_eq!(1234, number);
+        assert_eq!("MVI_1235.MOV", name.file_name(1235));
+        assert!(name != numbered_clip("MVI_1234.MP4").unwrap().0);
+
+        for file_name in [
+            "C0001M01.XML",
+            "C001.MP4",
+            "C0A01.MP4",
+            "MVI_1234.JPG",
+            "GX010123.MP4",
+        ] {
+            assert!(numbered_clip(file_name).is_none(), "{}", file_name);
+        }
+    }
+
+    #[test]
+    fn continues_clip_times() {
+        let dir = std::env::temp_dir().join(format!("dit-test-chapters-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name);
+
+        // a 10 minute recording, then one that starts right as it stops, and one a minute later
+        std::fs::write(path("C0001.MP4"), mp4(3_000_000_000, 600)).unwrap();
+        std::fs::write(path("C0002.MP4"), mp4(3_000_000_600, 300)).unwrap();
+        std::fs::write(path("C0003.MP4"), mp4(3_000_000_960, 300)).unwrap();
+        assert!(continues_clip(&path("C0001.MP4"), &path("C0002.MP4")));
+        assert!(!continues_clip(&path("C0002.MP4"), &path("C0003.MP4")));
+        assert!(!was_split(&path("C0001.MP4")));
+
+        // a file cut off at the FAT32 size limit goes on in the next one, whatever its times say
+        let file = File::create(path("C0004.MP4")).unwrap();
+        file.set_len(FAT32_MAX_FILE_SIZE - 1024 * 1024).unwrap();
+        assert!(was_split(&path("C0004.MP4")));
+        assert!(continues_clip(&path("C0004.MP4"), &path("C0001.MP4")));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
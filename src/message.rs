@@ -2,11 +2,19 @@ use std::path::PathBuf;
 
 /**
  * A request that simply indicates what the next type of transfer should be.
+ *
+ * Merge(n) says that the next n hash results (or None) from every read path are for the same
+ * sub path. n is only more than 1 when a layout gives more than one file in the same read path
+ * the same sub path.
+ *
+ * Group(n) says that the next n transfers are a family of files (see common::family_stem()),
+ * which are renamed together if any of them has a merge conflict.
  */
 #[derive(PartialEq)]
 pub enum TransferRequest {
     Copy,
-    Merge,
+    Merge(usize),
+    Group(usize),
 }
 
 /**
@@ -26,12 +34,27 @@ pub struct HashResult {
     pub hash: String,
 }
 
+/**
+ * Response to a HashRequest (or to None, if the file wasn't in that read path).
+ *
+ * Several hash threads can work through one read path's requests at once, so each response is
+ * numbered in the order its request was received, and put back into that order by the merge
+ * thread.
+ */
+pub struct HashResponse {
+    pub seq: u64,
+    pub hash_result: Option<HashResult>,
+}
+
 /**
  * Copy a single source file to its corresponding location in all of the destination directories.
  */
 pub struct CopyToDestRequest {
     pub sub_path: String,
     pub src_path: PathBuf,
+
+    // index of the read path that src_path is in
+    pub read_path_index: usize,
 }
 
 /**
@@ -40,4 +63,17 @@ pub struct CopyToDestRequest {
 pub struct CopyFileRequest {
     pub src_path: PathBuf,
     pub dest_path: PathBuf,
+
+    // index of the write path that dest_path is in
+    // (copy threads are shared by all of the write paths on the same device)
+    pub write_path_index: usize,
+
+    // hard link dest_path to this earlier copy of the same contents, instead of copying
+    pub link_path: Option<PathBuf>,
+
+    // hash of the source file, if it has been hashed already
+    pub src_hash: Option<String>,
+
+    // is dest_path renamed because of a merge conflict? (the pre-scan counts these as new files)
+    pub renamed: bool,
 }
@@ -0,0 +1,257 @@
+use crate::discover::ScanTotals;
+use crate::fsutil;
+use crate::ReadWritePaths;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/**
+ * Progress of a single read path, as of the time a ProgressReport was made.
+ */
+pub struct SourceProgress {
+    pub read_path: PathBuf,
+
+    // bytes read from this read path so far, for hashing and copying
+    pub bytes_read: u64,
+
+    // read throughput since the previous report
+    pub bytes_per_sec: f64,
+}
+
+/**
+ * Progress of a single write path, as of the time a ProgressReport was made.
+ */
+pub struct DestProgress {
+    pub write_path: PathBuf,
+
+    pub bytes_done: u64,
+    pub files_done: u64,
+
+    // totals from the pre-scan, less the files that turned out not to need copying
+    pub bytes_total: u64,
+    pub files_total: u64,
+
+    // write throughput since the previous report
+    pub bytes_per_sec: f64,
+}
+
+/**
+ * Periodic report of how far along a copy is, passed to the log_progress function.
+ */
+pub struct ProgressReport {
+    pub elapsed: Duration,
+
+    // estimated time remaining for the slowest destination, once there is enough data to guess
+    pub eta: Option<Duration>,
+
+    // is this the last report for the run?
+    pub finished: bool,
+
+    pub sources: Vec<SourceProgress>,
+    pub destinations: Vec<DestProgress>,
+}
+
+/**
+ * Point-in-time copy of the Progress counters, used to work out throughput between reports.
+ */
+pub struct ProgressSnapshot {
+    at: Instant,
+    src_bytes: Vec<u64>,
+    dest_bytes: Vec<u64>,
+}
+
+/**
+ * Shared progress counters, updated by the hash and copy threads as they work through each file.
+ *
+ * The list order of read_paths and write_paths is the index into the counters.
+ */
+pub struct Progress {
+    start: Instant,
+    read_paths: Vec<PathBuf>,
+    write_paths: Vec<PathBuf>,
+    src_bytes: Vec<AtomicU64>,
+    dest_bytes: Vec<AtomicU64>,
+    dest_files: Vec<AtomicU64>,
+
+    // totals from the pre-scan (see remove_dest_file())
+    dest_bytes_total: Vec<AtomicU64>,
+    dest_files_total: Vec<AtomicU64>,
+
+    // can each write path keep the holes in sparse files? (see dest_len())
+    dest_keeps_holes: Vec<bool>,
+}
+
+impl Progress {
+    pub fn new(read_write_paths: &ReadWritePaths, scan_totals: ScanTotals) -> Progress {
+        let read_paths = read_write_paths.read_paths.clone();
+        let write_paths = read_write_paths.write_paths.clone();
+
+        let src_bytes = read_paths.iter().map(|_| AtomicU64::new(0)).collect();
+        let dest_bytes = write_paths.iter().map(|_| AtomicU64::new(0)).collect();
+        let dest_files = write_paths.iter().map(|_| AtomicU64::new(0)).collect();
+        let dest_bytes_total = scan_totals
+            .dest_bytes
+            .into_iter()
+            .map(AtomicU64::new)
+            .collect();
+        let dest_files_total = scan_totals
+            .dest_files
+            .into_iter()
+            .map(AtomicU64::new)
+            .collect();
+
+        Progress {
+            start: Instant::now(),
+            read_paths,
+            write_paths,
+            src_bytes,
+            dest_bytes,
+            dest_files,
+            dest_bytes_total,
+            dest_files_total,
+            dest_keeps_holes: scan_totals.dest_keeps_holes,
+        }
+    }
+
+    /**
+     * Record bytes read from a source file, attributed to whichever read path it lives under.
+     */
+    pub fn add_src_bytes(&self, src_path: &Path, bytes: u64) {
+        for (read_path, src_bytes) in self.read_paths.iter().zip(&self.src_bytes) {
+            if src_path.starts_with(read_path) {
+                src_bytes.fetch_add(bytes, Ordering::Relaxed);
+                return;
+            }
+        }
+    }
+
+    /**
+     * Get the bytes a source file is counted as in the totals for the given write path, which
+     * includes the holes in a sparse file if the write path's filesystem fills them in.
+     */
+    pub fn dest_len(&self, write_path_index: usize, src_metadata: &Metadata) -> u64 {
+        fsutil::written_len(src_metadata, self.dest_keeps_holes[write_path_index])
+    }
+
+    /**
+     * Does the given write path's filesystem keep the holes in sparse files?
+     */
+    pub fn dest_keeps_holes(&self, write_path_index: usize) -> bool {
+        self.dest_keeps_holes[write_path_index]
+    }
+
+    /**
+     * Record bytes written to the given write path.
+     */
+    pub fn add_dest_bytes(&self, write_path_index: usize, bytes: u64) {
+        self.dest_bytes[write_path_index].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /**
+     * Record a finished file for the given write path.
+     */
+    pub fn add_dest_file(&self, write_path_index: usize) {
+        self.dest_files[write_path_index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /**
+     * Take a file that the pre-scan counted for the given write path back out of its totals,
+     * because it turned out not to need copying (it was already there, or the ledger or
+     * dedupe skipped it).
+     */
+    pub fn remove_dest_file(&self, write_path_index: usize, bytes: u64) {
+        let _ = self.dest_bytes_total[write_path_index].fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |total| Some(total.saturating_sub(bytes)),
+        );
+        let _ = self.dest_files_total[write_path_index].fetch_update(
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+            |total| Some(total.saturating_sub(1)),
+        );
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            at: Instant::now(),
+            src_bytes: load_all(&self.src_bytes),
+            dest_bytes: load_all(&self.dest_bytes),
+        }
+    }
+
+    /**
+     * Build a report from the current counters. Throughput is measured against the previous
+     * snapshot, and the ETA against the average rate since the start of the run.
+     */
+    pub fn report(&self, prev: &ProgressSnapshot, finished: bool) -> ProgressReport {
+        let now = self.snapshot();
+        let interval = now.at.duration_since(prev.at).as_secs_f64();
+        let elapsed = now.at.duration_since(self.start);
+
+        let mut sources = vec![];
+        for (i, read_path) in self.read_paths.iter().enumerate() {
+            sources.push(SourceProgress {
+                read_path: read_path.clone(),
+                bytes_read: now.src_bytes[i],
+                bytes_per_sec: rate(now.src_bytes[i] - prev.src_bytes[i], interval),
+            });
+        }
+
+        let mut eta = Some(Duration::ZERO);
+        let mut destinations = vec![];
+        for (i, write_path) in self.write_paths.iter().enumerate() {
+            // the pre-scan can't see every merge conflict coming (see discover::scan()), so the
+            // totals grow to cover any extra copies
+            let bytes_done = now.dest_bytes[i];
+            let bytes_total = self.dest_bytes_total[i]
+                .load(Ordering::Relaxed)
+                .max(bytes_done);
+            let files_done = self.dest_files[i].load(Ordering::Relaxed);
+            let files_total = self.dest_files_total[i]
+                .load(Ordering::Relaxed)
+                .max(files_done);
+
+            // the slowest destination determines when the whole run is done
+            let remaining = bytes_total.saturating_sub(bytes_done);
+            let average = rate(bytes_done, elapsed.as_secs_f64());
+            eta = match eta {
+                Some(eta) if remaining == 0 => Some(eta),
+                Some(eta) if average > 0.0 => {
+                    Some(eta.max(Duration::from_secs_f64(remaining as f64 / average)))
+                }
+                _ => None,
+            };
+
+            destinations.push(DestProgress {
+                write_path: write_path.clone(),
+                bytes_done,
+                files_done,
+                bytes_total,
+                files_total,
+                bytes_per_sec: rate(bytes_done - prev.dest_bytes[i], interval),
+            });
+        }
+
+        ProgressReport {
+            elapsed,
+            eta,
+            finished,
+            sources,
+            destinations,
+        }
+    }
+}
+
+fn load_all(counters: &[AtomicU64]) -> Vec<u64> {
+    counters.iter().map(|c| c.load(Ordering::Relaxed)).collect()
+}
+
+fn rate(bytes: u64, secs: f64) -> f64 {
+    if secs > 0.0 {
+        bytes as f64 / secs
+    } else {
+        0.0
+    }
+}
@@ -1,82 +1,303 @@
-use crate::common::ThreadRunContext;
+use crate::common::{dit_error, CopyOptions, Dedupe, MatchBy, ThreadRunContext};
+use crate::discover::ScanPlan;
+use crate::integrity;
+use crate::ledger::Ledger;
 use crate::message::{
-    CopyFileRequest, CopyToDestRequest, HashRequest, HashResult, TransferRequest,
+    CopyFileRequest, CopyToDestRequest, HashRequest, HashResponse, HashResult, TransferRequest,
 };
+use crate::progress::{Progress, ProgressReport};
+use crate::xmp::Sidecars;
 use crate::{common, discover, fsutil, MergeResult};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error::Error;
 use std::fs;
 use std::fs::File;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::{Receiver, SyncSender};
-use std::time::Duration;
+use std::sync::mpsc::{Receiver, RecvError, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 const RECV_TIMEOUT: Duration = Duration::from_millis(100);
 
-//////////////////////////////////////////////////////////////////////////////
-// PUBLIC FUNCTIONS                                                         //
-//////////////////////////////////////////////////////////////////////////////
+/**
+ * Hash requests for all of the read paths on one device, shared by that device's hash threads.
+ *
+ * Discovery sends one request (or None) to every read path for each merge, in order, so requests
+ * are taken from each read path in turn, in the same order, and numbered as they are taken.
+ */
+pub struct HashQueue {
+    // hash request receiver for each read path, with the number to give its next request
+    hash_req_channels_rx: Vec<(Receiver<Option<HashRequest>>, u64)>,
+
+    // index into hash_req_channels_rx of the read path whose turn it is
+    turn: usize,
+}
+
+impl HashQueue {
+    pub fn new(hash_req_channels_rx: Vec<Receiver<Option<HashRequest>>>) -> HashQueue {
+        HashQueue {
+            hash_req_channels_rx: hash_req_channels_rx.into_iter().map(|rx| (rx, 0)).collect(),
+            turn: 0,
+        }
+    }
+
+    /**
+     * Take the next hash request, along with the index of its read path and its number.
+     */
+    fn recv_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<(usize, u64, Option<HashRequest>), RecvTimeoutError> {
+        let i = self.turn;
+        let (hash_req_rx, next_seq) = &mut self.hash_req_channels_rx[i];
+        let option_hash_req = hash_req_rx.recv_timeout(timeout)?;
+
+        let seq = *next_seq;
+        *next_seq += 1;
+        self.turn = (i + 1) % self.hash_req_channels_rx.len();
+
+        Ok((i, seq, option_hash_req))
+    }
+}
 
 /**
- * Discover thread.
+ * Hash response receiver for a single read path, which puts the responses back in order.
  */
-pub fn discover(
-    thread_run_ctx: ThreadRunContext,
-    xfer_req_tx: &SyncSender<TransferRequest>,
-    copy_to_dest_req_tx: &SyncSender<CopyToDestRequest>,
-    hash_req_channels_tx: Vec<SyncSender<Option<HashRequest>>>,
-    read_paths: Vec<String>,
-    write_paths: Vec<String>,
-) {
-    // create vec of *references* to hash request tx channels
-    let mut hash_req_tx_vec = vec![];
-    for i in 0..hash_req_channels_tx.len() {
-        hash_req_tx_vec.push(hash_req_channels_tx.get(i).unwrap());
+pub struct HashResultReceiver {
+    hash_res_rx: Receiver<HashResponse>,
+    next_seq: u64,
+
+    // responses that arrived ahead of their turn, by number
+    pending: BTreeMap<u64, Option<HashResult>>,
+}
+
+impl HashResultReceiver {
+    pub fn new(hash_res_rx: Receiver<HashResponse>) -> HashResultReceiver {
+        HashResultReceiver {
+            hash_res_rx,
+            next_seq: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /**
+     * Receive the next hash result, in the order the requests were sent.
+     */
+    fn recv(&mut self) -> Result<Option<HashResult>, RecvError> {
+        loop {
+            if let Some(option_hash_res) = self.pending.remove(&self.next_seq) {
+                self.next_seq += 1;
+                return Ok(option_hash_res);
+            }
+
+            let hash_res = self.hash_res_rx.recv()?;
+            self.pending.insert(hash_res.seq, hash_res.hash_result);
+        }
+    }
+}
+
+/**
+ * Remembers the first file copied with each hash, so that files with the same contents under other
+ * sub paths can be dealt with according to copy_options.dedupe.
+ */
+pub struct Duplicates {
+    dedupe: Option<Dedupe>,
+
+    // hash -> sub path of the first file copied with that hash
+    originals: HashMap<String, String>,
+}
+
+impl Duplicates {
+    pub fn new(dedupe: Option<Dedupe>) -> Duplicates {
+        Duplicates {
+            dedupe,
+            originals: HashMap::new(),
+        }
+    }
+
+    /**
+     * Get the sub path of the file that the given file is a duplicate of, or None if it isn't a
+     * duplicate, in which case it's remembered as the original for its hash.
+     *
+     * Empty files are never duplicates.
+     */
+    fn check(&mut self, hash: &str, sub_path: &str, src_path: &Path) -> Option<String> {
+        // not deduping
+        self.dedupe?;
+
+        if let Ok(metadata) = src_path.metadata() {
+            if 0 == metadata.len() {
+                return None;
+            }
+        }
+
+        match self.originals.get(hash) {
+            Some(original) if original != sub_path => Some(String::from(original)),
+            Some(_) => None,
+            None => {
+                self.originals
+                    .insert(String::from(hash), String::from(sub_path));
+                None
+            }
+        }
+    }
+}
+
+/**
+ * Counts the write paths that each source file has been copied to, so its pages can be dropped
+ * from the page cache once the last copy is done, and not before, when the other copies would
+ * have to read it from the source again.
+ *
+ * Also keeps each source file's hash until then, when copies are verified, so the source is only
+ * hashed once.
+ */
+pub struct SourceCopies {
+    write_paths: usize,
+
+    // source path -> (number of copies done, hash)
+    copies: Mutex<HashMap<PathBuf, (usize, Option<String>)>>,
+}
+
+impl SourceCopies {
+    pub fn new(write_paths: usize) -> SourceCopies {
+        SourceCopies {
+            write_paths,
+            copies: Mutex::new(HashMap::new()),
+        }
     }
 
-    let mut read_paths_str = vec![];
-    for read_path in &read_paths {
-        read_paths_str.push(read_path.as_str());
+    /**
+     * Record that the source file has been copied to one more write path, and return true if it
+     * has now been copied to all of them.
+     */
+    fn copied(&self, src_path: &Path) -> bool {
+        let mut copies = self.copies.lock().unwrap();
+        let (count, _) = copies.entry(src_path.to_path_buf()).or_insert((0, None));
+        *count += 1;
+
+        if *count < self.write_paths {
+            return false;
+        }
+
+        copies.remove(src_path);
+        true
     }
 
-    let mut write_paths_str = vec![];
-    for write_path in &write_paths {
-        write_paths_str.push(write_path.as_str());
+    /**
+     * Get the hash of a source file, hashing it if no other copy has yet.
+     */
+    fn src_hash(&self, src_path: &Path, buf_size: usize) -> Result<String, Box<dyn Error>> {
+        if let Some((_, Some(hash))) = self.copies.lock().unwrap().get(src_path) {
+            return Ok(hash.clone());
+        }
+
+        // don't hold the lock while hashing, the other copy threads might need it
+        // the other copies will read the source file again, so leave it in the page cache
+        let hash = fsutil::hash_file_keep_cached(src_path, buf_size, &mut |_| Ok(()))?;
+
+        let mut copies = self.copies.lock().unwrap();
+        let (_, src_hash) = copies.entry(src_path.to_path_buf()).or_insert((0, None));
+        *src_hash = Some(hash.clone());
+
+        Ok(hash)
     }
+}
+
+/**
+ * What the discovery thread works with: the read and write paths, what scan() found out about
+ * them, and the channels it sends to.
+ */
+pub struct DiscoverContext {
+    pub read_paths: Vec<String>,
+    pub write_paths: Vec<String>,
+    pub scan_plan: ScanPlan,
+    pub copy_options: CopyOptions,
+    pub xfer_req_tx: SyncSender<TransferRequest>,
+    pub copy_to_dest_req_tx: SyncSender<CopyToDestRequest>,
+    pub hash_req_channels_tx: Vec<SyncSender<Option<HashRequest>>>,
+}
+
+/**
+ * What a hash thread works with, shared with the other hash threads for the same device: the
+ * queue for the read paths on the device and a hash response channel for each of them.
+ */
+#[derive(Clone)]
+pub struct HashContext {
+    pub log_warn: fn(&str),
+    pub progress: Arc<Progress>,
+    pub buf_size: usize,
+    pub check: bool,
+    pub hash_queue: Arc<Mutex<HashQueue>>,
+    pub hash_res_channels_tx: Vec<SyncSender<HashResponse>>,
+}
+
+/**
+ * What the merge thread works with: its settings, what it remembers between files, and the
+ * channels it reads from and writes to.
+ */
+pub struct MergeContext {
+    pub log_info: fn(&str),
+    pub log_warn: fn(&str),
+    pub write_paths: Vec<String>,
+    pub match_by: MatchBy,
+    pub duplicates: Duplicates,
+    pub progress: Arc<Progress>,
+    pub xfer_req_rx: Receiver<TransferRequest>,
+    pub hash_res_channels_rx: Vec<HashResultReceiver>,
+    pub copy_to_dest_rx: Receiver<CopyToDestRequest>,
+    pub copy_file_req_channels_tx: Vec<SyncSender<CopyFileRequest>>,
+}
+
+/**
+ * What a copy thread works with, shared with the other copy threads.
+ */
+#[derive(Clone)]
+pub struct CopyContext {
+    pub log_info: fn(&str),
+    pub log_warn: fn(&str),
+    pub copy_options: CopyOptions,
+    pub progress: Arc<Progress>,
+    pub source_copies: Arc<SourceCopies>,
+    pub ledger: Option<Arc<Ledger>>,
+    pub sidecars: Option<Arc<Sidecars>>,
+}
+
+//////////////////////////////////////////////////////////////////////////////
+// PUBLIC FUNCTIONS                                                         //
+//////////////////////////////////////////////////////////////////////////////
 
+/**
+ * Discover thread.
+ */
+pub fn discover(thread_run_ctx: ThreadRunContext, discover_ctx: DiscoverContext) {
     // discover files
-    let _ = discover::discover_files(
-        &thread_run_ctx,
-        &xfer_req_tx,
-        &copy_to_dest_req_tx,
-        &hash_req_tx_vec,
-        &read_paths_str,
-        &write_paths_str,
-    );
+    let _ = discover::discover_files(&thread_run_ctx, &discover_ctx);
 }
 
 /**
  * Hash thread.
+ *
+ * Several hash threads can share the queue for the read paths on one device. Each one sends its
+ * results to the hash response channel for the read path that the request came from, in
+ * hash_res_channels_tx (in the same order as the read paths in the queue).
+ *
+ * With check, each file is also checked for damage after it is hashed (see integrity::check()),
+ * and the thread returns MergeResult::Conflict if any file failed the check.
  */
-pub fn hash(
-    thread_run_ctx: ThreadRunContext,
-    log_warn: fn(&str),
-    hash_req_rx: Receiver<Option<HashRequest>>,
-    hash_res_tx: SyncSender<Option<HashResult>>,
-) {
+pub fn hash(thread_run_ctx: ThreadRunContext, hash_ctx: HashContext) -> MergeResult {
+    let hash_queue = &hash_ctx.hash_queue;
+    let mut check_result = MergeResult::Ok;
+
     while thread_run_ctx.is_running() {
-        match hash_req_rx.recv_timeout(RECV_TIMEOUT) {
-            Ok(option_hash_req) => {
+        let result = hash_queue.lock().unwrap().recv_timeout(RECV_TIMEOUT);
+        match result {
+            Ok((i, seq, option_hash_req)) => {
                 if thread_run_ctx.is_clean() {
-                    let option_hash_res =
-                        handle_hash_req(&thread_run_ctx, log_warn, option_hash_req);
-                    match hash_res_tx.send(option_hash_res) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            let err = format!("error sending to hash request queue: {}", e);
-                            log_warn(&err);
-                            thread_run_ctx.unclean_shutdown();
-                        }
-                    }
+                    let cur_result =
+                        handle_hash(&thread_run_ctx, &hash_ctx, i, seq, option_hash_req);
+                    check_result = max_merge_result(&check_result, &cur_result);
                 }
             }
             Err(_) => {
@@ -87,23 +308,17 @@ pub fn hash(
 
     // if we stopped early because of an error, don't drain the queue
     if !thread_run_ctx.is_clean() {
-        return;
+        return check_result;
     }
 
     loop {
-        match hash_req_rx.recv_timeout(RECV_TIMEOUT) {
-            Ok(option_hash_req) => {
+        let result = hash_queue.lock().unwrap().recv_timeout(RECV_TIMEOUT);
+        match result {
+            Ok((i, seq, option_hash_req)) => {
                 if thread_run_ctx.is_clean() {
-                    let option_hash_res =
-                        handle_hash_req(&thread_run_ctx, log_warn, option_hash_req);
-                    match hash_res_tx.send(option_hash_res) {
-                        Ok(_) => {}
-                        Err(e) => {
-                            let err = format!("error sending to hash request queue: {}", e);
-                            log_warn(&err);
-                            thread_run_ctx.unclean_shutdown();
-                        }
-                    }
+                    let cur_result =
+                        handle_hash(&thread_run_ctx, &hash_ctx, i, seq, option_hash_req);
+                    check_result = max_merge_result(&check_result, &cur_result);
                 }
             }
             Err(_) => {
@@ -112,36 +327,20 @@ pub fn hash(
             }
         }
     }
+
+    check_result
 }
 
 /**
  * Merge thread.
  */
-pub fn merge(
-    thread_run_ctx: ThreadRunContext,
-    log_info: fn(&str),
-    log_warn: fn(&str),
-    write_paths: Vec<String>,
-    xfer_req_rx: Receiver<TransferRequest>,
-    hash_res_channels_rx: Vec<Receiver<Option<HashResult>>>,
-    copy_to_dest_rx: Receiver<CopyToDestRequest>,
-    copy_file_req_channels_tx: Vec<SyncSender<CopyFileRequest>>,
-) -> MergeResult {
+pub fn merge(thread_run_ctx: ThreadRunContext, mut merge_ctx: MergeContext) -> MergeResult {
     let mut merge_result = MergeResult::Ok;
 
     while thread_run_ctx.is_running() {
-        match xfer_req_rx.recv_timeout(RECV_TIMEOUT) {
+        match merge_ctx.xfer_req_rx.recv_timeout(RECV_TIMEOUT) {
             Ok(xfer_req) => {
-                let cur_result = handle_xfer_req(
-                    &thread_run_ctx,
-                    log_info,
-                    log_warn,
-                    &write_paths,
-                    &hash_res_channels_rx,
-                    &copy_to_dest_rx,
-                    &copy_file_req_channels_tx,
-                    xfer_req,
-                );
+                let cur_result = handle_xfer_req(&thread_run_ctx, &mut merge_ctx, xfer_req);
                 merge_result = max_merge_result(&merge_result, &cur_result);
             }
             Err(_) => {
@@ -156,18 +355,9 @@ pub fn merge(
     }
 
     loop {
-        match xfer_req_rx.recv_timeout(RECV_TIMEOUT) {
+        match merge_ctx.xfer_req_rx.recv_timeout(RECV_TIMEOUT) {
             Ok(xfer_req) => {
-                let cur_result = handle_xfer_req(
-                    &thread_run_ctx,
-                    log_info,
-                    log_warn,
-                    &write_paths,
-                    &hash_res_channels_rx,
-                    &copy_to_dest_rx,
-                    &copy_file_req_channels_tx,
-                    xfer_req,
-                );
+                let cur_result = handle_xfer_req(&thread_run_ctx, &mut merge_ctx, xfer_req);
                 merge_result = max_merge_result(&merge_result, &cur_result);
             }
             Err(_) => {
@@ -182,16 +372,26 @@ pub fn merge(
 
 /**
  * Copy thread.
+ *
+ * Several copy threads can share the same channel, which carries the copy requests for all of the
+ * write paths on one device.
+ *
+ * Returns MergeResult::Conflict if any file was copied without something that couldn't be kept
+ * (like extended attributes on a filesystem without them), or without its XMP sidecar.
  */
 pub fn copy(
     thread_run_ctx: ThreadRunContext,
-    log_warn: fn(&str),
-    copy_file_req_rx: Receiver<CopyFileRequest>,
-) {
+    copy_ctx: CopyContext,
+    copy_file_req_rx: Arc<Mutex<Receiver<CopyFileRequest>>>,
+) -> MergeResult {
+    let mut copy_result = MergeResult::Ok;
+
     while thread_run_ctx.is_running() {
-        match copy_file_req_rx.recv_timeout(RECV_TIMEOUT) {
+        let result = copy_file_req_rx.lock().unwrap().recv_timeout(RECV_TIMEOUT);
+        match result {
             Ok(copy_file_req) => {
-                handle_copy(&thread_run_ctx, log_warn, copy_file_req);
+                let cur_result = handle_copy(&thread_run_ctx, &copy_ctx, copy_file_req);
+                copy_result = max_merge_result(&copy_result, &cur_result);
             }
             Err(_) => {
                 // timeout, ignore
@@ -201,13 +401,15 @@ pub fn copy(
 
     // if we stopped early because of an error, don't drain the queue
     if !thread_run_ctx.is_clean() {
-        return;
+        return copy_result;
     }
 
     loop {
-        match copy_file_req_rx.recv_timeout(RECV_TIMEOUT) {
+        let result = copy_file_req_rx.lock().unwrap().recv_timeout(RECV_TIMEOUT);
+        match result {
             Ok(copy_file_req) => {
-                handle_copy(&thread_run_ctx, log_warn, copy_file_req);
+                let cur_result = handle_copy(&thread_run_ctx, &copy_ctx, copy_file_req);
+                copy_result = max_merge_result(&copy_result, &cur_result);
             }
             Err(_) => {
                 // timeout, queue is empty
@@ -215,6 +417,40 @@ pub fn copy(
             }
         }
     }
+
+    copy_result
+}
+
+/**
+ * Progress thread.
+ *
+ * Calls log_progress every progress_interval until the thread is shut down, and then one last
+ * time with the final numbers (unless the run stopped because of an error).
+ */
+pub fn progress(
+    thread_run_ctx: ThreadRunContext,
+    log_progress: fn(&ProgressReport),
+    progress: Arc<Progress>,
+    progress_interval: Duration,
+) {
+    let mut prev = progress.snapshot();
+    let mut last_report = Instant::now();
+
+    while thread_run_ctx.is_running() {
+        thread::sleep(RECV_TIMEOUT.min(progress_interval));
+
+        if last_report.elapsed() >= progress_interval {
+            log_progress(&progress.report(&prev, false));
+            prev = progress.snapshot();
+            last_report = Instant::now();
+        }
+    }
+
+    if !thread_run_ctx.is_clean() {
+        return;
+    }
+
+    log_progress(&progress.report(&prev, true));
 }
 
 //////////////////////////////////////////////////////////////////////////////
@@ -226,32 +462,65 @@ pub fn copy(
  */
 fn handle_xfer_req(
     thread_run_ctx: &ThreadRunContext,
-    log_info: fn(&str),
-    log_warn: fn(&str),
-    write_paths: &Vec<String>,
-    hash_res_channels_rx: &Vec<Receiver<Option<HashResult>>>,
-    copy_to_dest_rx: &Receiver<CopyToDestRequest>,
-    copy_file_req_channels_tx: &Vec<SyncSender<CopyFileRequest>>,
+    merge_ctx: &mut MergeContext,
     xfer_req: TransferRequest,
 ) -> MergeResult {
     match xfer_req {
-        TransferRequest::Copy => handle_copy_to_dest(
-            &thread_run_ctx,
-            log_info,
-            log_warn,
-            &write_paths,
-            &copy_to_dest_rx,
-            &copy_file_req_channels_tx,
-        ),
-        TransferRequest::Merge => handle_hash_merge(
-            &thread_run_ctx,
-            log_info,
-            log_warn,
-            &write_paths,
-            &hash_res_channels_rx,
-            &copy_file_req_channels_tx,
-        ),
+        TransferRequest::Copy => handle_copy_to_dest(thread_run_ctx, merge_ctx),
+        TransferRequest::Merge(n) => handle_hash_merge(thread_run_ctx, merge_ctx, n),
+        TransferRequest::Group(n) => handle_family(thread_run_ctx, merge_ctx, n),
+    }
+}
+
+/**
+ * Handle a numbered HashRequest from read path i for a hash thread, and send the numbered result
+ * to the merge thread.
+ *
+ * With check, the file's structure is checked after hashing it (while it is still in the page
+ * cache), and a damaged file is shown as a warning, and returns MergeResult::Conflict. It is
+ * still copied, since it may be the only copy there is.
+ */
+fn handle_hash(
+    thread_run_ctx: &ThreadRunContext,
+    hash_ctx: &HashContext,
+    i: usize,
+    seq: u64,
+    option_hash_req: Option<HashRequest>,
+) -> MergeResult {
+    let log_warn = hash_ctx.log_warn;
+    let hash_res_tx = &hash_ctx.hash_res_channels_tx[i];
+
+    let hash_result = handle_hash_req(
+        thread_run_ctx,
+        log_warn,
+        &hash_ctx.progress,
+        hash_ctx.buf_size,
+        option_hash_req,
+    );
+
+    let mut check_result = MergeResult::Ok;
+    if let (true, Some(hash_result)) = (hash_ctx.check, &hash_result) {
+        if let Err(e) = integrity::check(&hash_result.src_path) {
+            let err = format!(
+                "{} looks damaged: {}",
+                hash_result.src_path.to_str().unwrap(),
+                e
+            );
+            log_warn(&err);
+            check_result = MergeResult::Conflict;
+        }
+    }
+
+    match hash_res_tx.send(HashResponse { seq, hash_result }) {
+        Ok(_) => {}
+        Err(e) => {
+            let err = format!("error sending to hash request queue: {}", e);
+            log_warn(&err);
+            thread_run_ctx.unclean_shutdown();
+        }
     }
+
+    check_result
 }
 
 /**
@@ -260,30 +529,39 @@ fn handle_xfer_req(
 fn handle_hash_req(
     thread_run_ctx: &ThreadRunContext,
     log_warn: fn(&str),
+    progress: &Progress,
+    buf_size: usize,
     option_hash_req: Option<HashRequest>,
 ) -> Option<HashResult> {
     match option_hash_req {
-        Some(hash_req) => match crate::fsutil::hash_file(hash_req.src_path.as_path()) {
-            Ok(hash) => {
-                let sub_path = hash_req.sub_path;
-                let src_path = hash_req.src_path;
-                let hash_result = HashResult {
-                    sub_path,
-                    src_path,
-                    hash,
-                };
+        Some(hash_req) => {
+            // the file is about to be copied, so leave it in the page cache for the copy threads,
+            // which drop it after the last copy (see release_src_file())
+            match fsutil::hash_file_keep_cached(&hash_req.src_path, buf_size, &mut |n| {
+                progress.add_src_bytes(&hash_req.src_path, n);
+                Ok(())
+            }) {
+                Ok(hash) => {
+                    let sub_path = hash_req.sub_path;
+                    let src_path = hash_req.src_path;
+                    let hash_result = HashResult {
+                        sub_path,
+                        src_path,
+                        hash,
+                    };
 
-                return Some(hash_result);
-            }
-            Err(_) => {
-                let err = format!(
-                    "error hashing file: {}",
-                    hash_req.src_path.to_str().unwrap()
-                );
-                log_warn(&err);
-                thread_run_ctx.unclean_shutdown();
+                    return Some(hash_result);
+                }
+                Err(_) => {
+                    let err = format!(
+                        "error hashing file: {}",
+                        hash_req.src_path.to_str().unwrap()
+                    );
+                    log_warn(&err);
+                    thread_run_ctx.unclean_shutdown();
+                }
             }
-        },
+        }
         None => {}
     }
 
@@ -317,8 +595,10 @@ fn get_merge_conflict_dest_file_path(
     The entire file name if there is no embedded .;
     The entire file name if the file name begins with . and has no other .s within;
     Otherwise, the portion of the file name before the final .
+
+    (the sub path's file name can differ from the source file's with a layout)
      */
-    let file_stem = src_path.file_stem();
+    let file_stem = path_parent_path_buf.file_stem();
 
     /*
     None, if there is no file name;
@@ -326,7 +606,7 @@ fn get_merge_conflict_dest_file_path(
     None, if the file name begins with . and has no other .s within;
     Otherwise, the portion of the file name after the final .
      */
-    let extension = src_path.extension();
+    let extension = path_parent_path_buf.extension();
 
     if file_stem.is_some() {
         file_name.push_str(file_stem.unwrap().to_str().unwrap());
@@ -368,36 +648,50 @@ fn get_merge_conflict_dest_file_path(
 
 /**
  * Handle a hashed file merge for the merge thread.
+ *
+ * Takes n hash results (or None) from each read path for the same sub path (see
+ * TransferRequest::Merge).
  */
 fn handle_hash_merge(
     thread_run_ctx: &ThreadRunContext,
-    log_info: fn(&str),
-    log_warn: fn(&str),
-    write_paths: &Vec<String>,
-    hash_res_channels_rx: &Vec<Receiver<Option<HashResult>>>,
-    copy_file_req_channels_tx: &Vec<SyncSender<CopyFileRequest>>,
+    merge_ctx: &mut MergeContext,
+    n: usize,
 ) -> MergeResult {
+    let log_info = merge_ctx.log_info;
+    let log_warn = merge_ctx.log_warn;
+    let write_paths = &merge_ctx.write_paths;
+    let match_by = merge_ctx.match_by;
+    let duplicates = &mut merge_ctx.duplicates;
+    let hash_res_channels_rx = &mut merge_ctx.hash_res_channels_rx;
+    let copy_file_req_channels_tx = &merge_ctx.copy_file_req_channels_tx;
+
     // map of hash -> HashResult
     let mut map = BTreeMap::new();
 
     // build a map of each unique copy of this file sub path
-    for hash_res_rx in hash_res_channels_rx {
-        match hash_res_rx.recv() {
-            Ok(option_hash_res) => match option_hash_res {
-                Some(hash_res) => {
-                    if !map.contains_key(&hash_res.hash) {
-                        map.insert(String::from(&hash_res.hash), hash_res);
+    for _ in 0..n {
+        for hash_res_rx in hash_res_channels_rx.iter_mut() {
+            match hash_res_rx.recv() {
+                Ok(option_hash_res) => match option_hash_res {
+                    Some(hash_res) => {
+                        if !map.contains_key(&hash_res.hash) {
+                            map.insert(String::from(&hash_res.hash), hash_res);
+                        } else {
+                            // the same contents from another read path (or another file that a
+                            // layout gave the same sub path), which won't be copied
+                            drop_src_file(&hash_res.src_path);
+                        }
                     }
+                    None => {}
+                },
+                Err(e) => {
+                    if thread_run_ctx.is_clean() {
+                        let err = format!("error reading from hash result queue: {}", e);
+                        log_warn(&err);
+                        thread_run_ctx.unclean_shutdown();
+                    }
+                    return MergeResult::Error;
                 }
-                None => {}
-            },
-            Err(e) => {
-                if thread_run_ctx.is_clean() {
-                    let err = format!("error reading from hash result queue: {}", e);
-                    log_warn(&err);
-                    thread_run_ctx.unclean_shutdown();
-                }
-                return MergeResult::Error;
             }
         }
     }
@@ -421,8 +715,40 @@ fn handle_hash_merge(
                 write_paths,
                 &hash_res.src_path,
                 &hash_res.sub_path,
+                match_by,
+                &mut Some(String::from(&hash_res.hash)),
             );
 
+            // the same contents under a different sub path, with dedupe
+            let mut duplicate_of = None;
+            if !has_write_merge_conflict {
+                duplicate_of =
+                    duplicates.check(&hash_res.hash, &hash_res.sub_path, &hash_res.src_path);
+            }
+            if let Some(original) = &duplicate_of {
+                let action = match duplicates.dedupe {
+                    Some(Dedupe::Skip) => "skipped",
+                    Some(Dedupe::Link) => "linked",
+                    _ => "copied",
+                };
+                let err = format!(
+                    "{} is a duplicate of {} ({})",
+                    &hash_res.sub_path, original, action
+                );
+                log_warn(&err);
+
+                if Some(Dedupe::Skip) == duplicates.dedupe {
+                    // the pre-scan counted it wherever it wasn't already there
+                    for (i, write_path) in write_paths.iter().enumerate() {
+                        if !Path::new(write_path).join(&hash_res.sub_path).exists() {
+                            remove_from_totals(&merge_ctx.progress, i, &hash_res.src_path);
+                        }
+                    }
+                    drop_src_file(&hash_res.src_path);
+                    return MergeResult::Ok;
+                }
+            }
+
             let mut i = 0;
             for write_path in write_paths {
                 // get copy file request tx handle
@@ -448,6 +774,10 @@ fn handle_hash_merge(
                     let copy_file_req = CopyFileRequest {
                         src_path: PathBuf::from(&hash_res.src_path),
                         dest_path: PathBuf::from(dest_path_str),
+                        write_path_index: i,
+                        link_path: None,
+                        src_hash: Some(String::from(&hash_res.hash)),
+                        renamed: true,
                     };
 
                     if thread_run_ctx.is_clean() {
@@ -468,9 +798,22 @@ fn handle_hash_merge(
                     let mut dest_path_buf = PathBuf::from(write_path);
                     dest_path_buf.push(&hash_res.sub_path);
 
+                    // special case: link a duplicate to the first copy of its contents
+                    let mut link_path = None;
+                    if let (Some(original), Some(Dedupe::Link)) = (&duplicate_of, duplicates.dedupe)
+                    {
+                        let mut link_path_buf = PathBuf::from(write_path);
+                        link_path_buf.push(original);
+                        link_path = Some(link_path_buf);
+                    }
+
                     let copy_file_req = CopyFileRequest {
                         src_path: PathBuf::from(&hash_res.src_path),
                         dest_path: dest_path_buf,
+                        write_path_index: i,
+                        link_path,
+                        src_hash: Some(String::from(&hash_res.hash)),
+                        renamed: false,
                     };
 
                     if thread_run_ctx.is_clean() {
@@ -533,6 +876,10 @@ fn handle_hash_merge(
                 let copy_file_req = CopyFileRequest {
                     src_path: PathBuf::from(&hash_res.src_path),
                     dest_path: PathBuf::from(&dest_path),
+                    write_path_index: i,
+                    link_path: None,
+                    src_hash: Some(String::from(&hash_res.hash)),
+                    renamed: true,
                 };
 
                 match copy_file_req_tx.send(copy_file_req) {
@@ -558,23 +905,27 @@ fn handle_hash_merge(
 /**
  * Handle copying a source file to all destinations for the merge thread.
  */
-fn handle_copy_to_dest(
-    thread_run_ctx: &ThreadRunContext,
-    log_info: fn(&str),
-    log_warn: fn(&str),
-    write_paths: &Vec<String>,
-    copy_to_dest_rx: &Receiver<CopyToDestRequest>,
-    copy_file_req_channels_tx: &Vec<SyncSender<CopyFileRequest>>,
-) -> MergeResult {
+fn handle_copy_to_dest(thread_run_ctx: &ThreadRunContext, merge_ctx: &MergeContext) -> MergeResult {
+    let log_info = merge_ctx.log_info;
+    let log_warn = merge_ctx.log_warn;
+    let write_paths = &merge_ctx.write_paths;
+    let match_by = merge_ctx.match_by;
+    let copy_to_dest_rx = &merge_ctx.copy_to_dest_rx;
+    let copy_file_req_channels_tx = &merge_ctx.copy_file_req_channels_tx;
+
     match copy_to_dest_rx.recv() {
         Ok(copy_to_dest_req) => {
             let msg = format!("{}", &copy_to_dest_req.sub_path);
             log_info(&msg);
 
+            // the source file's hash, if it has been hashed yet
+            let mut src_hash = None;
             let has_write_merge_conflict = common::has_write_merge_conflict(
                 write_paths,
                 &copy_to_dest_req.src_path,
                 &copy_to_dest_req.sub_path,
+                match_by,
+                &mut src_hash,
             );
 
             let mut i = 0;
@@ -587,7 +938,7 @@ fn handle_copy_to_dest(
                         write_path,
                         &copy_to_dest_req.src_path,
                         &copy_to_dest_req.sub_path,
-                        None,
+                        src_hash.as_deref(),
                         "WRITE_MERGE_CONFLICT",
                     );
 
@@ -601,6 +952,10 @@ fn handle_copy_to_dest(
                     let copy_file_req = CopyFileRequest {
                         src_path: PathBuf::from(&copy_to_dest_req.src_path),
                         dest_path: PathBuf::from(dest_path_str),
+                        write_path_index: i,
+                        link_path: None,
+                        src_hash: src_hash.clone(),
+                        renamed: true,
                     };
 
                     if thread_run_ctx.is_clean() {
@@ -624,6 +979,10 @@ fn handle_copy_to_dest(
                     let copy_file_req = CopyFileRequest {
                         src_path: PathBuf::from(&copy_to_dest_req.src_path),
                         dest_path: dest_path_buf,
+                        write_path_index: i,
+                        link_path: None,
+                        src_hash: src_hash.clone(),
+                        renamed: false,
                     };
 
                     if thread_run_ctx.is_clean() {
@@ -658,56 +1017,432 @@ fn handle_copy_to_dest(
 }
 
 /**
- * Get the "maximum" merge result.
- *
- * Success is the "lowest" value, a merge conflict is in the middle, and an error is the "highest"
- * value.
- *
- * This lets us ratchet up so we can remember the most serious error we've seen.
+ * One file in a family of files (see common::family_stem()), as found in each read path.
  */
-fn max_merge_result(a: &MergeResult, b: &MergeResult) -> MergeResult {
-    if MergeResult::Error.eq(a) || MergeResult::Error.eq(b) {
-        return MergeResult::Error;
-    }
-
-    if MergeResult::Conflict.eq(a) || MergeResult::Conflict.eq(b) {
-        return MergeResult::Conflict;
-    }
+struct FamilyMember {
+    sub_path: String,
 
-    MergeResult::Ok
+    // source file, and its hash if it was hashed, for each read path (in the same order as the
+    // read paths), or None if that read path doesn't have the file
+    src_files: Vec<Option<(PathBuf, Option<String>)>>,
 }
 
 /**
- * Handle a file copy request for a copy thread.
+ * Handle a family of files for the merge thread, which is made up of the next n transfer
+ * requests.
+ *
+ * If any file in the family has a read merge conflict, the files from each read path are copied
+ * under names tagged with the hashes of that read path's versions of the conflicting files, so
+ * each version of the family still pairs up. Likewise, if any of the files that would keep their
+ * names has a write merge conflict, all of them are tagged with the hashes of the conflicting
+ * files.
  */
-fn handle_copy(
+fn handle_family(
     thread_run_ctx: &ThreadRunContext,
-    log_warn: fn(&str),
-    copy_file_req: CopyFileRequest,
-) {
-    // if the destination path already exists, don't copy the file again
-    // we are trusting that the destination file is correct, because if it was copied
-    // by this program last time, it would have been written atomically
-    if copy_file_req.dest_path.exists() {
-        return;
+    merge_ctx: &mut MergeContext,
+    n: usize,
+) -> MergeResult {
+    let log_info = merge_ctx.log_info;
+    let log_warn = merge_ctx.log_warn;
+    let write_paths = &merge_ctx.write_paths;
+    let match_by = merge_ctx.match_by;
+    let duplicates = &mut merge_ctx.duplicates;
+    let hash_res_channels_rx = &mut merge_ctx.hash_res_channels_rx;
+    let copy_to_dest_rx = &merge_ctx.copy_to_dest_rx;
+    let copy_file_req_channels_tx = &merge_ctx.copy_file_req_channels_tx;
+    let xfer_req_rx = &merge_ctx.xfer_req_rx;
+
+    let mut members = vec![];
+    for _ in 0..n {
+        match recv_family_member(xfer_req_rx, hash_res_channels_rx, copy_to_dest_rx) {
+            Ok(member) => {
+                log_info(&member.sub_path);
+                members.push(member);
+            }
+            Err(e) => {
+                if thread_run_ctx.is_clean() {
+                    let err = format!("error reading family of files: {}", e);
+                    log_warn(&err);
+                    thread_run_ctx.unclean_shutdown();
+                }
+                return MergeResult::Error;
+            }
+        }
     }
 
-    // get the destination directory
-    let dest_parent_path = copy_file_req.dest_path.parent();
-    if dest_parent_path.is_none() {
-        let err = format!(
-            "invalid destination path: '{}'",
+    // files that have different contents in different read paths
+    let conflicts: Vec<&FamilyMember> = members
+        .iter()
+        .filter(|member| {
+            let hashes: BTreeSet<&String> = member
+                .src_files
+                .iter()
+                .flatten()
+                .filter_map(|(_, hash)| hash.as_ref())
+                .collect();
+            hashes.len() > 1
+        })
+        .collect();
+
+    // read merge conflict tag for the files in each read path,
+    // or None if the read path doesn't have any of the conflicting files
+    let read_path_tags: Vec<Option<String>> = (0..hash_res_channels_rx.len())
+        .map(|i| {
+            let hashes: Vec<&str> = conflicts
+                .iter()
+                .map(|member| match &member.src_files[i] {
+                    Some((_, Some(hash))) => hash.as_str(),
+                    _ => "-",
+                })
+                .collect();
+
+            if hashes.iter().all(|hash| "-" == *hash) {
+                None
+            } else {
+                Some(fsutil::hash_hashes(&hashes))
+            }
+        })
+        .collect();
+
+    // each distinct (index into members, source file, read merge conflict tag) to copy
+    let mut copies: Vec<(usize, &PathBuf, Option<&String>)> = vec![];
+    for (j, member) in members.iter().enumerate() {
+        for (i, src_file) in member.src_files.iter().enumerate() {
+            if let Some((src_path, _)) = src_file {
+                let tag = read_path_tags[i].as_ref();
+                if !copies.iter().any(|(k, _, t)| j == *k && tag == *t) {
+                    copies.push((j, src_path, tag));
+                }
+            }
+        }
+    }
+
+    // the same contents from other read paths, which won't be copied
+    for member in &members {
+        for (src_path, _) in member.src_files.iter().flatten() {
+            if !copies.iter().any(|(_, path, _)| path == &src_path) {
+                drop_src_file(src_path);
+            }
+        }
+    }
+
+    // hashes of the files keeping their names that conflict with files in the destinations
+    let mut write_conflict_hashes = vec![];
+    for (j, src_path, tag) in &copies {
+        let member = &members[*j];
+        if tag.is_some() {
+            continue;
+        }
+
+        let mut hash = member
+            .src_files
+            .iter()
+            .flatten()
+            .find(|(path, _)| path == *src_path)
+            .and_then(|(_, hash)| hash.clone());
+        if !common::has_write_merge_conflict(
+            write_paths,
+            src_path,
+            &member.sub_path,
+            match_by,
+            &mut hash,
+        ) {
+            continue;
+        }
+
+        match hash {
+            Some(hash) => write_conflict_hashes.push(hash),
+            None => match fsutil::hash_file(src_path) {
+                Ok(hash) => write_conflict_hashes.push(hash),
+                Err(e) => {
+                    if thread_run_ctx.is_clean() {
+                        let err = format!("error hashing '{}': {}", src_path.to_str().unwrap(), e);
+                        log_warn(&err);
+                        thread_run_ctx.unclean_shutdown();
+                    }
+                    return MergeResult::Error;
+                }
+            },
+        }
+    }
+    let write_conflict_tag = if write_conflict_hashes.is_empty() {
+        None
+    } else {
+        let hashes: Vec<&str> = write_conflict_hashes.iter().map(|h| h.as_str()).collect();
+        Some(fsutil::hash_hashes(&hashes))
+    };
+
+    let mut merge_result = MergeResult::Ok;
+    for (j, src_path, read_path_tag) in copies {
+        let sub_path = &members[j].sub_path;
+        let src_hash = members[j]
+            .src_files
+            .iter()
+            .flatten()
+            .find(|(path, _)| path == src_path)
+            .and_then(|(_, hash)| hash.as_ref());
+        let (tag, conflict_type) = match read_path_tag {
+            Some(tag) => (Some(tag), "READ_MERGE_CONFLICT"),
+            None => (write_conflict_tag.as_ref(), "WRITE_MERGE_CONFLICT"),
+        };
+
+        // the same contents under a different sub path, with dedupe
+        let mut duplicate_of = None;
+        if let (None, Some(hash)) = (tag, src_hash) {
+            duplicate_of = duplicates.check(hash, sub_path, src_path);
+        }
+        if let Some(original) = &duplicate_of {
+            let action = match duplicates.dedupe {
+                Some(Dedupe::Skip) => "skipped",
+                Some(Dedupe::Link) => "linked",
+                _ => "copied",
+            };
+            let err = format!("{} is a duplicate of {} ({})", sub_path, original, action);
+            log_warn(&err);
+
+            if Some(Dedupe::Skip) == duplicates.dedupe {
+                // the pre-scan counted it wherever it wasn't already there
+                for (i, write_path) in write_paths.iter().enumerate() {
+                    if !Path::new(write_path).join(sub_path).exists() {
+                        remove_from_totals(&merge_ctx.progress, i, src_path);
+                    }
+                }
+                drop_src_file(src_path);
+                continue;
+            }
+        }
+
+        for (i, write_path) in write_paths.iter().enumerate() {
+            let dest_path = match tag {
+                Some(tag) => {
+                    // special case: merge conflict, tag the whole family
+                    // e.g. /path/to/disk1/DSCF1234.__READ_MERGE_CONFLICT__<tag>.RAF
+                    let dest_path = get_family_conflict_dest_file_path(
+                        write_path,
+                        sub_path,
+                        tag,
+                        conflict_type,
+                    );
+
+                    let err = format!(
+                        "{} -> {}",
+                        src_path.to_str().unwrap(),
+                        dest_path.to_str().unwrap()
+                    );
+                    log_warn(&err);
+
+                    merge_result = MergeResult::Conflict;
+                    dest_path
+                }
+                None => {
+                    // common case: no merge conflict
+                    let mut dest_path = PathBuf::from(write_path);
+                    dest_path.push(sub_path);
+                    dest_path
+                }
+            };
+
+            // special case: link a duplicate to the first copy of its contents
+            let mut link_path = None;
+            if let (Some(original), Some(Dedupe::Link)) = (&duplicate_of, duplicates.dedupe) {
+                let mut link_path_buf = PathBuf::from(write_path);
+                link_path_buf.push(original);
+                link_path = Some(link_path_buf);
+            }
+
+            let copy_file_req = CopyFileRequest {
+                src_path: PathBuf::from(src_path),
+                dest_path,
+                write_path_index: i,
+                link_path,
+                src_hash: src_hash.cloned(),
+                renamed: tag.is_some(),
+            };
+
+            if thread_run_ctx.is_clean() {
+                match copy_file_req_channels_tx[i].send(copy_file_req) {
+                    Ok(_) => {}
+                    Err(_) => {
+                        if thread_run_ctx.is_clean() {
+                            log_warn("error writing copy file request");
+                            thread_run_ctx.unclean_shutdown();
+                        }
+                        return MergeResult::Error;
+                    }
+                }
+            }
+        }
+    }
+
+    merge_result
+}
+
+/**
+ * Receive the next file in a family of files, from the copy to dest channel or the hash result
+ * channels, depending on the next transfer request.
+ */
+fn recv_family_member(
+    xfer_req_rx: &Receiver<TransferRequest>,
+    hash_res_channels_rx: &mut [HashResultReceiver],
+    copy_to_dest_rx: &Receiver<CopyToDestRequest>,
+) -> Result<FamilyMember, Box<dyn Error>> {
+    let mut src_files = vec![None; hash_res_channels_rx.len()];
+
+    match xfer_req_rx.recv()? {
+        TransferRequest::Copy => {
+            let copy_to_dest_req = copy_to_dest_rx.recv()?;
+            src_files[copy_to_dest_req.read_path_index] = Some((copy_to_dest_req.src_path, None));
+            Ok(FamilyMember {
+                sub_path: copy_to_dest_req.sub_path,
+                src_files,
+            })
+        }
+        TransferRequest::Merge(1) => {
+            let mut sub_path = None;
+            for (i, hash_res_rx) in hash_res_channels_rx.iter_mut().enumerate() {
+                if let Some(hash_res) = hash_res_rx.recv()? {
+                    sub_path = Some(hash_res.sub_path);
+                    src_files[i] = Some((hash_res.src_path, Some(hash_res.hash)));
+                }
+            }
+
+            match sub_path {
+                Some(sub_path) => Ok(FamilyMember {
+                    sub_path,
+                    src_files,
+                }),
+                None => dit_error("0 records from all hash result queues"),
+            }
+        }
+        TransferRequest::Merge(_) => dit_error("several versions of a file inside a family"),
+        TransferRequest::Group(_) => dit_error("family of files inside another family"),
+    }
+}
+
+/**
+ * Get the destination path for a file in a family of files (see common::family_stem()) that is
+ * renamed because of a merge conflict.
+ *
+ * The tag goes right after the family stem, e.g. DSCF1234.__READ_MERGE_CONFLICT__<tag>.RAF and
+ * C0001.__READ_MERGE_CONFLICT__<tag>M01.XML, so the renamed files still pair up.
+ */
+fn get_family_conflict_dest_file_path(
+    write_path: &str,
+    sub_path: &str,
+    tag: &str,
+    conflict_type: &str,
+) -> PathBuf {
+    let sub_path = Path::new(sub_path);
+    let file_name = sub_path.file_name().unwrap().to_str().unwrap();
+    let family_stem = common::family_stem(file_name);
+
+    let mut dest_path = PathBuf::from(write_path);
+    if let Some(parent) = sub_path.parent() {
+        dest_path.push(parent);
+    }
+    dest_path.push(format!(
+        "{}.__{}__{}{}",
+        family_stem,
+        conflict_type,
+        tag,
+        &file_name[family_stem.len()..]
+    ));
+
+    dest_path
+}
+
+/**
+ * Get the "maximum" merge result.
+ *
+ * Success is the "lowest" value, a merge conflict is in the middle, and an error is the "highest"
+ * value.
+ *
+ * This lets us ratchet up so we can remember the most serious error we've seen.
+ */
+pub fn max_merge_result(a: &MergeResult, b: &MergeResult) -> MergeResult {
+    if MergeResult::Error.eq(a) || MergeResult::Error.eq(b) {
+        return MergeResult::Error;
+    }
+
+    if MergeResult::Conflict.eq(a) || MergeResult::Conflict.eq(b) {
+        return MergeResult::Conflict;
+    }
+
+    MergeResult::Ok
+}
+
+/**
+ * Handle a file copy request for a copy thread.
+ */
+fn handle_copy(
+    thread_run_ctx: &ThreadRunContext,
+    copy_ctx: &CopyContext,
+    copy_file_req: CopyFileRequest,
+) -> MergeResult {
+    let log_info = copy_ctx.log_info;
+    let log_warn = copy_ctx.log_warn;
+    let copy_options = &copy_ctx.copy_options;
+    let progress = &copy_ctx.progress;
+    let source_copies = &copy_ctx.source_copies;
+    let ledger = copy_ctx.ledger.as_deref();
+    let sidecars = copy_ctx.sidecars.as_deref();
+    let write_path_index = copy_file_req.write_path_index;
+
+    // if the ledger says this file was already written to this write path in an earlier run,
+    // don't copy it again, even if it would go to a different sub path now
+    if let (Some(ledger), Some(hash)) = (ledger, &copy_file_req.src_hash) {
+        if ledger.contains(write_path_index, hash) {
+            // the pre-scan counted it, unless it found the same file already there
+            if copy_file_req.renamed || !copy_file_req.dest_path.exists() {
+                remove_from_totals(progress, write_path_index, &copy_file_req.src_path);
+            }
+            release_src_file(source_copies, &copy_file_req.src_path);
+
+            if copy_options.verbose {
+                let msg = format!(
+                    "{} (already ingested)",
+                    &copy_file_req.src_path.to_str().unwrap()
+                );
+                log_info(&msg);
+            }
+            return MergeResult::Ok;
+        }
+    }
+
+    // if the destination path already exists, don't copy the file again
+    // we are trusting that the destination file is correct, because if it was copied
+    // by this program last time, it would have been written atomically
+    if copy_file_req.dest_path.exists() {
+        // the pre-scan only counted it if it was renamed (it skips files that are already there)
+        if copy_file_req.renamed {
+            remove_from_totals(progress, write_path_index, &copy_file_req.src_path);
+        }
+        release_src_file(source_copies, &copy_file_req.src_path);
+        record_in_ledger(thread_run_ctx, log_warn, ledger, &copy_file_req);
+
+        // keep the provenance from when the file was first ingested
+        if !Sidecars::sidecar_path(&copy_file_req.dest_path).exists() {
+            return write_sidecar(log_warn, sidecars, &copy_file_req);
+        }
+        return MergeResult::Ok;
+    }
+
+    // get the destination directory
+    let dest_parent_path = copy_file_req.dest_path.parent();
+    if dest_parent_path.is_none() {
+        let err = format!(
+            "invalid destination path: '{}'",
             copy_file_req.dest_path.to_str().unwrap()
         );
         log_warn(&err);
         thread_run_ctx.unclean_shutdown();
-        return;
+        return MergeResult::Error;
     }
     let dest_parent_path = dest_parent_path.unwrap();
 
     // try to create the destination directory if it doesn't already exist
     if !dest_parent_path.exists() {
-        match fsutil::mkdir_p(&dest_parent_path) {
+        match fsutil::mkdir_p(dest_parent_path, copy_options.dir_mode) {
             Ok(_) => {}
             Err(e) => {
                 let err = format!(
@@ -717,11 +1452,49 @@ fn handle_copy(
                 );
                 log_warn(&err);
                 thread_run_ctx.unclean_shutdown();
-                return;
+                return MergeResult::Error;
             }
         }
     }
 
+    // with dedupe, link to the first copy of the same contents instead of copying
+    if let Some(link_path) = &copy_file_req.link_path {
+        return match link_copy(thread_run_ctx, link_path, &copy_file_req.dest_path) {
+            Ok(true) => {
+                if let Ok(metadata) = copy_file_req.dest_path.metadata() {
+                    progress.add_dest_bytes(write_path_index, fsutil::allocated_len(&metadata));
+                }
+                progress.add_dest_file(write_path_index);
+                release_src_file(source_copies, &copy_file_req.src_path);
+                record_in_ledger(thread_run_ctx, log_warn, ledger, &copy_file_req);
+                let sidecar_result = write_sidecar(log_warn, sidecars, &copy_file_req);
+
+                if copy_options.verbose {
+                    let msg = format!(
+                        "{} -> {} (link to {})",
+                        &copy_file_req.src_path.to_str().unwrap(),
+                        &copy_file_req.dest_path.to_str().unwrap(),
+                        &link_path.to_str().unwrap()
+                    );
+                    log_info(&msg);
+                }
+                sidecar_result
+            }
+            Ok(false) => MergeResult::Ok,
+            Err(e) => {
+                let err = format!(
+                    "error linking '{}' to '{}': '{}'",
+                    &copy_file_req.dest_path.to_str().unwrap(),
+                    &link_path.to_str().unwrap(),
+                    e
+                );
+                log_warn(&err);
+                thread_run_ctx.unclean_shutdown();
+                MergeResult::Error
+            }
+        };
+    }
+
     // create temp file to write into
     let mkstemp_result = fsutil::mkstemp(&dest_parent_path);
     if mkstemp_result.is_err() {
@@ -731,13 +1504,14 @@ fn handle_copy(
         );
         log_warn(&err);
         thread_run_ctx.unclean_shutdown();
-        return;
+        return MergeResult::Error;
     }
 
     // files are automatically closed when they go out of scope
     // the block below wraps around the parts of the code that operate on the temp file
     // while it still needs to be open
     let mut tmp_path_buf = PathBuf::new();
+    let copy_method;
     {
         let (tmp_file, path_buf) = mkstemp_result.unwrap();
         tmp_path_buf.push(path_buf);
@@ -745,20 +1519,77 @@ fn handle_copy(
         // open source file for reading
         match File::open(&copy_file_req.src_path) {
             // copy the source file to the tmp destination file
-            Ok(src_file) => match fsutil::copy_file(&src_file, &tmp_file) {
-                Ok(_) => {}
-                Err(e) => {
+            Ok(src_file) => {
+                fsutil::advise_sequential(&src_file);
+
+                // allocate space for the whole file up front, so a full disk fails right away
+                if let Err(e) = fsutil::preallocate(&src_file, &tmp_file) {
                     let err = format!(
-                        "error copying '{}' to '{}': '{}'",
+                        "error allocating space for '{}' in '{}': '{}'",
                         &copy_file_req.src_path.to_str().unwrap(),
-                        &copy_file_req.dest_path.to_str().unwrap(),
+                        &tmp_path_buf.to_str().unwrap(),
                         e
                     );
                     log_warn(&err);
                     thread_run_ctx.unclean_shutdown();
-                    return;
+                    remove_tmp_file(log_warn, &tmp_path_buf);
+                    return MergeResult::Error;
                 }
-            },
+
+                let mut copied = 0;
+                match fsutil::copy_file(&src_file, &tmp_file, copy_options.buf_size, &mut |n| {
+                    progress.add_src_bytes(&copy_file_req.src_path, n);
+                    progress.add_dest_bytes(write_path_index, n);
+                    copied += n;
+
+                    // stop in the middle of a large file if we've been cancelled
+                    if thread_run_ctx.is_cancelled() {
+                        return common::dit_error("cancelled");
+                    }
+                    Ok(())
+                }) {
+                    Ok(method) => copy_method = method,
+                    Err(_) if thread_run_ctx.is_cancelled() => {
+                        remove_tmp_file(log_warn, &tmp_path_buf);
+                        return MergeResult::Ok;
+                    }
+                    Err(e) => {
+                        let err = format!(
+                            "error copying '{}' to '{}': '{}'",
+                            &copy_file_req.src_path.to_str().unwrap(),
+                            &copy_file_req.dest_path.to_str().unwrap(),
+                            e
+                        );
+                        log_warn(&err);
+                        thread_run_ctx.unclean_shutdown();
+                        remove_tmp_file(log_warn, &tmp_path_buf);
+                        return MergeResult::Error;
+                    }
+                }
+
+                // only the data in a sparse file was copied, but a filesystem without holes
+                // writes out the rest as zeros, which the totals count (see Progress::dest_len())
+                if fsutil::CopyMethod::Sparse == copy_method
+                    && !progress.dest_keeps_holes(write_path_index)
+                {
+                    let len = src_file.metadata().map(|m| m.len()).unwrap_or(copied);
+                    progress.add_dest_bytes(write_path_index, len.saturating_sub(copied));
+                }
+
+                // nothing reads the destination file again, so don't let it fill up the page
+                // cache (this waits for its data to be written, which can't be dropped before)
+                if let Err(e) = fsutil::flush_cached_pages(&tmp_file) {
+                    let err = format!(
+                        "error writing '{}': '{}'",
+                        &tmp_path_buf.to_str().unwrap(),
+                        e
+                    );
+                    log_warn(&err);
+                    thread_run_ctx.unclean_shutdown();
+                    remove_tmp_file(log_warn, &tmp_path_buf);
+                    return MergeResult::Error;
+                }
+            }
             Err(e) => {
                 let err = format!(
                     "error opening source file for reading: '{}': {}",
@@ -770,22 +1601,45 @@ fn handle_copy(
                 thread_run_ctx.unclean_shutdown();
 
                 // if we had an error while the temp file was open, try to remove it
-                match fs::remove_file(&tmp_path_buf) {
-                    Ok(_) => {}
-                    Err(_) => {
-                        let err = format!(
-                            "error removing temp file: '{}'",
-                            &tmp_path_buf.to_str().unwrap()
-                        );
-                        log_warn(&err);
-                    }
-                }
+                remove_tmp_file(log_warn, &tmp_path_buf);
+
+                return MergeResult::Error;
+            }
+        }
+    }
+
+    // if we were cancelled while the copy was in flight, roll it back instead of finishing it
+    if thread_run_ctx.is_cancelled() {
+        remove_tmp_file(log_warn, &tmp_path_buf);
+        return MergeResult::Ok;
+    }
 
-                return;
+    // read the copy back from the destination device, and make sure it matches the source
+    if copy_options.verify {
+        match verify_copy(
+            copy_options,
+            source_copies,
+            &copy_file_req.src_path,
+            &tmp_path_buf,
+        ) {
+            Ok(_) => {}
+            Err(e) => {
+                let err = format!(
+                    "error verifying '{}' -> '{}': {}",
+                    &copy_file_req.src_path.to_str().unwrap(),
+                    &copy_file_req.dest_path.to_str().unwrap(),
+                    e
+                );
+                log_warn(&err);
+                thread_run_ctx.unclean_shutdown();
+                remove_tmp_file(log_warn, &tmp_path_buf);
+                return MergeResult::Error;
             }
         }
     }
 
+    release_src_file(source_copies, &copy_file_req.src_path);
+
     // copy file time metadata from the source file to the dest file
     match fsutil::copy_file_time_metadata(&copy_file_req.src_path, &tmp_path_buf.as_path()) {
         Ok(_) => {}
@@ -798,12 +1652,58 @@ fn handle_copy(
             );
             log_warn(&err);
             thread_run_ctx.unclean_shutdown();
-            return;
+            remove_tmp_file(log_warn, &tmp_path_buf);
+            return MergeResult::Error;
         }
     }
 
-    // chmod the destination file 0644
-    match fsutil::chmod(&tmp_path_buf.as_path()) {
+    // get the source file metadata, for the attributes we might need to preserve
+    let src_metadata = match fs::metadata(&copy_file_req.src_path) {
+        Ok(src_metadata) => src_metadata,
+        Err(e) => {
+            let err = format!(
+                "error reading file metadata from '{}': '{}'",
+                &copy_file_req.src_path.to_str().unwrap(),
+                e
+            );
+            log_warn(&err);
+            thread_run_ctx.unclean_shutdown();
+            remove_tmp_file(log_warn, &tmp_path_buf);
+            return MergeResult::Error;
+        }
+    };
+
+    // chown the destination file to match the source file (only root can do this)
+    // this has to happen before chmod, because chown clears the setuid and setgid bits
+    let preserve_owner = copy_options.preserve.owner && nix::unistd::geteuid().is_root();
+    if preserve_owner {
+        match fsutil::chown(&tmp_path_buf, src_metadata.uid(), src_metadata.gid()) {
+            Ok(_) => {}
+            Err(e) => {
+                let err = format!(
+                    "error changing file owner on '{}': '{}'",
+                    &tmp_path_buf.to_str().unwrap(),
+                    e
+                );
+                log_warn(&err);
+                thread_run_ctx.unclean_shutdown();
+                remove_tmp_file(log_warn, &tmp_path_buf);
+                return MergeResult::Error;
+            }
+        }
+    }
+
+    // chmod the destination file 0644, to the configured file mode, or to match the source file
+    // the setuid, setgid and sticky bits are only kept from the source if the owner is kept too
+    let chmod_result = match copy_options.file_mode {
+        Some(file_mode) => fsutil::chmod_verified(&tmp_path_buf, file_mode),
+        None if copy_options.preserve.mode => {
+            let mask = if preserve_owner { 0o7777 } else { 0o777 };
+            fsutil::chmod(&tmp_path_buf, src_metadata.mode() & mask)
+        }
+        None => fsutil::chmod(&tmp_path_buf, 0o644),
+    };
+    match chmod_result {
         Ok(_) => {}
         Err(e) => {
             let err = format!(
@@ -813,7 +1713,55 @@ fn handle_copy(
             );
             log_warn(&err);
             thread_run_ctx.unclean_shutdown();
-            return;
+            remove_tmp_file(log_warn, &tmp_path_buf);
+            return MergeResult::Error;
+        }
+    }
+
+    // copy extended attributes and ACLs
+    // this has to happen after chmod, because setting an ACL also sets the permission bits
+    let mut xattr_prefixes = vec![];
+    if copy_options.preserve.xattr {
+        xattr_prefixes.push("user.");
+
+        // only root can set security.* attributes, so for anyone else every one of them would
+        // be skipped with a warning
+        if nix::unistd::geteuid().is_root() {
+            xattr_prefixes.push("security.");
+        }
+    }
+    if copy_options.preserve.acl {
+        xattr_prefixes.push("system.posix_acl_access");
+    }
+    let mut copy_result = MergeResult::Ok;
+    if !xattr_prefixes.is_empty() {
+        match fsutil::copy_xattrs(&copy_file_req.src_path, &tmp_path_buf, &xattr_prefixes) {
+            Ok(skipped) => {
+                // the destination filesystem doesn't support them (like exFAT), or doesn't let
+                // us set them, which isn't worth stopping the whole run for
+                for (name, e) in skipped {
+                    let err = format!(
+                        "could not copy extended attribute '{}' to '{}' (skipped): {}",
+                        name,
+                        &copy_file_req.dest_path.to_str().unwrap(),
+                        e
+                    );
+                    log_warn(&err);
+                    copy_result = MergeResult::Conflict;
+                }
+            }
+            Err(e) => {
+                let err = format!(
+                    "error copying extended attributes from '{}' to '{}': '{}'",
+                    &copy_file_req.src_path.to_str().unwrap(),
+                    &tmp_path_buf.to_str().unwrap(),
+                    e
+                );
+                log_warn(&err);
+                thread_run_ctx.unclean_shutdown();
+                remove_tmp_file(log_warn, &tmp_path_buf);
+                return MergeResult::Error;
+            }
         }
     }
 
@@ -829,7 +1777,203 @@ fn handle_copy(
             );
             log_warn(&err);
             thread_run_ctx.unclean_shutdown();
-            return;
+            remove_tmp_file(log_warn, &tmp_path_buf);
+            return MergeResult::Error;
         }
     }
+
+    progress.add_dest_file(write_path_index);
+    record_in_ledger(thread_run_ctx, log_warn, ledger, &copy_file_req);
+    let sidecar_result = write_sidecar(log_warn, sidecars, &copy_file_req);
+    copy_result = max_merge_result(&copy_result, &sidecar_result);
+
+    if copy_options.verbose {
+        let verified = if copy_options.verify {
+            ", verified"
+        } else {
+            ""
+        };
+        let msg = format!(
+            "{} -> {} ({}{})",
+            &copy_file_req.src_path.to_str().unwrap(),
+            &copy_file_req.dest_path.to_str().unwrap(),
+            copy_method,
+            verified
+        );
+        log_info(&msg);
+    }
+
+    copy_result
+}
+
+/**
+ * Add a file that is now in a write path to that write path's ledger, if we're keeping one.
+ */
+fn record_in_ledger(
+    thread_run_ctx: &ThreadRunContext,
+    log_warn: fn(&str),
+    ledger: Option<&Ledger>,
+    copy_file_req: &CopyFileRequest,
+) {
+    let ledger = match ledger {
+        Some(ledger) => ledger,
+        None => return,
+    };
+
+    let result = match &copy_file_req.src_hash {
+        Some(hash) => ledger.record(
+            copy_file_req.write_path_index,
+            hash,
+            &copy_file_req.src_path,
+            &copy_file_req.dest_path,
+        ),
+        None => fsutil::hash_file(&copy_file_req.src_path).and_then(|hash| {
+            ledger.record(
+                copy_file_req.write_path_index,
+                &hash,
+                &copy_file_req.src_path,
+                &copy_file_req.dest_path,
+            )
+        }),
+    };
+
+    if let Err(e) = result {
+        let err = format!(
+            "error adding '{}' to the ledger: '{}'",
+            &copy_file_req.dest_path.to_str().unwrap(),
+            e
+        );
+        log_warn(&err);
+        thread_run_ctx.unclean_shutdown();
+    }
+}
+
+/**
+ * Write or update the XMP sidecar for a file that is now in a write path, if we're writing them.
+ *
+ * A sidecar that can't be written is only a warning, since the file itself was copied, and
+ * returns MergeResult::Conflict.
+ */
+fn write_sidecar(
+    log_warn: fn(&str),
+    sidecars: Option<&Sidecars>,
+    copy_file_req: &CopyFileRequest,
+) -> MergeResult {
+    let sidecars = match sidecars {
+        Some(sidecars) => sidecars,
+        None => return MergeResult::Ok,
+    };
+
+    let result = match &copy_file_req.src_hash {
+        Some(hash) => sidecars.write(&copy_file_req.src_path, &copy_file_req.dest_path, hash),
+        None => fsutil::hash_file(&copy_file_req.src_path).and_then(|hash| {
+            sidecars.write(&copy_file_req.src_path, &copy_file_req.dest_path, &hash)
+        }),
+    };
+
+    if let Err(e) = result {
+        let err = format!(
+            "error writing the XMP sidecar for '{}': {}",
+            &copy_file_req.dest_path.to_str().unwrap(),
+            e
+        );
+        log_warn(&err);
+        return MergeResult::Conflict;
+    }
+
+    MergeResult::Ok
+}
+
+/**
+ * Hard link a destination file to an earlier copy of the same contents, for a copy thread.
+ *
+ * The earlier copy was sent to the copy threads for this device first, but another copy thread
+ * can still be writing it, so this waits for it to be renamed into place.
+ *
+ * Returns false if it stopped waiting, because the earlier copy failed or we've been cancelled.
+ */
+fn link_copy(
+    thread_run_ctx: &ThreadRunContext,
+    link_path: &Path,
+    dest_path: &Path,
+) -> Result<bool, Box<dyn Error>> {
+    while !link_path.exists() {
+        if !thread_run_ctx.is_clean() {
+            return Ok(false);
+        }
+        thread::sleep(RECV_TIMEOUT);
+    }
+
+    fs::hard_link(link_path, dest_path)?;
+    Ok(true)
+}
+
+/**
+ * Check that a copied file has the same hash as its source file, reading the copy back from the
+ * destination device rather than the page cache.
+ */
+fn verify_copy(
+    copy_options: &CopyOptions,
+    source_copies: &SourceCopies,
+    src_path: &Path,
+    tmp_path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let src_hash = source_copies.src_hash(src_path, copy_options.buf_size)?;
+    let dest_hash = fsutil::hash_file_uncached(tmp_path, copy_options.buf_size)?;
+
+    if src_hash != dest_hash {
+        let err_msg = format!("hash mismatch: {} != {}", src_hash, dest_hash);
+        return common::dit_error(&err_msg);
+    }
+
+    Ok(())
+}
+
+/**
+ * Take a source file that won't be copied out of the progress totals for a write path, as it
+ * was counted by the pre-scan (see discover::scan()).
+ */
+fn remove_from_totals(progress: &Progress, write_path_index: usize, src_path: &Path) {
+    if let Ok(metadata) = src_path.metadata() {
+        progress.remove_dest_file(
+            write_path_index,
+            progress.dest_len(write_path_index, &metadata),
+        );
+    }
+}
+
+/**
+ * Record that a source file has been copied to one more write path, and drop it from the page
+ * cache once it has been copied to all of them.
+ */
+fn release_src_file(source_copies: &SourceCopies, src_path: &Path) {
+    if source_copies.copied(src_path) {
+        if let Ok(src_file) = File::open(src_path) {
+            fsutil::drop_cached_pages(&src_file);
+        }
+    }
+}
+
+/**
+ * Drop a hashed source file that won't be copied from the page cache, since no copy thread will
+ * release it.
+ */
+fn drop_src_file(src_path: &Path) {
+    if let Ok(src_file) = File::open(src_path) {
+        fsutil::drop_cached_pages(&src_file);
+    }
+}
+
+/**
+ * Try to remove a temp file left behind by a failed or cancelled copy.
+ */
+fn remove_tmp_file(log_warn: fn(&str), tmp_path: &Path) {
+    if let Err(e) = fs::remove_file(tmp_path) {
+        let err = format!(
+            "error removing temp file: '{}': '{}'",
+            tmp_path.to_str().unwrap(),
+            e
+        );
+        log_warn(&err);
+    }
 }
@@ -0,0 +1,264 @@
+use nix::sys::stat::{major, minor};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+// udev's symlinks to block devices, named by filesystem label and UUID
+const DEV_DISK_BY_LABEL: &str = "/dev/disk/by-label";
+const DEV_DISK_BY_UUID: &str = "/dev/disk/by-uuid";
+
+// mounted filesystems, with their device numbers
+const PROC_SELF_MOUNTINFO: &str = "/proc/self/mountinfo";
+
+// sysfs entries for block devices, named by device number
+const SYS_DEV_BLOCK: &str = "/sys/dev/block";
+
+// top of the sysfs device tree
+const SYS_DEVICES: &str = "/sys/devices";
+
+// fills in {card} for volumes without a label or UUID
+pub const UNKNOWN_CARD: &str = "unknown";
+
+/**
+ * Identifies the card or volume that a read path is on, as far as the system lets us see it.
+ *
+ * Everything is optional, since it depends on the filesystem (e.g. tmpfs has no label or UUID),
+ * the device (card readers don't always pass on a serial number), and on udev and sysfs being
+ * there at all.
+ */
+#[derive(Clone, Default)]
+pub struct Volume {
+    // filesystem label (e.g. "NIKON D750", or "EOS_DIGITAL")
+    pub label: Option<String>,
+
+    // filesystem UUID (for FAT and exFAT, the volume serial number set when the card is formatted)
+    pub uuid: Option<String>,
+
+    // mount source (e.g. "/dev/sdb1")
+    pub device: Option<String>,
+
+    // serial number of the card, or of the reader or drive it is in
+    pub serial: Option<String>,
+}
+
+impl Volume {
+    /**
+     * Look up the volume that the given path is on.
+     *
+     * Reads /proc/self/mountinfo for the mount source, udev's /dev/disk/by-label and
+     * /dev/disk/by-uuid for the label and UUID, and sysfs for the serial number. Anything that
+     * can't be read is left out.
+     */
+    pub fn of(path: &Path) -> Volume {
+        let dev = match fs::metadata(path) {
+            Ok(metadata) => metadata.dev(),
+            Err(_) => return Volume::default(),
+        };
+
+        Volume {
+            label: find_dev_link(DEV_DISK_BY_LABEL, dev),
+            uuid: find_dev_link(DEV_DISK_BY_UUID, dev),
+            device: find_mount_source(path, dev),
+            serial: find_serial(dev),
+        }
+    }
+
+    /**
+     * Short name for the volume, for the {card} layout token: the label, or else the UUID.
+     */
+    pub fn card_name(&self) -> &str {
+        match (&self.label, &self.uuid) {
+            (Some(label), _) => label,
+            (None, Some(uuid)) => uuid,
+            (None, None) => UNKNOWN_CARD,
+        }
+    }
+}
+
+impl Display for Volume {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut details = vec![];
+        if let Some(uuid) = &self.uuid {
+            details.push(format!("UUID {}", uuid));
+        }
+        if let Some(device) = &self.device {
+            details.push(device.clone());
+        }
+        if let Some(serial) = &self.serial {
+            details.push(format!("serial {}", serial));
+        }
+
+        match &self.label {
+            Some(label) => write!(f, "'{}'", label)?,
+            None => write!(f, "unlabeled volume")?,
+        }
+        if !details.is_empty() {
+            write!(f, " ({})", details.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/**
+ * Get the mount points of the filesystems that are mounted from a device node (e.g. "/dev/sdb1",
+ * or "/dev/loop0" for a mounted image), like cards, drives and partitions, leaving out tmpfs,
+ * proc, network filesystems and the like.
+ */
+pub fn device_mount_points() -> Vec<PathBuf> {
+    let mountinfo = match fs::read_to_string(PROC_SELF_MOUNTINFO) {
+        Ok(mountinfo) => mountinfo,
+        Err(_) => return vec![],
+    };
+
+    let mut mount_points = vec![];
+    for line in mountinfo.lines() {
+        let (mount, fs) = match line.split_once(" - ") {
+            Some(fields) => fields,
+            None => continue,
+        };
+        let mount: Vec<&str> = mount.split(' ').collect();
+        let fs: Vec<&str> = fs.split(' ').collect();
+        if mount.len() < 5 || fs.len() < 2 || !fs[1].starts_with("/dev/") {
+            continue;
+        }
+
+        mount_points.push(PathBuf::from(unescape_mountinfo(mount[4])));
+    }
+
+    mount_points
+}
+
+/**
+ * Find the entry in a udev /dev/disk/by-* directory that links to the block device with the given
+ * device number, and return its name.
+ */
+fn find_dev_link(dir: &str, dev: u64) -> Option<String> {
+    let mut names = vec![];
+    for dirent in fs::read_dir(dir).ok()? {
+        let dirent = dirent.ok()?;
+
+        // follows the symlink to the device node
+        match fs::metadata(dirent.path()) {
+            Ok(metadata) if dev == metadata.rdev() => {}
+            _ => continue,
+        }
+
+        if let Some(name) = dirent.file_name().to_str() {
+            names.push(unescape_udev(name));
+        }
+    }
+
+    names.sort();
+    names.into_iter().next()
+}
+
+/**
+ * Find the mount source (usually a device node) of the filesystem with the given device number,
+ * preferring the mount that the path is under, when the filesystem is mounted more than once.
+ */
+fn find_mount_source(path: &Path, dev: u64) -> Option<String> {
+    let mountinfo = fs::read_to_string(PROC_SELF_MOUNTINFO).ok()?;
+    let path = fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    let dev_str = format!("{}:{}", major(dev), minor(dev));
+
+    let mut source = None;
+    let mut longest_mount_point = 0;
+    for line in mountinfo.lines() {
+        // e.g. "36 35 8:17 / /media/sd rw,nosuid shared:1 - exfat /dev/sdb1 rw,..."
+        let (mount, fs) = match line.split_once(" - ") {
+            Some(fields) => fields,
+            None => continue,
+        };
+        let mount: Vec<&str> = mount.split(' ').collect();
+        let fs: Vec<&str> = fs.split(' ').collect();
+        if mount.len() < 5 || fs.len() < 2 || dev_str != mount[2] {
+            continue;
+        }
+
+        let mount_point = unescape_mountinfo(mount[4]);
+        if path.starts_with(&mount_point) && mount_point.len() >= longest_mount_point {
+            longest_mount_point = mount_point.len();
+            source = Some(unescape_mountinfo(fs[1]));
+        } else if source.is_none() {
+            source = Some(unescape_mountinfo(fs[1]));
+        }
+    }
+
+    source
+}
+
+/**
+ * Find the serial number of the block device with the given device number.
+ *
+ * The serial is kept by whichever device above the partition in sysfs knows it: an SD card
+ * itself (on a built-in reader), a USB reader or drive, or an NVMe drive. The nearest one wins.
+ */
+fn find_serial(dev: u64) -> Option<String> {
+    let sys_path = format!("{}/{}:{}", SYS_DEV_BLOCK, major(dev), minor(dev));
+    let mut dir = fs::canonicalize(sys_path).ok()?;
+
+    while dir.starts_with(SYS_DEVICES) {
+        let mut serial_path = dir.clone();
+        serial_path.push("serial");
+        if let Ok(serial) = fs::read_to_string(&serial_path) {
+            let serial = serial.trim();
+            if !serial.is_empty() {
+                return Some(String::from(serial));
+            }
+        }
+
+        if !dir.pop() {
+            break;
+        }
+    }
+
+    None
+}
+
+/**
+ * Undo udev's escaping of names in /dev/disk/by-label (e.g. "NIKON\x20D750").
+ */
+fn unescape_udev(s: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while !rest.is_empty() {
+        if rest.len() >= 4 && rest.starts_with(b"\\x") {
+            if let Ok(hex) = std::str::from_utf8(&rest[2..4]) {
+                if let Ok(b) = u8::from_str_radix(hex, 16) {
+                    bytes.push(b);
+                    rest = &rest[4..];
+                    continue;
+                }
+            }
+        }
+        bytes.push(rest[0]);
+        rest = &rest[1..];
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/**
+ * Undo the kernel's escaping of paths in /proc/self/mountinfo (e.g. "/media/NIKON\040D750").
+ */
+fn unescape_mountinfo(s: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while !rest.is_empty() {
+        if rest.len() >= 4 && b'\\' == rest[0] {
+            if let Ok(octal) = std::str::from_utf8(&rest[1..4]) {
+                if let Ok(b) = u8::from_str_radix(octal, 8) {
+                    bytes.push(b);
+                    rest = &rest[4..];
+                    continue;
+                }
+            }
+        }
+        bytes.push(rest[0]);
+        rest = &rest[1..];
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,682 @@
+use crate::api;
+use crate::common::{dit_error, CancelHandle, CopyOptions, MergeResult};
+use crate::layout;
+use crate::progress::ProgressReport;
+use crate::threads;
+use crate::volume;
+use crate::volume::Volume;
+use crate::ReadWritePaths;
+use std::collections::{BTreeSet, HashSet};
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::iter::Peekable;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+use std::thread;
+use std::time::Duration;
+
+// how often to look for new volumes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// what a volume needs to have to be copied, when the profile doesn't say
+const DEFAULT_MATCH: &str = "DCIM";
+
+/**
+ * Settings for watch mode, read from a profile file (see Profile::load()).
+ */
+pub struct Profile {
+    // where to copy each new volume
+    pub write_paths: Vec<PathBuf>,
+
+    // options for each copy, given the same way as on the command line
+    pub copy_options: CopyOptions,
+
+    // directories where volumes show up as new subdirectories (e.g. "/media/alice"), besides
+    // the mount points of new mounts
+    pub watch_dirs: Vec<PathBuf>,
+
+    // a new volume is copied if it has any of these paths (e.g. "DCIM")
+    pub match_paths: Vec<PathBuf>,
+}
+
+impl Profile {
+    /**
+     * Read a profile, which is a small subset of TOML: keys at the top level (no tables), each
+     * set to a string or to an array of strings, with # comments. For example:
+     *
+     * ```toml
+     * write = ["/mnt/ssd1/trip", "/mnt/ssd2/trip"]
+     * options = ["--layout={card}/{year}-{month}-{day}/{filename}", "--ledger", "--check"]
+     * watch = "/media/$USER"
+     * match = ["DCIM", "PRIVATE/M4ROOT"]
+     * ```
+     *
+     * Environment variables like $USER (or ${USER}) are expanded in the write and watch paths.
+     * Only write is required.
+     */
+    pub fn load(path: &Path) -> Result<Profile, Box<dyn Error>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => return profile_error(path, &e.to_string()),
+        };
+        let entries = match ProfileParser::new(&contents).parse() {
+            Ok(entries) => entries,
+            Err(e) => return profile_error(path, &e.to_string()),
+        };
+
+        let mut write_paths = vec![];
+        let mut options = vec![];
+        let mut watch_dirs = vec![];
+        let mut match_paths = vec![];
+        for (key, values) in entries {
+            match key.as_str() {
+                "write" => {
+                    for value in values {
+                        write_paths.push(PathBuf::from(expand_env(path, &value)?));
+                    }
+                }
+                "options" => {
+                    for value in values {
+                        if !value.starts_with("--") {
+                            let reason = format!("not an option: '{}'", value);
+                            return profile_error(path, &reason);
+                        }
+                        options.push(value);
+                    }
+                }
+                "watch" => {
+                    for value in values {
+                        watch_dirs.push(PathBuf::from(expand_env(path, &value)?));
+                    }
+                }
+                "match" => {
+                    for value in values {
+                        if value.is_empty() || value.starts_with('/') {
+                            let reason = format!("match paths must be relative: '{}'", value);
+                            return profile_error(path, &reason);
+                        }
+                        match_paths.push(PathBuf::from(value));
+                    }
+                }
+                _ => {
+                    let reason = format!("unknown key: '{}'", key);
+                    return profile_error(path, &reason);
+                }
+            }
+        }
+
+        if write_paths.is_empty() {
+            return profile_error(path, "must have at least one write path");
+        }
+        if match_paths.is_empty() {
+            match_paths.push(PathBuf::from(DEFAULT_MATCH));
+        }
+
+        let copy_options = match api::get_cli_copy_options(&options) {
+            Ok(copy_options) => copy_options,
+            Err(e) => return profile_error(path, &e.to_string()),
+        };
+
+        Ok(Profile {
+            write_paths,
+            copy_options,
+            watch_dirs,
+            match_paths,
+        })
+    }
+
+    /**
+     * Does the volume at the given path have any of the match paths?
+     */
+    fn matches(&self, volume_path: &Path) -> bool {
+        self.match_paths
+            .iter()
+            .any(|p| volume_path.join(p).exists())
+    }
+
+    /**
+     * Find the write path that the volume at the given path is inside of, or that is inside of it,
+     * if there is one. Copying it would copy the write path into itself.
+     */
+    fn overlapping_write_path(&self, volume_path: &Path) -> Option<&PathBuf> {
+        self.write_paths
+            .iter()
+            .find(|p| p.starts_with(volume_path) || volume_path.starts_with(p))
+    }
+}
+
+/**
+ * Watch for new volumes, and copy each one that matches the profile to the profile's write paths,
+ * one at a time, until cancelled.
+ *
+ * A new volume is a filesystem that is newly mounted from a device (see
+ * volume::device_mount_points()), or a new subdirectory of one of the profile's watch
+ * directories, which is how desktops show mounted cards (e.g. "/media/alice/NIKON D750"), and
+ * also lets a plain directory stand in for a card. Volumes that are already there when watching
+ * starts are left alone. A volume that goes away and comes back is copied again (with --ledger,
+ * files that were already copied are skipped).
+ *
+ * Volumes are looked for every second, so a directory that is being copied into a watch
+ * directory (instead of being moved there) could be copied before it is complete.
+ *
+ * Returns the most serious MergeResult of all the copies, or MergeResult::Cancelled if a copy
+ * was cancelled.
+ */
+pub fn watch(
+    log_info: fn(&str),
+    log_warn: fn(&str),
+    log_progress: fn(&ProgressReport),
+    profile: &Profile,
+    cancel_handle: &CancelHandle,
+) -> Result<MergeResult, Box<dyn Error>> {
+    let match_paths: Vec<&str> = profile
+        .match_paths
+        .iter()
+        .map(|p| p.to_str().unwrap())
+        .collect();
+    let mut watching = vec![String::from("new mounts")];
+    for watch_dir in &profile.watch_dirs {
+        watching.push(format!("'{}'", watch_dir.to_str().unwrap()));
+    }
+    log_info(&format!(
+        "watching {} for volumes with {}",
+        watching.join(", "),
+        match_paths.join(" or ")
+    ));
+
+    // volumes that have been seen (and copied, or skipped) while they've been there
+    let mut seen = HashSet::new();
+    for volume_path in find_volumes(profile) {
+        if profile.matches(&volume_path) {
+            log_info(&format!(
+                "not copying '{}', it was there before watching started",
+                volume_path.to_str().unwrap()
+            ));
+            seen.insert(volume_path);
+        }
+    }
+
+    let mut result = MergeResult::Ok;
+    while !cancel_handle.is_cancelled() {
+        let volume_paths = find_volumes(profile);
+
+        // forget volumes that have gone away, so they're copied again if they come back
+        seen.retain(|p| volume_paths.contains(p));
+
+        for volume_path in volume_paths {
+            if seen.contains(&volume_path) || !profile.matches(&volume_path) {
+                continue;
+            }
+            seen.insert(volume_path.clone());
+
+            if let Some(write_path) = profile.overlapping_write_path(&volume_path) {
+                log_warn(&format!(
+                    "not copying '{}', it overlaps write path '{}'",
+                    volume_path.to_str().unwrap(),
+                    write_path.to_str().unwrap()
+                ));
+                continue;
+            }
+
+            let copy_result = copy_volume(
+                log_info,
+                log_warn,
+                log_progress,
+                profile,
+                &volume_path,
+                cancel_handle,
+            );
+            if MergeResult::Cancelled == copy_result {
+                return Ok(MergeResult::Cancelled);
+            }
+            result = threads::max_merge_result(&result, &copy_result);
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    Ok(result)
+}
+
+/**
+ * Copy a new volume to the profile's write paths, and log how it went.
+ *
+ * Without a layout, the volume is copied into a directory of its own in each write path (see
+ * volume_dir_name()), so files with the same names on different cards don't conflict, and a card
+ * that is copied again goes in the same place.
+ */
+fn copy_volume(
+    log_info: fn(&str),
+    log_warn: fn(&str),
+    log_progress: fn(&ProgressReport),
+    profile: &Profile,
+    volume_path: &Path,
+    cancel_handle: &CancelHandle,
+) -> MergeResult {
+    let volume_path_str = volume_path.to_str().unwrap();
+    log_info(&format!("copying new volume '{}'", volume_path_str));
+
+    let read_volume = Volume::of(volume_path);
+    let mut write_paths = profile.write_paths.clone();
+    if profile.copy_options.layout.is_none() {
+        let volume_dir = volume_dir_name(volume_path, &read_volume);
+        write_paths = write_paths.iter().map(|p| p.join(&volume_dir)).collect();
+    }
+
+    let read_write_paths = ReadWritePaths {
+        read_paths: vec![volume_path.to_path_buf()],
+        write_paths,
+        read_volumes: vec![read_volume],
+    };
+    let result = match api::copy(
+        log_info,
+        log_warn,
+        log_progress,
+        &read_write_paths,
+        &profile.copy_options,
+        cancel_handle,
+    ) {
+        Ok(result) => result,
+        Err(e) => {
+            log_warn(&format!("error copying '{}': {}", volume_path_str, e));
+            MergeResult::Error
+        }
+    };
+
+    match result {
+        MergeResult::Ok => {
+            log_info(&format!("finished copying '{}'", volume_path_str));
+        }
+        MergeResult::Conflict => {
+            log_warn(&format!(
+                "finished copying '{}', with merge conflicts, damaged files or incomplete clips",
+                volume_path_str
+            ));
+        }
+        MergeResult::Error => {
+            log_warn(&format!("could not finish copying '{}'", volume_path_str));
+        }
+        MergeResult::Cancelled => {
+            log_warn(&format!("cancelled copying '{}'", volume_path_str));
+        }
+    }
+
+    result
+}
+
+/**
+ * Get the name of the directory that a volume is copied into without a layout: the name of the
+ * directory it is mounted on (which desktops name after its label, or its UUID), then its UUID, if
+ * it has one that isn't the name already. Cards from the same camera maker often have the same
+ * label, but a FAT or exFAT card gets a new UUID each time it is formatted.
+ */
+fn volume_dir_name(volume_path: &Path, volume: &Volume) -> String {
+    let name = match volume_path.file_name() {
+        Some(name) => name.to_str().unwrap(),
+        None => volume.card_name(),
+    };
+    let name = match &volume.uuid {
+        Some(uuid) if uuid != name => format!("{}-{}", name, uuid),
+        _ => String::from(name),
+    };
+
+    layout::path_safe(&name, volume::UNKNOWN_CARD)
+}
+
+/**
+ * Find the volumes that are there right now: the mount points of filesystems mounted from a
+ * device, and the subdirectories of each watch directory (skipping hidden ones). Watch
+ * directories that don't exist (yet) are skipped.
+ */
+fn find_volumes(profile: &Profile) -> BTreeSet<PathBuf> {
+    let mut volume_paths: BTreeSet<PathBuf> = volume::device_mount_points().into_iter().collect();
+
+    for watch_dir in &profile.watch_dirs {
+        let dirents = match fs::read_dir(watch_dir) {
+            Ok(dirents) => dirents,
+            Err(_) => continue,
+        };
+        for dirent in dirents.flatten() {
+            if dirent.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = dirent.path();
+            if path.is_dir() {
+                volume_paths.insert(path);
+            }
+        }
+    }
+
+    volume_paths
+}
+
+/**
+ * Expand environment variables ($NAME or ${NAME}) in a profile value.
+ */
+fn expand_env(profile_path: &Path, s: &str) -> Result<String, Box<dyn Error>> {
+    let mut expanded = String::new();
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        rest = &rest[start + 1..];
+
+        let (name, len) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], end + 2),
+                None => {
+                    let reason = format!("unterminated ${{ in '{}'", s);
+                    return profile_error(profile_path, &reason);
+                }
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric() && '_' != c)
+                    .unwrap_or(rest.len());
+                (&rest[..end], end)
+            }
+        };
+        match env::var(name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_) => {
+                let reason = format!("environment variable is not set: '{}'", name);
+                return profile_error(profile_path, &reason);
+            }
+        }
+        rest = &rest[len..];
+    }
+    expanded.push_str(rest);
+
+    Ok(expanded)
+}
+
+fn profile_error<T>(profile_path: &Path, reason: &str) -> Result<T, Box<dyn Error>> {
+    let err_msg = format!(
+        "error reading profile '{}': {}",
+        profile_path.to_str().unwrap(),
+        reason
+    );
+    dit_error(&err_msg)
+}
+
+// a key in a profile, and its values
+type ProfileEntry = (String, Vec<String>);
+
+/**
+ * Parses the subset of TOML used for profiles (see Profile::load()) into (key, values) pairs,
+ * where a single string is returned as one value.
+ */
+struct ProfileParser<'a> {
+    chars: Peekable<Chars<'a>>,
+
+    // current line, for error messages
+    line: usize,
+}
+
+impl<'a> ProfileParser<'a> {
+    fn new(contents: &'a str) -> ProfileParser<'a> {
+        ProfileParser {
+            chars: contents.chars().peekable(),
+            line: 1,
+        }
+    }
+
+    fn parse(&mut self) -> Result<Vec<ProfileEntry>, Box<dyn Error>> {
+        let mut entries: Vec<ProfileEntry> = vec![];
+
+        loop {
+            self.skip_space(true);
+            match self.chars.peek() {
+                None => break,
+                Some('[') => return self.error("tables are not supported"),
+                _ => {}
+            }
+
+            let key = self.parse_key()?;
+            if entries.iter().any(|(k, _)| k == &key) {
+                return self.error(&format!("'{}' is set more than once", key));
+            }
+
+            self.skip_space(false);
+            if Some('=') != self.next() {
+                return self.error(&format!("expected '=' after '{}'", key));
+            }
+            self.skip_space(false);
+
+            let values = match self.chars.peek() {
+                Some('[') => self.parse_array()?,
+                _ => vec![self.parse_string()?],
+            };
+
+            self.skip_space(false);
+            match self.next() {
+                None | Some('\n') => {}
+                Some(c) => return self.error(&format!("unexpected '{}' after value", c)),
+            }
+
+            entries.push((key, values));
+        }
+
+        Ok(entries)
+    }
+
+    fn parse_key(&mut self) -> Result<String, Box<dyn Error>> {
+        let mut key = String::new();
+        while let Some(&c) = self.chars.peek() {
+            if !c.is_ascii_alphanumeric() && '_' != c && '-' != c {
+                break;
+            }
+            key.push(c);
+            self.next();
+        }
+
+        if key.is_empty() {
+            return self.error("expected a key");
+        }
+
+        Ok(key)
+    }
+
+    fn parse_array(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+        // opening bracket
+        self.next();
+
+        let mut values = vec![];
+        loop {
+            self.skip_space(true);
+            if Some(&']') == self.chars.peek() {
+                self.next();
+                break;
+            }
+
+            values.push(self.parse_string()?);
+
+            self.skip_space(true);
+            match self.next() {
+                Some(',') => {}
+                Some(']') => break,
+                _ => return self.error("expected ',' or ']' in array"),
+            }
+        }
+
+        Ok(values)
+    }
+
+    /**
+     * Parse a basic string ("...", with backslash escapes) or a literal string ('...').
+     */
+    fn parse_string(&mut self) -> Result<String, Box<dyn Error>> {
+        let quote = match self.next() {
+            Some(c) if '"' == c || '\'' == c => c,
+            _ => return self.error("expected a string (values must be quoted)"),
+        };
+
+        let mut s = String::new();
+        loop {
+            // stop before the newline, so the error has the string's line
+            if matches!(self.chars.peek(), None | Some('\n')) {
+                return self.error("unterminated string");
+            }
+
+            match self.next() {
+                None => return self.error("unterminated string"),
+                Some(c) if quote == c => break,
+                Some('\\') if '"' == quote => match self.next() {
+                    Some('\\') => s.push('\\'),
+                    Some('"') => s.push('"'),
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    _ => return self.error("unsupported escape in string"),
+                },
+                Some(c) => s.push(c),
+            }
+        }
+
+        Ok(s)
+    }
+
+    /**
+     * Skip spaces, tabs and comments, and newlines too if asked to.
+     */
+    fn skip_space(&mut self, newlines: bool) {
+        while let Some(&c) = self.chars.peek() {
+            match c {
+                ' ' | '\t' | '\r' => {}
+                '\n' if newlines => {}
+                '#' => {
+                    while !matches!(self.chars.peek(), None | Some('\n')) {
+                        self.next();
+                    }
+                    continue;
+                }
+                _ => break,
+            }
+            self.next();
+        }
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if Some('\n') == c {
+            self.line += 1;
+        }
+        c
+    }
+
+    fn error<T>(&self, reason: &str) -> Result<T, Box<dyn Error>> {
+        dit_error(&format!("line {}: {}", self.line, reason))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<Vec<ProfileEntry>, String> {
+        ProfileParser::new(contents)
+            .parse()
+            .map_err(|e| e.to_string())
+    }
+
+    fn entry(key: &str, values: &[&str]) -> ProfileEntry {
+        (
+            String::from(key),
+            values.iter().map(|value| String::from(*value)).collect(),
+        )
+    }
+
+    #[test]
+    fn parse_profile() {
+        let contents = r#"
+# where to copy each card
+write = ["/mnt/ssd1/trip", "/mnt/ssd2/trip"]
+
+options = [
+    "--layout={card}/{year}-{month}-{day}/{filename}",  # by day
+    '--ledger',
+    "--check",
+]
+watch = "/media/$USER"   # a single string
+match=[]
+"#;
+        assert_eq!(
+            Ok(vec![
+                entry("write", &["/mnt/ssd1/trip", "/mnt/ssd2/trip"]),
+                entry(
+                    "options",
+                    &[
+                        "--layout={card}/{year}-{month}-{day}/{filename}",
+                        "--ledger",
+                        "--check",
+                    ]
+                ),
+                entry("watch", &["/media/$USER"]),
+                entry("match", &[]),
+            ]),
+            parse(contents)
+        );
+        assert_eq!(Ok(vec![]), parse("\n  # nothing here\n"));
+    }
+
+    #[test]
+    fn parse_strings() {
+        assert_eq!(
+            Ok(vec![entry("a", &["x \"y\" \\ \t\n"])]),
+            parse(r#"a = "x \"y\" \\ \t\n""#)
+        );
+
+        // no escapes in literal strings
+        assert_eq!(
+            Ok(vec![entry("a", &[r"C:\dir\n"])]),
+            parse(r"a = 'C:\dir\n'")
+        );
+        assert_eq!(Ok(vec![entry("a", &["#1"])]), parse("a = \"#1\" # not #2"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        for (contents, err) in [
+            ("[table]\n", "line 1: tables are not supported"),
+            ("a = 'x'\na = 'y'\n", "line 2: 'a' is set more than once"),
+            ("a 'x'\n", "line 1: expected '=' after 'a'"),
+            ("= 'x'\n", "line 1: expected a key"),
+            (
+                "a = x\n",
+                "line 1: expected a string (values must be quoted)",
+            ),
+            ("a = 'x' 'y'\n", "line 1: unexpected ''' after value"),
+            ("\na = \"x\n", "line 2: unterminated string"),
+            ("a = 'x", "line 1: unterminated string"),
+            ("a = \"\\q\"\n", "line 1: unsupported escape in string"),
+            ("a = ['x' 'y']\n", "line 1: expected ',' or ']' in array"),
+            (
+                "a = ['x',\n\n",
+                "line 3: expected a string (values must be quoted)",
+            ),
+        ] {
+            assert_eq!(Err(String::from(err)), parse(contents), "{:?}", contents);
+        }
+    }
+
+    #[test]
+    fn volume_dir_names() {
+        let volume = |uuid: Option<&str>| Volume {
+            uuid: uuid.map(String::from),
+            ..Volume::default()
+        };
+
+        let card = Path::new("/media/alice/EOS_DIGITAL");
+        assert_eq!(
+            "EOS_DIGITAL-3A1F-19C2",
+            volume_dir_name(card, &volume(Some("3A1F-19C2")))
+        );
+        assert_eq!("EOS_DIGITAL", volume_dir_name(card, &volume(None)));
+
+        // an unlabeled card, which the desktop mounted by its UUID
+        let card = Path::new("/media/alice/3A1F-19C2");
+        assert_eq!(
+            "3A1F-19C2",
+            volume_dir_name(card, &volume(Some("3A1F-19C2")))
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use crate::common::dit_error;
+use crate::fsutil;
+use crate::media;
+use crate::volume::Volume;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// XML namespace for the properties that dit writes
+const DIT_NS: &str = "https://nathanrosenquist.com/dit/ns/1.0/";
+
+// start of the rdf:Description element that holds dit's properties, which is how dit finds it
+// again to update it
+const DIT_DESCRIPTION_START: &str = "<rdf:Description rdf:about=\"\"\n    xmlns:dit=\"";
+const DESCRIPTION_END: &str = "</rdf:Description>";
+const RDF_END: &str = "</rdf:RDF>";
+
+/**
+ * Writes an XMP sidecar next to each photo or video written to a write path, with where it came
+ * from: the source path, the card or volume it was on (see Volume), when it was ingested, its
+ * SHA-256 hash, and the version of dit.
+ *
+ * The sidecar for DSCF1234.RAF is DSCF1234.RAF.xmp, so it doesn't clash with a DSCF1234.xmp
+ * that the camera or an editor wrote for the whole family of files. dit's properties are kept in
+ * their own rdf:Description element, in their own namespace, and updating a sidecar only
+ * replaces that element, so the rest of the sidecar is never touched.
+ */
+pub struct Sidecars {
+    read_paths: Vec<PathBuf>,
+
+    // card or volume that each read path is on, as it's written in the sidecars
+    read_volumes: Vec<String>,
+
+    // permissions for new sidecars, like the files they go with
+    file_mode: u32,
+}
+
+impl Sidecars {
+    pub fn new(read_paths: &[PathBuf], read_volumes: &[Volume], file_mode: u32) -> Sidecars {
+        Sidecars {
+            read_paths: read_paths.to_vec(),
+            read_volumes: read_volumes.iter().map(|v| v.to_string()).collect(),
+            file_mode,
+        }
+    }
+
+    /**
+     * Get the path of the sidecar for a destination file.
+     */
+    pub fn sidecar_path(dest_path: &Path) -> PathBuf {
+        let mut sidecar_path = dest_path.as_os_str().to_owned();
+        sidecar_path.push(".xmp");
+        PathBuf::from(sidecar_path)
+    }
+
+    /**
+     * Write or update the sidecar for a destination file, if it is a photo or video (see
+     * media::classify()).
+     */
+    pub fn write(
+        &self,
+        src_path: &Path,
+        dest_path: &Path,
+        hash: &str,
+    ) -> Result<(), Box<dyn Error>> {
+        if !media::classify(src_path).is_media() {
+            return Ok(());
+        }
+
+        let source_card = match self.read_paths.iter().position(|p| src_path.starts_with(p)) {
+            Some(i) => self.read_volumes[i].as_str(),
+            None => "-",
+        };
+
+        let mut description = format!("{}{}\">\n", DIT_DESCRIPTION_START, DIT_NS);
+        for (name, value) in [
+            ("SourcePath", escape(src_path.to_str().unwrap())),
+            ("SourceCard", escape(source_card)),
+            ("IngestTime", now_iso8601()?),
+            ("SHA256", String::from(hash)),
+            ("Version", String::from(env!("CARGO_PKG_VERSION"))),
+        ] {
+            description.push_str(&format!("   <dit:{}>{}</dit:{}>\n", name, value, name));
+        }
+        description.push_str("  ");
+        description.push_str(DESCRIPTION_END);
+
+        let sidecar_path = Sidecars::sidecar_path(dest_path);
+        let contents = match fs::read_to_string(&sidecar_path) {
+            Ok(contents) => update(&contents, &description)?,
+            Err(e) if ErrorKind::NotFound == e.kind() => new_sidecar(&description),
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        // write to a temp file and rename it into place, so the sidecar is never half written
+        let (mut tmp_file, tmp_path) = fsutil::mkstemp(sidecar_path.parent().unwrap())?;
+        let result = write_sidecar(&mut tmp_file, &tmp_path, &contents, self.file_mode);
+        match result {
+            Ok(()) => fsutil::atomic_rename(&tmp_path, &sidecar_path),
+            Err(e) => {
+                let _ = fs::remove_file(&tmp_path);
+                Err(e)
+            }
+        }
+    }
+}
+
+/**
+ * Write the contents of a sidecar to its temp file, and set its permissions.
+ */
+fn write_sidecar(
+    tmp_file: &mut File,
+    tmp_path: &Path,
+    contents: &str,
+    file_mode: u32,
+) -> Result<(), Box<dyn Error>> {
+    tmp_file.write_all(contents.as_bytes())?;
+    fsutil::chmod(tmp_path, file_mode)
+}
+
+/**
+ * Get the contents of a new sidecar, with just dit's rdf:Description element.
+ */
+fn new_sidecar(description: &str) -> String {
+    let mut contents = String::new();
+    contents.push_str("<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n");
+    contents.push_str(&format!(
+        "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\" x:xmptk=\"dit {}\">\n",
+        env!("CARGO_PKG_VERSION")
+    ));
+    contents.push_str(" <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n");
+    contents.push_str(&format!("  {}\n", description));
+    contents.push_str(&format!(" {}\n", RDF_END));
+    contents.push_str("</x:xmpmeta>\n");
+    contents.push_str("<?xpacket end=\"w\"?>\n");
+    contents
+}
+
+/**
+ * Replace dit's rdf:Description element in an existing sidecar, or add it if there isn't one.
+ */
+fn update(contents: &str, description: &str) -> Result<String, Box<dyn Error>> {
+    let start_tag = format!("{}{}\">", DIT_DESCRIPTION_START, DIT_NS);
+
+    if let Some(start) = contents.find(&start_tag) {
+        if let Some(len) = contents[start..].find(DESCRIPTION_END) {
+            let end = start + len + DESCRIPTION_END.len();
+            return Ok(format!(
+                "{}{}{}",
+                &contents[..start],
+                description,
+                &contents[end..]
+            ));
+        }
+    }
+
+    // another tool rewrote dit's properties in some other form, and replacing them could lose
+    // other properties along with them
+    if contents.contains(DIT_NS) {
+        return dit_error("it has dit properties in a form that dit can't update");
+    }
+
+    match contents.rfind(RDF_END) {
+        Some(end) => Ok(format!(
+            "{} {}\n {}",
+            &contents[..end],
+            description,
+            &contents[end..]
+        )),
+        None => dit_error("it has no rdf:RDF element"),
+    }
+}
+
+/**
+ * Get the current local time in ISO 8601 format, with the offset from UTC
+ * (e.g. "2024-06-01T14:30:00+02:00").
+ */
+fn now_iso8601() -> Result<String, Box<dyn Error>> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let time = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+
+    let result = unsafe { libc::localtime_r(&time, &mut tm) };
+    if result.is_null() {
+        return dit_error("could not get the local time");
+    }
+
+    let offset_minutes = tm.tm_gmtoff / 60;
+    Ok(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        if offset_minutes < 0 { '-' } else { '+' },
+        offset_minutes.abs() / 60,
+        offset_minutes.abs() % 60
+    ))
+}
+
+/**
+ * Escape the characters that are special in XML text.
+ */
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a sidecar written by another tool, with its own properties
+    const OTHER_SIDECAR: &str = "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">
+ <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">
+  <rdf:Description rdf:about=\"\"
+    xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"
+   xmp:Rating=\"3\"/>
+ </rdf:RDF>
+</x:xmpmeta>
+";
+
+    // dit's rdf:Description element, with just a hash
+    fn description(hash: &str) -> String {
+        format!(
+            "{}{}\">\n   <dit:SHA256>{}</dit:SHA256>\n  {}",
+            DIT_DESCRIPTION_START, DIT_NS, hash, DESCRIPTION_END
+        )
+    }
+
+    #[test]
+    fn update_replaces_dit_properties() {
+        let contents = new_sidecar(&description("aaaa"));
+        let updated = update(&contents, &description("bbbb")).unwrap();
+        assert_eq!(new_sidecar(&description("bbbb")), updated);
+    }
+
+    #[test]
+    fn update_adds_dit_properties_to_other_sidecars() {
+        let updated = update(OTHER_SIDECAR, &description("aaaa")).unwrap();
+        let (before, after) = OTHER_SIDECAR.split_at(OTHER_SIDECAR.rfind(RDF_END).unwrap());
+        assert_eq!(
+            format!("{} {}\n {}", before, description("aaaa"), after),
+            updated
+        );
+
+        // the other tool's properties are left alone when dit's are updated again
+        let updated = update(&updated, &description("bbbb")).unwrap();
+        assert_eq!(
+            update(OTHER_SIDECAR, &description("bbbb")).unwrap(),
+            updated
+        );
+        assert!(updated.contains("xmp:Rating=\"3\""));
+        assert!(!updated.contains("aaaa"));
+    }
+
+    #[test]
+    fn update_refuses_unknown_forms() {
+        // dit's properties rewritten as attributes by another tool
+        let rewritten = OTHER_SIDECAR.replace(
+            "xmp:Rating=\"3\"/>",
+            &format!("xmlns:dit=\"{}\"\n   dit:SHA256=\"aaaa\"/>", DIT_NS),
+        );
+        let err = update(&rewritten, &description("bbbb")).err().unwrap();
+        assert!(err.to_string().contains("can't update"), "{}", err);
+
+        let err = update("<x:xmpmeta/>", &description("bbbb")).err().unwrap();
+        assert!(err.to_string().contains("no rdf:RDF element"), "{}", err);
+    }
+
+    #[test]
+    fn escape_xml_text() {
+        assert_eq!(
+            "/Volumes/A&amp;B/&lt;1&gt; &quot;x&quot;.jpg",
+            escape("/Volumes/A&B/<1> \"x\".jpg")
+        );
+    }
+}